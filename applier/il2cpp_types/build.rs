@@ -1,5 +1,7 @@
+use merge_data::{parse_unity_version, MetadataRevision};
 use std::env::{self, VarError};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[cfg(target_os = "linux")]
 const NDK_HOST_TAG: &str = "linux-x86_64";
@@ -10,8 +12,46 @@ const NDK_HOST_TAG: &str = "windows";
 #[cfg(all(target_os = "windows", target_arch = "x86_64"))]
 const NDK_HOST_TAG: &str = "windows-x86_64";
 
+fn enabled_revision() -> MetadataRevision {
+    let revisions = [
+        ("CARGO_FEATURE_METADATA_V24_1", MetadataRevision::V24_1),
+        ("CARGO_FEATURE_METADATA_V24_2", MetadataRevision::V24_2),
+        ("CARGO_FEATURE_METADATA_V24_3", MetadataRevision::V24_3),
+        ("CARGO_FEATURE_METADATA_V24_4", MetadataRevision::V24_4),
+        ("CARGO_FEATURE_METADATA_V24_5", MetadataRevision::V24_5),
+    ];
+    let enabled: Vec<_> = revisions
+        .iter()
+        .filter(|(feature, _)| env::var_os(feature).is_some())
+        .map(|&(_, revision)| revision)
+        .collect();
+    match enabled[..] {
+        [revision] => revision,
+        _ => panic!(
+            "exactly one metadata revision feature must be enabled, got {:?}",
+            enabled
+        ),
+    }
+}
+
+/// Finds an installed editor whose il2cpp uses `revision`
+fn find_editor(editors_path: &Path, revision: MetadataRevision) -> PathBuf {
+    let newest = fs::read_dir(editors_path)
+        .expect("unable to read $UNITY_EDITORS")
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|version| MetadataRevision::from_unity_version(version) == Some(revision))
+        .max_by_key(|version| parse_unity_version(version));
+    match newest {
+        Some(version) => editors_path.join(version),
+        None => panic!(
+            "no unity editor using metadata revision {} was found in $UNITY_EDITORS",
+            revision
+        ),
+    }
+}
+
 fn main() {
-    // TODO: Find a matching unity install for every enabled il2cpp version and generate bindings for each
+    let revision = enabled_revision();
     let editors_path = match env::var("UNITY_EDITORS") {
         Ok(path) => PathBuf::from(path),
         Err(VarError::NotPresent) => panic!(
@@ -35,7 +75,7 @@ fn main() {
     let target = "aarch64-linux-android";
     let ndk_sysroot_target_include = sysroot_path.join(format!("usr/include/{}", target));
 
-    let libil2cpp_path = editors_path.join("2019.4.28f1/Editor/Data/il2cpp/libil2cpp");
+    let libil2cpp_path = find_editor(&editors_path, revision).join("Editor/Data/il2cpp/libil2cpp");
     let bindings = bindgen::Builder::default()
         .clang_arg(format!("-I{}", libil2cpp_path.to_str().unwrap()))
         .clang_arg(format!("-isystem{}", ndk_sysroot_include.to_str().unwrap()))
@@ -58,6 +98,6 @@ fn main() {
 
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     bindings
-        .write_to_file(out_path.join("bindings_24_5.rs"))
+        .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
 }
@@ -4,7 +4,7 @@
 // 128 bit ints are not ffi safe
 #![allow(improper_ctypes)]
 
-include!(concat!(env!("OUT_DIR"), "/bindings_24_5.rs"));
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 unsafe impl Sync for Il2CppCodeRegistration {}
 unsafe impl Sync for Il2CppMetadataRegistration {}
@@ -64,7 +64,7 @@ pub(crate) extern "C" fn resolve_method_by_call_helper_addr(fn_addr: P) -> unsaf
 
     let class = unsafe { &*method_info.klass };
     let ptr = if class.valuetype() != 0 {
-        get_method_pointer(class.image, method_info.token).unwrap()
+        get_method_pointer(method_info).unwrap()
     } else {
         method_info.methodPointer.unwrap()
     };
@@ -538,11 +538,7 @@ impl<'a> HookGenerator<'a> {
         let dest = HOOK_ALLOCATOR.lock().unwrap().alloc(self.code.size());
 
         let hook = Hook::new();
-        let orig_ptr = get_method_pointer(
-            unsafe { &*self.original.method.klass }.image,
-            self.original.method.token,
-        )
-        .unwrap();
+        let orig_ptr = get_method_pointer(self.original.method).unwrap();
         unsafe {
             hook.install(orig_ptr as _, dest as _);
         }
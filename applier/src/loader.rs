@@ -10,7 +10,9 @@ use dlopen::raw::Library;
 use il2cpp_types::{Il2CppCodeRegistration, Il2CppMetadataRegistration};
 use inline_hook::Hook;
 use merge_data::MergeModData;
-use metadata_builder::{CodeRegistrationBuilder, Metadata, MetadataRegistrationBuilder};
+use metadata_builder::{
+    CodeRegistrationBuilder, Metadata, MetadataRegistrationBuilder, METADATA_REVISION,
+};
 use std::collections::HashMap;
 use std::fs;
 use std::mem::transmute;
@@ -176,6 +178,13 @@ fn load_mods(
         file_path.set_extension("mmd");
         let mmd = fs::read(&file_path).context("could not read mod data")?;
         let mmd = MergeModData::deserialize(&mmd).context("failed to deserialize mod data")?;
+        if mmd.metadata_revision != METADATA_REVISION {
+            error!(
+                "Could not load mod {} because it was built for metadata revision {}, but the game uses {}",
+                id, mmd.metadata_revision, METADATA_REVISION
+            );
+            continue;
+        }
 
         file_path.set_extension("so");
         let so_path = EXEC_PATH.join(file_path.file_name().unwrap());
@@ -203,17 +212,11 @@ fn load_mods(
 
     if let Some(core_image) = modloader.find_image("QMergeCore.dll")? {
         info!("Hooking core natives");
-        let code_gen_module = modloader
-            .code_registration
-            .find_module("QMergeCore.dll")
-            .context("could not find core module")?;
         for (name, fn_ptr) in NATIVE_MAP {
             let token = modloader
                 .find_method_token_by_name(core_image, name.0, name.1, name.2)?
                 .context("Could not resolve a core native")?;
-            let rid = token & 0x00FFFFFF;
-            let original_ptr =
-                unsafe { code_gen_module.methodPointers.add(rid as usize - 1).read() };
+            let (original_ptr, _) = modloader.method_code("QMergeCore.dll", token)?;
 
             unsafe {
                 Hook::new().install(transmute::<_, _>(original_ptr), *fn_ptr);
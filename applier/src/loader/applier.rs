@@ -1,4 +1,6 @@
-use super::metadata_builder::{CodeRegistrationBuilder, Metadata, MetadataRegistrationBuilder};
+use super::metadata_builder::{
+    CodeGenModule, CodeRegistrationBuilder, Metadata, MetadataRegistrationBuilder,
+};
 use super::{ImportLut, Mod, ModRefs, MOD_IMPORT_LUT};
 use crate::loader::{FixupEntry, ImportLutEntry, MODS};
 use crate::utils::{get_str, offset_len};
@@ -6,8 +8,9 @@ use anyhow::{bail, Context, Result};
 use dlopen::raw::Library;
 use il2cpp_types::*;
 use merge_data::{
-    AddedGenericContainer, EncodedMethodIndex, GenericClassInst, GenericContainerOwner,
-    GenericInst, MergeModData, TypeDescription, TypeDescriptionData,
+    AddedGenericContainer, ArrayTypeDescription, DeclaringType, EncodedMethodIndex,
+    GenericClassInst, GenericContainerOwner, GenericInst, MergeModData, RgctxData, TypeDescription,
+    TypeDescriptionData,
 };
 use std::collections::HashMap;
 use std::ffi::c_void;
@@ -45,6 +48,57 @@ impl TypeResolveContext {
     }
 }
 
+/// Array types are compared by their shape, so that the resolved `Il2CppType` can be deduplicated
+/// by pointer like every other type
+fn find_or_add_array_type(
+    types: &[*const Il2CppType],
+    etype: *const Il2CppType,
+    desc: &ArrayTypeDescription,
+) -> *mut Il2CppArrayType {
+    let existing = types.iter().find_map(|&ty| unsafe {
+        if (*ty).type_() != Il2CppTypeEnum_IL2CPP_TYPE_ARRAY {
+            return None;
+        }
+        let array = (*ty).data.array;
+        let array_ref = &*array;
+        let sizes = if array_ref.sizes.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(array_ref.sizes, array_ref.numsizes as usize)
+        };
+        let lower_bounds = if array_ref.lobounds.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(array_ref.lobounds, array_ref.numlobounds as usize)
+        };
+        let matches = array_ref.etype == etype
+            && array_ref.rank == desc.rank
+            && sizes == desc.sizes.as_slice()
+            && lower_bounds == desc.lower_bounds.as_slice();
+        matches.then(|| array)
+    });
+    if let Some(array) = existing {
+        return array;
+    }
+
+    fn leak_or_null(values: &[i32]) -> *mut i32 {
+        if values.is_empty() {
+            ptr::null_mut()
+        } else {
+            Box::leak(values.to_vec().into_boxed_slice()).as_mut_ptr()
+        }
+    }
+    let array = Il2CppArrayType {
+        etype,
+        rank: desc.rank,
+        numsizes: desc.sizes.len() as u8,
+        numlobounds: desc.lower_bounds.len() as u8,
+        sizes: leak_or_null(&desc.sizes),
+        lobounds: leak_or_null(&desc.lower_bounds),
+    };
+    Box::leak(Box::new(array))
+}
+
 struct TypeResolver<'a> {
     descs: &'a [TypeDescription],
     type_def_refs: &'a [usize],
@@ -110,6 +164,19 @@ impl<'a> TypeResolver<'a> {
             TypeDescriptionData::GenericClass(idx) => Il2CppType__bindgen_ty_1 {
                 generic_class: self.resolve_generic_class(idx, loader, ctx)?,
             },
+            TypeDescriptionData::Array(ref array) => {
+                let (elem_idx, elem_has_generic_param) =
+                    self.resolve_internal(array.elem_ty, loader, ctx, cache_generics)?;
+                has_generic_param = elem_has_generic_param;
+                let etype = loader.metadata_registration.types[elem_idx as usize];
+                Il2CppType__bindgen_ty_1 {
+                    array: find_or_add_array_type(
+                        &loader.metadata_registration.types,
+                        etype,
+                        array,
+                    ),
+                }
+            }
         };
         let bitfield = Il2CppType::new_bitfield_1(
             desc.attrs as u32,
@@ -329,11 +396,18 @@ impl<'md> ModLoader<'md> {
                     -1 => None,
                     ty_idx => unsafe {
                         let ty = &*metadata_registration.types[ty_idx as usize];
-                        assert!(
-                            ty.type_() == Il2CppTypeEnum_IL2CPP_TYPE_CLASS
-                                || ty.type_() == Il2CppTypeEnum_IL2CPP_TYPE_VALUETYPE
-                        );
-                        Some(ty.data.klassIndex as usize)
+                        match ty.type_() {
+                            Il2CppTypeEnum_IL2CPP_TYPE_CLASS
+                            | Il2CppTypeEnum_IL2CPP_TYPE_VALUETYPE => {
+                                Some(ty.data.klassIndex as usize)
+                            }
+                            // Types nested in generic classes are declared by an instance of the
+                            // class, so use its generic type definition
+                            Il2CppTypeEnum_IL2CPP_TYPE_GENERICINST => {
+                                Some((*ty.data.generic_class).typeDefinitionIndex as usize)
+                            }
+                            ty => bail!("unexpected declaring type with type enum {}", ty),
+                        }
                     },
                 };
 
@@ -405,6 +479,7 @@ impl<'md> ModLoader<'md> {
         gen_methods: &[usize],
     ) -> Result<u32> {
         Ok(match eidx {
+            EncodedMethodIndex::Invalid => 0,
             EncodedMethodIndex::Il2CppClass(idx) => {
                 ty_resolver.resolve(idx, self, ctx)? as u32 | 0x20000000
             }
@@ -482,6 +557,11 @@ impl<'md> ModLoader<'md> {
         Ok(None)
     }
 
+    pub fn method_code(&self, image_name: &str, token: u32) -> Result<(Il2CppMethodPointer, i32)> {
+        self.code_registration
+            .method_code(self.metadata, image_name, token)
+    }
+
     pub fn find_method_token_by_name(
         &self,
         image: usize,
@@ -561,7 +641,17 @@ impl<'md> ModLoader<'md> {
                     continue;
                 }
                 let ty_def_desc = &mod_data.type_def_descriptions[i];
-                let decl_ty = match ty_def_desc.decl_type {
+                let decl_ty_def_ref_idx = match ty_def_desc.decl_type {
+                    None => None,
+                    Some(DeclaringType::TypeDef(idx)) => Some(idx),
+                    // Lookups only care about the generic type definition
+                    Some(DeclaringType::GenericClass(idx)) => Some(
+                        mod_data.generic_class_insts[idx]
+                            .class
+                            .context("declaring generic class has no type definition")?,
+                    ),
+                };
+                let decl_ty = match decl_ty_def_ref_idx {
                     None => None,
                     Some(decl_ty_def_ref_idx) => match type_def_refs[decl_ty_def_ref_idx] {
                         Some(ty_def_idx) => Some(ty_def_idx),
@@ -603,6 +693,57 @@ impl<'md> ModLoader<'md> {
         Ok(type_def_refs.into_iter().map(|r| r.unwrap()).collect())
     }
 
+    /// 24.1 keeps the method pointers of the whole game in one table and the runtime generic
+    /// context in the metadata, so the mod's code gen module is added to those and the mod's
+    /// definitions get their indices into them
+    #[cfg(feature = "metadata_v24_1")]
+    fn add_method_code(&mut self, module: &CodeGenModule, ty_defs_start: usize) -> Result<()> {
+        let (method_pointers, invoker_indices, rgctx_ranges, rgctxs) = unsafe {
+            (
+                slice::from_raw_parts(module.methodPointers, module.methodPointerCount as usize),
+                slice::from_raw_parts(module.invokerIndices, module.methodPointerCount as usize),
+                slice::from_raw_parts(module.rgctxRanges, module.rgctxRangesCount as usize),
+                slice::from_raw_parts(module.rgctxs, module.rgctxsCount as usize),
+            )
+        };
+        let rgctx_start = self.metadata.rgctx_entries.len() as i32;
+        self.metadata.rgctx_entries.extend_from_slice(rgctxs);
+        let rgctx_range = |token: u32| {
+            rgctx_ranges
+                .iter()
+                .find(|pair| pair.token == token)
+                .map_or((-1, 0), |pair| (rgctx_start + pair.start, pair.length))
+        };
+
+        let metadata = &mut *self.metadata;
+        for ty_def in &mut metadata.type_definitions[ty_defs_start..] {
+            (ty_def.rgctxStartIndex, ty_def.rgctxCount) = rgctx_range(ty_def.token);
+
+            let methods_range = offset_len(ty_def.methodStart, ty_def.method_count as i32);
+            for method in &mut metadata.methods[methods_range] {
+                let rid = (method.token & 0x00FFFFFF) as usize;
+                let method_pointer =
+                    *method_pointers.get(rid.wrapping_sub(1)).with_context(|| {
+                        format!(
+                            "mod method {:08x} is not in its code gen module",
+                            method.token
+                        )
+                    })?;
+                method.methodIndex = match method_pointer {
+                    Some(_) => {
+                        let idx = self.code_registration.method_pointers.len();
+                        self.code_registration.method_pointers.push(method_pointer);
+                        idx as i32
+                    }
+                    None => -1,
+                };
+                method.invokerIndex = invoker_indices[rid - 1];
+                (method.rgctxStartIndex, method.rgctxCount) = rgctx_range(method.token);
+            }
+        }
+        Ok(())
+    }
+
     pub fn load_mod(&mut self, id: &str, mod_data: &MergeModData, lib: Arc<Library>) -> Result<()> {
         let image_name = self.add_str(&mod_data.added_image.name) as i32;
         self.metadata.images.push(Il2CppImageDefinition {
@@ -639,13 +780,16 @@ impl<'md> ModLoader<'md> {
 
         let mut ty_resolver = TypeResolver::new(mod_data, &type_def_refs);
 
-        let code_gen_module: *const Il2CppCodeGenModule =
+        let code_gen_module: *const CodeGenModule =
             unsafe { lib.symbol(&format!("g_{}CodeGenModule", id))? };
+        #[cfg(not(feature = "metadata_v24_1"))]
         self.code_registration
             .code_gen_modules
             .push(code_gen_module);
 
         let mut load_fn = None;
+        // The generic parameters that the runtime generic context of each type and method uses
+        let mut rgctx_owners = HashMap::new();
 
         debug!("Adding mod type defs");
         // Fill in everything that doesn't requre method references now
@@ -659,6 +803,7 @@ impl<'md> ModLoader<'md> {
                 &Default::default(),
             )?;
             let ctx = TypeResolveContext::new(container_idx);
+            rgctx_owners.insert(ty_def.token, ctx);
 
             let fields_start = self.metadata.fields.len();
             for field in &ty_def.fields {
@@ -693,6 +838,7 @@ impl<'md> ModLoader<'md> {
                     &ctx,
                 )?;
                 let ctx = ctx.with_method(container_idx);
+                rgctx_owners.insert(method.token, ctx);
 
                 if ty_def.name == "Plugin" && method.name == "Init" && method.parameters.is_empty()
                 {
@@ -741,6 +887,17 @@ impl<'md> ModLoader<'md> {
                     iflags: method.iflags,
                     slot: method.slot,
                     parameterCount: method.parameters.len() as u16,
+                    // These get filled in by `add_method_code`
+                    #[cfg(feature = "metadata_v24_1")]
+                    methodIndex: -1,
+                    #[cfg(feature = "metadata_v24_1")]
+                    invokerIndex: -1,
+                    #[cfg(feature = "metadata_v24_1")]
+                    reversePInvokeWrapperIndex: -1,
+                    #[cfg(feature = "metadata_v24_1")]
+                    rgctxStartIndex: -1,
+                    #[cfg(feature = "metadata_v24_1")]
+                    rgctxCount: 0,
                 })
             }
             let nested_types_start = self.metadata.nested_types.len();
@@ -812,6 +969,12 @@ impl<'md> ModLoader<'md> {
 
                 bitfield: ty_def.bitfield,
                 token: ty_def.token,
+
+                // These get filled in by `add_method_code`
+                #[cfg(feature = "metadata_v24_1")]
+                rgctxStartIndex: -1,
+                #[cfg(feature = "metadata_v24_1")]
+                rgctxCount: 0,
             })
         }
 
@@ -917,14 +1080,19 @@ impl<'md> ModLoader<'md> {
                 gen_methods.push(idx);
             }
         }
-        let mod_adj_thunks: *const Il2CppMethodPointer =
-            unsafe { lib.symbol("g_Il2CppGenericAdjustorThunks")? };
+        // Generic adjustor thunks were only added in 24.5
+        #[cfg(feature = "metadata_v24_5")]
         let adj_thunks_offset = self.code_registration.generic_adjustor_thunks.len();
-        for i in 0..mod_data.code_table_sizes.generic_adjustor_thunks {
-            let method_pointer = unsafe { mod_adj_thunks.add(i).read() };
-            self.code_registration
-                .generic_adjustor_thunks
-                .push(method_pointer);
+        #[cfg(feature = "metadata_v24_5")]
+        {
+            let mod_adj_thunks: *const Il2CppMethodPointer =
+                unsafe { lib.symbol("g_Il2CppGenericAdjustorThunks")? };
+            for i in 0..mod_data.code_table_sizes.generic_adjustor_thunks {
+                let method_pointer = unsafe { mod_adj_thunks.add(i).read() };
+                self.code_registration
+                    .generic_adjustor_thunks
+                    .push(method_pointer);
+            }
         }
         let mod_gen_method_ptrs: *const Il2CppMethodPointer =
             unsafe { lib.symbol("g_Il2CppGenericMethodPointers")? };
@@ -954,6 +1122,7 @@ impl<'md> ModLoader<'md> {
                     indices: Il2CppGenericMethodIndices {
                         methodIndex: (gen_method_funcs.method_idx + gen_method_ptrs_offset) as i32,
                         invokerIndex: (gen_method_funcs.invoker_idx + invokers_offset) as i32,
+                        #[cfg(feature = "metadata_v24_5")]
                         adjustorThunkIndex: gen_method_funcs
                             .adjustor_thunk_idx
                             .map_or(-1, |idx| (idx + adj_thunks_offset) as i32),
@@ -974,6 +1143,60 @@ impl<'md> ModLoader<'md> {
             *invoker_idx += invokers_offset as i32;
         }
 
+        debug!("Resolving mod runtime generic context");
+        let rgctxs = unsafe {
+            let code_gen_module = &*code_gen_module;
+            slice::from_raw_parts_mut(
+                code_gen_module.rgctxs as *mut Il2CppRGCTXDefinition,
+                code_gen_module.rgctxsCount as usize,
+            )
+        };
+        if rgctxs.len() != mod_data.rgctx_values.len() {
+            bail!(
+                "mod has {} runtime generic context values but its data describes {}",
+                rgctxs.len(),
+                mod_data.rgctx_values.len()
+            );
+        }
+        for (rgctx, value) in rgctxs.iter_mut().zip(&mod_data.rgctx_values) {
+            let ctx = rgctx_owners.get(&value.owner_token).with_context(|| {
+                format!(
+                    "runtime generic context owner {:08x} is not defined by the mod",
+                    value.owner_token
+                )
+            })?;
+            let (data_ty, data) = match value.data {
+                RgctxData::Type(idx) => (
+                    Il2CppRGCTXDataType_IL2CPP_RGCTX_DATA_TYPE,
+                    Il2CppRGCTXDefinitionData {
+                        typeIndex: ty_resolver.resolve(idx, self, ctx)?,
+                    },
+                ),
+                RgctxData::Class(idx) => (
+                    Il2CppRGCTXDataType_IL2CPP_RGCTX_DATA_CLASS,
+                    Il2CppRGCTXDefinitionData {
+                        typeIndex: ty_resolver.resolve(idx, self, ctx)?,
+                    },
+                ),
+                RgctxData::Method(idx) => (
+                    Il2CppRGCTXDataType_IL2CPP_RGCTX_DATA_METHOD,
+                    Il2CppRGCTXDefinitionData {
+                        methodIndex: gen_methods[idx] as i32,
+                    },
+                ),
+                RgctxData::Array(idx) => (
+                    Il2CppRGCTXDataType_IL2CPP_RGCTX_DATA_ARRAY,
+                    Il2CppRGCTXDefinitionData {
+                        typeIndex: ty_resolver.resolve(idx, self, ctx)?,
+                    },
+                ),
+            };
+            rgctx.type_ = data_ty;
+            rgctx.data = data;
+        }
+        #[cfg(feature = "metadata_v24_1")]
+        self.add_method_code(unsafe { &*code_gen_module }, ty_defs_start)?;
+
         debug!("Resolving rest of added type defs");
         // Now that method references have been resolved, we go back through the type definitions and add items that required method references.
         for (i, ty_def) in mod_data.added_type_defintions.iter().enumerate() {
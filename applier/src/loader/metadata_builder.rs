@@ -1,12 +1,37 @@
 use super::{CODE_REGISTRATION, METADATA_REGISTRATION};
-use anyhow::{ensure, Result};
+use anyhow::{ensure, Context, Result};
+use cfg_if::cfg_if;
 use il2cpp_types::*;
-use std::ffi::CStr;
+use merge_data::MetadataRevision;
 use std::mem::size_of;
 use std::slice;
 
+cfg_if! {
+    if #[cfg(feature = "metadata_v24_1")] {
+        use crate::utils::{get_str, offset_len};
+        use anyhow::bail;
+        use std::ffi::c_char;
+    } else {
+        use std::ffi::CStr;
+    }
+}
+
 const SANITY: i32 = 0xFAB11BAFu32 as i32;
 
+cfg_if! {
+    if #[cfg(feature = "metadata_v24_1")] {
+        pub const METADATA_REVISION: MetadataRevision = MetadataRevision::V24_1;
+    } else if #[cfg(feature = "metadata_v24_2")] {
+        pub const METADATA_REVISION: MetadataRevision = MetadataRevision::V24_2;
+    } else if #[cfg(feature = "metadata_v24_3")] {
+        pub const METADATA_REVISION: MetadataRevision = MetadataRevision::V24_3;
+    } else if #[cfg(feature = "metadata_v24_4")] {
+        pub const METADATA_REVISION: MetadataRevision = MetadataRevision::V24_4;
+    } else if #[cfg(feature = "metadata_v24_5")] {
+        pub const METADATA_REVISION: MetadataRevision = MetadataRevision::V24_5;
+    }
+}
+
 unsafe fn table_from_raw<T: Clone>(offset_ptr: *const u8, count: i32) -> Vec<T> {
     slice::from_raw_parts(offset_ptr.cast(), count as usize / size_of::<T>()).to_vec()
 }
@@ -23,43 +48,66 @@ unsafe fn write_to_table<T>(ptr: *mut u8, data: T) -> *mut u8 {
 
 macro_rules! metadata {
     ($(
+        $(#[cfg($cfg:meta)])?
         #[metadata($offset_name:ident, $count_name:ident)]
         $name:ident: $ty:ty,
     )*) => {
         pub struct Metadata {
             $(
+                $(#[cfg($cfg)])?
                 pub $name: $ty,
             )*
         }
 
         impl Metadata {
+            /// Revisions 24.2 through 24.5 share the same global metadata header and table
+            /// layouts, their differences are all in the code and metadata registrations. 24.1
+            /// also has a table for the runtime generic context, and its method and type
+            /// definitions have extra fields. The header and table types come from the bindings
+            /// for `METADATA_REVISION`.
             pub unsafe fn from_raw(original: *const u8) -> Result<Self> {
                 let header: &Il2CppGlobalMetadataHeader = &*original.cast();
-                ensure!(header.sanity == SANITY);
-                ensure!(header.version == 24);
+                ensure!(header.sanity == SANITY, "global metadata has an invalid sanity value");
+                ensure!(
+                    header.version == 24,
+                    "global metadata version is {}, expected 24 (revision {})",
+                    header.version,
+                    METADATA_REVISION
+                );
 
                 $(
+                    $(#[cfg($cfg)])?
                     let $name = table_from_raw(original.offset(header.$offset_name as isize), header.$count_name);
                 )*
 
                 Ok(Self {
                     $(
+                        $(#[cfg($cfg)])?
                         $name,
                     )*
                 })
             }
 
             pub fn build(self) -> *const u8 {
-                let size = size_of::<Il2CppGlobalMetadataHeader>() $(+ table_size(&self.$name))*;
+                let mut size = size_of::<Il2CppGlobalMetadataHeader>();
+                $(
+                    $(#[cfg($cfg)])?
+                    {
+                        size += table_size(&self.$name);
+                    }
+                )*
                 let data: *mut u8 = Box::leak(vec![0u8; size].into_boxed_slice()).as_mut_ptr();
 
                 let mut cur: *mut u8 = unsafe { data.add(size_of::<Il2CppGlobalMetadataHeader>()) };
 
                 $(
+                    $(#[cfg($cfg)])?
                     #[allow(non_snake_case)]
                     let $offset_name = unsafe { cur.offset_from(data) } as i32;
+                    $(#[cfg($cfg)])?
                     #[allow(non_snake_case)]
                     let $count_name = table_size(&self.$name) as i32;
+                    $(#[cfg($cfg)])?
                     for item in self.$name {
                         cur = unsafe { write_to_table(cur, item) };
                     }
@@ -69,7 +117,9 @@ macro_rules! metadata {
                     sanity: SANITY,
                     version: 24,
                     $(
+                        $(#[cfg($cfg)])?
                         $offset_name,
+                        $(#[cfg($cfg)])?
                         $count_name,
                     )*
                 };
@@ -123,6 +173,9 @@ metadata! {
     interface_offsets: Vec<Il2CppInterfaceOffsetPair>,
     #[metadata(typeDefinitionsOffset, typeDefinitionsCount)]
     type_definitions: Vec<Il2CppTypeDefinition>,
+    #[cfg(feature = "metadata_v24_1")]
+    #[metadata(rgctxEntriesOffset, rgctxEntriesCount)]
+    rgctx_entries: Vec<Il2CppRGCTXDefinition>,
     #[metadata(imagesOffset, imagesCount)]
     images: Vec<Il2CppImageDefinition>,
     #[metadata(assembliesOffset, assembliesCount)]
@@ -149,17 +202,79 @@ metadata! {
     exported_type_definitions: Vec<TypeDefinitionIndex>,
 }
 
+cfg_if! {
+    if #[cfg(feature = "metadata_v24_1")] {
+        /// The runtime generic context values in a code gen module that belong to the type or
+        /// method with `token`
+        #[repr(C)]
+        pub struct TokenRangePair {
+            pub token: u32,
+            pub start: i32,
+            pub length: i32,
+        }
+
+        /// 24.1 doesn't have code gen modules, so qmerge generates this in each mod's
+        /// `<id>_CodeGen.c` in their place. The loader spreads it over the game's method pointers
+        /// and the metadata's runtime generic context.
+        #[repr(C)]
+        #[allow(non_snake_case)]
+        pub struct CodeGenModule {
+            pub moduleName: *const c_char,
+            pub methodPointerCount: u32,
+            pub methodPointers: *const Il2CppMethodPointer,
+            pub invokerIndices: *const i32,
+            pub rgctxRangesCount: u32,
+            pub rgctxRanges: *const TokenRangePair,
+            pub rgctxsCount: u32,
+            pub rgctxs: *const Il2CppRGCTXDefinition,
+        }
+
+        fn find_method<'md>(
+            metadata: &'md Metadata,
+            image_name: &str,
+            token: u32,
+        ) -> Result<&'md Il2CppMethodDefinition> {
+            for image in &metadata.images {
+                if get_str(&metadata.string, image.nameIndex as usize)? != image_name {
+                    continue;
+                }
+                let type_defs = offset_len(image.typeStart, image.typeCount as i32);
+                for type_def in &metadata.type_definitions[type_defs] {
+                    let methods = offset_len(type_def.methodStart, type_def.method_count as i32);
+                    if let Some(method) = metadata.methods[methods]
+                        .iter()
+                        .find(|method| method.token == token)
+                    {
+                        return Ok(method);
+                    }
+                }
+                bail!("{} has no method with token {:08x}", image_name, token);
+            }
+            bail!("could not find image {}", image_name);
+        }
+    } else {
+        pub type CodeGenModule = Il2CppCodeGenModule;
+    }
+}
+
 pub struct CodeRegistrationBuilder {
     raw: *mut *const Il2CppCodeRegistration,
 
+    /// Only 24.1 has the method pointers of the whole game here, later revisions split them into
+    /// code gen modules
+    #[cfg(feature = "metadata_v24_1")]
+    pub method_pointers: Vec<Il2CppMethodPointer>,
     pub generic_method_pointers: Vec<Il2CppMethodPointer>,
+    /// Always empty before 24.5
     pub generic_adjustor_thunks: Vec<Il2CppMethodPointer>,
     pub invoker_pointers: Vec<InvokerMethod>,
     pub custom_attribute_generators: Vec<CustomAttributesCacheGenerator>, // TODO
+    #[cfg(not(feature = "metadata_v24_1"))]
     pub code_gen_modules: Vec<*const Il2CppCodeGenModule>,
 }
 
 impl CodeRegistrationBuilder {
+    #[cfg(not(feature = "metadata_v24_1"))]
     pub fn find_module(&self, image_name: &str) -> Option<&Il2CppCodeGenModule> {
         self.code_gen_modules.iter().find_map(|&module| unsafe {
             let module = &*module;
@@ -172,22 +287,76 @@ impl CodeRegistrationBuilder {
         })
     }
 
+    /// The method pointer and invoker index of the method with `token` in an image
+    #[cfg(feature = "metadata_v24_1")]
+    pub fn method_code(
+        &self,
+        metadata: &Metadata,
+        image_name: &str,
+        token: u32,
+    ) -> Result<(Il2CppMethodPointer, i32)> {
+        let method = find_method(metadata, image_name, token)?;
+        let method_pointer = match method.methodIndex {
+            -1 => None,
+            idx => *self
+                .method_pointers
+                .get(idx as usize)
+                .context("method index is out of bounds")?,
+        };
+        Ok((method_pointer, method.invokerIndex))
+    }
+
+    /// The method pointer and invoker index of the method with `token` in an image
+    #[cfg(not(feature = "metadata_v24_1"))]
+    pub fn method_code(
+        &self,
+        _metadata: &Metadata,
+        image_name: &str,
+        token: u32,
+    ) -> Result<(Il2CppMethodPointer, i32)> {
+        let module = self
+            .find_module(image_name)
+            .with_context(|| format!("could not find code gen module for {}", image_name))?;
+        let rid = token & 0x00FFFFFF;
+        ensure!(
+            rid != 0 && rid <= module.methodPointerCount,
+            "{} has no method with token {:08x}",
+            image_name,
+            token
+        );
+        unsafe {
+            Ok((
+                module.methodPointers.add(rid as usize - 1).read(),
+                module.invokerIndices.add(rid as usize - 1).read(),
+            ))
+        }
+    }
+
     pub unsafe fn from_raw(raw: *mut *const Il2CppCodeRegistration) -> Self {
         let cr = &**raw;
 
         Self {
             raw,
 
+            #[cfg(feature = "metadata_v24_1")]
+            method_pointers: slice::from_raw_parts(
+                cr.methodPointers,
+                cr.methodPointersCount as usize,
+            )
+            .to_vec(),
             generic_method_pointers: slice::from_raw_parts(
                 cr.genericMethodPointers,
                 cr.genericMethodPointersCount as usize,
             )
             .to_vec(),
+            #[cfg(feature = "metadata_v24_5")]
             generic_adjustor_thunks: slice::from_raw_parts(
                 cr.genericAdjustorThunks,
                 cr.genericMethodPointersCount as usize,
             )
             .to_vec(),
+            #[cfg(not(feature = "metadata_v24_5"))]
+            generic_adjustor_thunks: Vec::new(),
             invoker_pointers: slice::from_raw_parts(
                 cr.invokerPointers,
                 cr.invokerPointersCount as usize,
@@ -198,6 +367,7 @@ impl CodeRegistrationBuilder {
                 cr.customAttributeCount as usize,
             )
             .to_vec(),
+            #[cfg(not(feature = "metadata_v24_1"))]
             code_gen_modules: slice::from_raw_parts(
                 cr.codeGenModules,
                 cr.codeGenModulesCount as usize,
@@ -213,14 +383,25 @@ impl CodeRegistrationBuilder {
         }
 
         let mut cr = Box::new(unsafe { **self.raw });
+        #[cfg(feature = "metadata_v24_1")]
+        {
+            let mp = to_raw(self.method_pointers);
+            (cr.methodPointers, cr.methodPointersCount) = (mp.0, mp.1 as _);
+        }
         (cr.genericMethodPointers, cr.genericMethodPointersCount) =
             to_raw(self.generic_method_pointers);
-        (cr.genericAdjustorThunks, _) = to_raw(self.generic_adjustor_thunks);
+        #[cfg(feature = "metadata_v24_5")]
+        {
+            (cr.genericAdjustorThunks, _) = to_raw(self.generic_adjustor_thunks);
+        }
         (cr.invokerPointers, cr.invokerPointersCount) = to_raw(self.invoker_pointers);
         let ca = to_raw(self.custom_attribute_generators);
         (cr.customAttributeGenerators, cr.customAttributeCount) = (ca.0, ca.1 as i32);
-        let cg = to_raw(self.code_gen_modules);
-        (cr.codeGenModules, cr.codeGenModulesCount) = (cg.0 as _, cg.1);
+        #[cfg(not(feature = "metadata_v24_1"))]
+        {
+            let cg = to_raw(self.code_gen_modules);
+            (cr.codeGenModules, cr.codeGenModulesCount) = (cg.0 as _, cg.1);
+        }
 
         let static_ref = Box::leak(cr);
         unsafe {
@@ -5,10 +5,12 @@ use crate::codegen_api::{
     _ZN6il2cpp2vm12ClassInlines19InitFromCodegenSlowEP11Il2CppClass,
     _ZN6il2cpp2vm13MetadataCache34GetTypeInfoFromTypeDefinitionIndexEi,
 };
+#[cfg(feature = "metadata_v24_1")]
+use crate::loader::CODE_REGISTRATION;
 use anyhow::{ensure, Context, Result};
 use il2cpp_types::{
-    FieldInfo, Il2CppClass, Il2CppImage, Il2CppType, Il2CppTypeEnum_IL2CPP_TYPE_CLASS,
-    Il2CppTypeEnum_IL2CPP_TYPE_VALUETYPE, TypeDefinitionIndex,
+    FieldInfo, Il2CppClass, Il2CppType, Il2CppTypeEnum_IL2CPP_TYPE_CLASS,
+    Il2CppTypeEnum_IL2CPP_TYPE_VALUETYPE, MethodInfo, TypeDefinitionIndex,
 };
 use std::{slice, str};
 
@@ -62,12 +64,34 @@ pub fn get_ty_class(ty: &Il2CppType) -> &Il2CppClass {
     get_class_from_idx(unsafe { ty.data.klassIndex })
 }
 
-pub fn get_method_pointer(image: *const Il2CppImage, token: u32) -> Result<unsafe extern "C" fn()> {
-    let rid = token & 0x00FFFFFF;
+/// Finds the code of a method that is not generic
+#[cfg(feature = "metadata_v24_1")]
+pub fn get_method_pointer(method: &MethodInfo) -> Result<unsafe extern "C" fn()> {
+    let method_def = unsafe { &*method.__bindgen_anon_1.methodDefinition };
+    ensure!(method_def.methodIndex >= 0, "method has no code");
+
+    let code_registration = CODE_REGISTRATION
+        .get()
+        .context("il2cpp is not initialized")?;
+    ensure!((method_def.methodIndex as u32) < code_registration.methodPointersCount);
+
+    unsafe {
+        code_registration
+            .methodPointers
+            .add(method_def.methodIndex as usize)
+            .read()
+    }
+    .context("method pointer was null")
+}
+
+/// Finds the code of a method that is not generic
+#[cfg(not(feature = "metadata_v24_1"))]
+pub fn get_method_pointer(method: &MethodInfo) -> Result<unsafe extern "C" fn()> {
+    let rid = method.token & 0x00FFFFFF;
     // let table = token & 0xFF000000;
     ensure!(rid != 0);
 
-    let code_gen_module = unsafe { &*(*image).codeGenModule };
+    let code_gen_module = unsafe { &*(*(*method.klass).image).codeGenModule };
     ensure!(rid <= code_gen_module.methodPointerCount);
 
     unsafe { code_gen_module.methodPointers.add(rid as usize - 1).read() }
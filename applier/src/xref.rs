@@ -32,29 +32,17 @@ impl Il2CppRoot {
     fn get(
         token: u32,
         image_name: &str,
+        metadata: &Metadata,
         code_registration: &CodeRegistrationBuilder,
     ) -> Result<Self> {
-        let rid = 0x00FFFFFF & token;
-        let module = code_registration
-            .find_module(image_name)
-            .context("could not find module for xref trace")?;
-        let method_pointer = unsafe {
-            let ptr = module.methodPointers.add(rid as usize - 1);
-            if ptr.is_null() {
-                None
-            } else {
-                Some(transmute(ptr.read()))
-            }
-        };
-        let invoker = unsafe {
-            let invoker_idx = module.invokerIndices.add(rid as usize - 1).read();
-            if invoker_idx != -1 {
-                Some(transmute(
-                    code_registration.invoker_pointers[invoker_idx as usize],
-                ))
-            } else {
-                None
-            }
+        let (method_pointer, invoker_idx) = code_registration
+            .method_code(metadata, image_name, token)
+            .context("could not find method for xref trace")?;
+        let method_pointer = method_pointer.map(|ptr| unsafe { transmute(ptr) });
+        let invoker = if invoker_idx != -1 {
+            Some(unsafe { transmute(code_registration.invoker_pointers[invoker_idx as usize]) })
+        } else {
+            None
         };
 
         Ok(Self {
@@ -93,7 +81,8 @@ pub fn initialize_roots(
                     .take(&(namespace, class, method_name))
                     .is_some()
                 {
-                    let root = Il2CppRoot::get(method.token, image_name, code_registration)?;
+                    let root =
+                        Il2CppRoot::get(method.token, image_name, metadata, code_registration)?;
                     roots.insert(
                         (
                             namespace.to_string(),
@@ -1,5 +1,9 @@
 use bincode::error::{DecodeError, EncodeError};
 use bincode::{Decode, Encode};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 type ImageDescriptionIdx = usize;
 type TypeDescriptionIdx = usize;
@@ -12,39 +16,180 @@ type GenericInstIdx = usize;
 type GenericMethodInstIdx = usize;
 type GenericClassInstIdx = usize;
 
+/// The sub-revision of metadata version 24 that a unity version's il2cpp emits. The global
+/// metadata header only ever says 24, but the code and metadata registrations differ between
+/// these.
+///
+/// 24.0 (2017.x and 2018.1 - 2018.2) is not supported. It attaches custom attributes to each
+/// definition with a `customAttributeIndex` instead of the token ranges that mod data records
+/// them as.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MetadataRevision {
+    /// 2018.3 - 2018.4, method pointers are in one table for the whole game and the runtime
+    /// generic context is stored in the global metadata
+    #[cfg_attr(feature = "serde", serde(rename = "24.1"))]
+    V24_1,
+    /// 2019.1 - 2019.3.6, code gen modules were introduced
+    #[cfg_attr(feature = "serde", serde(rename = "24.2"))]
+    V24_2,
+    /// 2019.3.7 - 2019.4.14 and 2020.1.0 - 2020.1.10, adds windows runtime factories to the code
+    /// registration
+    #[cfg_attr(feature = "serde", serde(rename = "24.3"))]
+    V24_3,
+    /// 2019.4.15 - 2019.4.20 and 2020.1.11+
+    #[cfg_attr(feature = "serde", serde(rename = "24.4"))]
+    V24_4,
+    /// 2019.4.21+, adds generic adjustor thunks
+    #[cfg_attr(feature = "serde", serde(rename = "24.5"))]
+    V24_5,
+}
+
+/// Splits a unity version like `2019.4.28f1` into its year, minor and patch numbers, its release
+/// kind (alpha, beta, final or patch) and release number. The tuples order the same way as the
+/// versions they were parsed from.
+pub fn parse_unity_version(version: &str) -> Option<(u32, u32, u32, char, u32)> {
+    let mut parts = version.splitn(3, '.');
+    let year = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let rest = parts.next()?;
+    let patch_len = rest.find(|c: char| !c.is_ascii_digit())?;
+    let patch = rest[..patch_len].parse().ok()?;
+    let mut suffix = rest[patch_len..].chars();
+    let kind = suffix.next()?;
+    if !matches!(kind, 'a' | 'b' | 'f' | 'p') {
+        return None;
+    }
+    let release = suffix.as_str().parse().ok()?;
+    Some((year, minor, patch, kind, release))
+}
+
+impl MetadataRevision {
+    /// The metadata revision used by a unity version, or `None` if the version is malformed or
+    /// its il2cpp does not emit a supported revision of metadata version 24
+    pub fn from_unity_version(version: &str) -> Option<Self> {
+        let (year, minor, patch, _, _) = parse_unity_version(version)?;
+        Some(match (year, minor, patch) {
+            (2020, 1, patch) if patch >= 11 => MetadataRevision::V24_4,
+            (2020, 1, _) => MetadataRevision::V24_3,
+            (2019, 4, patch) if patch >= 21 => MetadataRevision::V24_5,
+            (2019, 4, patch) if patch >= 15 => MetadataRevision::V24_4,
+            (2019, 4, _) => MetadataRevision::V24_3,
+            (2019, 3, patch) if patch >= 7 => MetadataRevision::V24_3,
+            (2019, _, _) => MetadataRevision::V24_2,
+            (2018, 3..=4, _) => MetadataRevision::V24_1,
+            _ => return None,
+        })
+    }
+
+    /// Whether the code registration has a code gen module for each image
+    pub fn has_code_gen_modules(self) -> bool {
+        self >= MetadataRevision::V24_2
+    }
+
+    /// Whether the code registration and generic method indices have adjustor thunks
+    pub fn has_generic_adjustor_thunks(self) -> bool {
+        self >= MetadataRevision::V24_5
+    }
+}
+
+impl fmt::Display for MetadataRevision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let revision = match self {
+            MetadataRevision::V24_1 => "24.1",
+            MetadataRevision::V24_2 => "24.2",
+            MetadataRevision::V24_3 => "24.3",
+            MetadataRevision::V24_4 => "24.4",
+            MetadataRevision::V24_5 => "24.5",
+        };
+        f.write_str(revision)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseMetadataRevisionError;
+
+impl fmt::Display for ParseMetadataRevisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("metadata revision must be one of 24.1, 24.2, 24.3, 24.4 or 24.5")
+    }
+}
+
+impl std::error::Error for ParseMetadataRevisionError {}
+
+impl FromStr for MetadataRevision {
+    type Err = ParseMetadataRevisionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "24.1" => MetadataRevision::V24_1,
+            "24.2" => MetadataRevision::V24_2,
+            "24.3" => MetadataRevision::V24_3,
+            "24.4" => MetadataRevision::V24_4,
+            "24.5" => MetadataRevision::V24_5,
+            _ => return Err(ParseMetadataRevisionError),
+        })
+    }
+}
+
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ImageDescription {
     pub name: String,
 }
 
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum DeclaringType {
+    TypeDef(TypeDefDescriptionIdx),
+    /// Types nested in a generic class are declared by the class instantiated over its own
+    /// generic parameters
+    GenericClass(GenericClassInstIdx),
+}
+
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct TypeDefDescription {
     pub image: ImageDescriptionIdx,
-    pub decl_type: Option<TypeDefDescriptionIdx>,
+    pub decl_type: Option<DeclaringType>,
     pub name: String,
     pub namespace: String,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum GenericParamOwner {
     Class(TypeDefDescriptionIdx),
     Method(MethodDescriptionIdx),
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum TypeDescriptionData {
     /// for VALUETYPE and CLASS
     TypeDefIdx(TypeDefDescriptionIdx),
     /// for PTR and SZARRAY
     TypeIdx(TypeDescriptionIdx),
-    // TODO: Arrays
+    /// for ARRAY
+    Array(ArrayTypeDescription),
     /// for VAR and MVAR
     GenericParam(GenericContainerOwner, u16),
     /// for GENERICINST
     GenericClass(GenericClassInstIdx),
 }
 
+/// The shape of a multi-dimensional array, mirroring `Il2CppArrayType`
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ArrayTypeDescription {
+    pub elem_ty: TypeDescriptionIdx,
+    pub rank: u8,
+    pub sizes: Vec<i32>,
+    pub lower_bounds: Vec<i32>,
+}
+
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct TypeDescription {
     pub data: TypeDescriptionData,
     pub attrs: u16,
@@ -54,6 +199,7 @@ pub struct TypeDescription {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct MethodDescription {
     pub defining_type: TypeDefDescriptionIdx,
     pub name: String,
@@ -63,12 +209,14 @@ pub struct MethodDescription {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct FieldDescription {
     pub defining_type: TypeDescriptionIdx,
     pub idx: u32,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedAssembly {
     // Il2CppAssemblyNameDefinition
     pub name: String,
@@ -87,12 +235,14 @@ pub struct AddedAssembly {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedImage {
     pub name: String,
     pub token: u32,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedField {
     pub name: String,
     pub ty: TypeDescriptionIdx,
@@ -101,6 +251,7 @@ pub struct AddedField {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedParameter {
     pub name: String,
     pub token: u32,
@@ -109,6 +260,7 @@ pub struct AddedParameter {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedMethod {
     pub name: String,
     pub declaring_type: TypeDefDescriptionIdx,
@@ -122,6 +274,7 @@ pub struct AddedMethod {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedEvent {
     pub name: String,
     pub ty: TypeDescriptionIdx,
@@ -132,6 +285,7 @@ pub struct AddedEvent {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedProperty {
     pub name: String,
     pub get: MethodDescriptionIdx,
@@ -141,7 +295,10 @@ pub struct AddedProperty {
 }
 
 #[derive(Encode, Decode, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum EncodedMethodIndex {
+    /// Used for empty vtable slots, which don't refer to anything
+    Invalid,
     Il2CppClass(TypeDescriptionIdx),
     Il2CppType(TypeDescriptionIdx),
     MethodInfo(MethodDescriptionIdx),
@@ -150,7 +307,29 @@ pub enum EncodedMethodIndex {
     MethodRef(GenericMethodInstIdx),
 }
 
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum RgctxData {
+    Type(TypeDescriptionIdx),
+    Class(TypeDescriptionIdx),
+    Method(GenericMethodInstIdx),
+    /// The element type of the array
+    Array(TypeDescriptionIdx),
+}
+
+/// An entry of the mod's `s_rgctxValues`, which the loader fills in once everything it refers to
+/// has been resolved
+#[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RgctxDefinition {
+    /// The token of the type or method definition whose runtime generic context this belongs to,
+    /// which provides the generic parameters for resolving the data
+    pub owner_token: u32,
+    pub data: RgctxData,
+}
+
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedTypeDefinition {
     pub name: String,
     pub namespace: String,
@@ -181,18 +360,21 @@ pub struct AddedTypeDefinition {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedMetadataUsagePair {
     pub source: EncodedMethodIndex,
     pub dest: usize,
 }
 
 #[derive(Encode, Decode, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum GenericContainerOwner {
     Class(TypeDefDescriptionIdx),
     Method(MethodDescriptionIdx),
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedGenericParameter {
     pub name: String,
     pub constraints: Vec<TypeDescriptionIdx>,
@@ -200,29 +382,34 @@ pub struct AddedGenericParameter {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedGenericContainer {
     pub owner: GenericContainerOwner,
     pub parameters: Vec<AddedGenericParameter>,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct GenericInst {
     pub types: Vec<TypeDescriptionIdx>,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct GenericContext {
     pub class: Option<GenericInstIdx>,
     pub method: Option<GenericInstIdx>,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct GenericMethodInst {
     pub method: MethodDescriptionIdx,
     pub context: GenericContext,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct GenericMethodFunctions {
     pub generic_method: GenericMethodInstIdx,
 
@@ -232,18 +419,21 @@ pub struct GenericMethodFunctions {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct GenericClassInst {
     pub class: Option<TypeDefDescriptionIdx>,
     pub context: GenericContext,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct CustomAttributeTypeRange {
     pub token: u32,
     pub types: Vec<TypeDescriptionIdx>,
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct CodeTableSizes {
     pub generic_adjustor_thunks: usize,
     pub generic_method_pointers: usize,
@@ -253,7 +443,9 @@ pub struct CodeTableSizes {
 }
 
 #[derive(Encode, Decode, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct MergeModData {
+    pub metadata_revision: MetadataRevision,
     pub code_table_sizes: CodeTableSizes,
 
     // Linkage information
@@ -281,6 +473,7 @@ pub struct MergeModData {
     pub generic_method_insts: Vec<GenericMethodInst>,
     pub generic_method_funcs: Vec<GenericMethodFunctions>,
     pub generic_class_insts: Vec<GenericClassInst>,
+    pub rgctx_values: Vec<RgctxDefinition>,
 }
 
 impl MergeModData {
@@ -293,3 +486,46 @@ impl MergeModData {
         bincode::decode_from_slice(data, bincode::config::standard()).map(|(data, _)| data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_unity_versions() {
+        let mut versions = [
+            "2019.4.28f1",
+            "2019.4.9f1",
+            "2020.1.0b12",
+            "2019.4.28p1",
+            "2020.1.0f1",
+        ];
+        versions.sort_by_key(|version| parse_unity_version(version).unwrap());
+        assert_eq!(
+            versions,
+            [
+                "2019.4.9f1",
+                "2019.4.28f1",
+                "2019.4.28p1",
+                "2020.1.0b12",
+                "2020.1.0f1",
+            ]
+        );
+        assert_eq!(parse_unity_version("2019.4"), None);
+        assert_eq!(parse_unity_version("2019.4.28"), None);
+        assert_eq!(parse_unity_version("2019.4.28x1"), None);
+    }
+
+    #[test]
+    fn finds_metadata_revisions() {
+        let revision = MetadataRevision::from_unity_version;
+        assert_eq!(revision("2018.2.21f1"), None);
+        assert_eq!(revision("2018.4.36f1"), Some(MetadataRevision::V24_1));
+        assert_eq!(revision("2019.3.6f1"), Some(MetadataRevision::V24_2));
+        assert_eq!(revision("2019.3.7f1"), Some(MetadataRevision::V24_3));
+        assert_eq!(revision("2019.4.15f1"), Some(MetadataRevision::V24_4));
+        assert_eq!(revision("2019.4.28f1"), Some(MetadataRevision::V24_5));
+        assert_eq!(revision("2020.1.11f1"), Some(MetadataRevision::V24_4));
+        assert_eq!(revision("2020.2.0f1"), None);
+    }
+}
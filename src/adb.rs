@@ -1,4 +1,4 @@
-use crate::config::Config;
+use crate::config::{Config, Setting};
 use crate::manifest::Manifest;
 use color_eyre::eyre::{anyhow, Result};
 use std::fs::File;
@@ -35,6 +35,31 @@ impl Adb {
         self.check_status(status)
     }
 
+    fn pull(&self, from: &str, to: &str) -> Result<()> {
+        let status = Command::new(&self.path)
+            .arg("pull")
+            .arg(from)
+            .arg(to)
+            .status()?;
+        self.check_status(status)
+    }
+
+    /// The directory the app's apk was installed to
+    fn app_dir(&self, id: &str) -> Result<String> {
+        let output = Command::new(&self.path)
+            .args(["shell", "pm", "path"])
+            .arg(id)
+            .output()?;
+        self.check_status(output.status)?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let apk_path = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("package:"))
+            .filter(|path| path.ends_with("/base.apk"))
+            .ok_or_else(|| anyhow!("could not find the apk of {}", id))?;
+        Ok(apk_path.trim_end_matches("/base.apk").to_string())
+    }
+
     fn restart_app(&self, id: &str) -> Result<()> {
         let status = Command::new(&self.path)
             .args(["shell", "am", "start", "-S"])
@@ -60,6 +85,7 @@ pub fn upload(config: &mut Config) -> Result<()> {
     let mod_id = &manifest.plugin.id;
     let app = &manifest.plugin.app;
 
+    config.ensure_settings(&[Setting::AdbPath])?;
     let adb = Adb {
         path: config.get_adb_path()?,
     };
@@ -76,10 +102,36 @@ pub fn upload(config: &mut Config) -> Result<()> {
     Ok(())
 }
 
+/// Copies the game's unmodified `global-metadata.dat` from the device to `to`
+pub fn pull_metadata(config: &mut Config, app: &str, to: &str) -> Result<()> {
+    config.ensure_settings(&[Setting::AdbPath])?;
+    let adb = Adb {
+        path: config.get_adb_path()?,
+    };
+
+    let metadata_path = format!(
+        "/sdcard/Android/data/{}/files/il2cpp/Metadata/global-metadata.dat",
+        app
+    );
+    adb.pull(&metadata_path, to)
+}
+
+/// Copies the game's `libil2cpp.so` from the device to `to`
+pub fn pull_libil2cpp(config: &mut Config, app: &str, to: &str) -> Result<()> {
+    config.ensure_settings(&[Setting::AdbPath])?;
+    let adb = Adb {
+        path: config.get_adb_path()?,
+    };
+
+    let lib_path = format!("{}/lib/arm64/libil2cpp.so", adb.app_dir(app)?);
+    adb.pull(&lib_path, to)
+}
+
 pub fn start_and_log(config: &mut Config) -> Result<()> {
     let manifest = Manifest::load()?;
     let app = &manifest.plugin.app;
 
+    config.ensure_settings(&[Setting::AdbPath])?;
     let adb = Adb {
         path: config.get_adb_path()?,
     };
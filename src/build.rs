@@ -1,49 +1,49 @@
 mod clang;
 mod codegen;
+mod csharp;
 mod custom_attributes;
 mod data;
 mod function_usages;
 mod generics;
+mod il2cpp;
+mod libil2cpp;
 mod metadata_usage;
 mod parser;
+mod profile;
 mod runtime_metadata;
+mod scripts;
+mod shims;
+mod tokenizer;
 mod type_sizes;
 
-use crate::config::Config;
-use crate::manifest::{Manifest, Mod};
+use crate::config::{Config, Setting};
+use crate::manifest::Manifest;
+use crate::repository;
 use crate::utils::platform_executable;
 use clang::CompileCommand;
+pub use clang::{applier_path, clang_path};
 use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
-use data::{get_str, offset_len, ModDataBuilder, RuntimeMetadata};
+pub use data::{get_str, offset_len};
+use data::{ModDataBuilder, RuntimeMetadata};
 use function_usages::ModFunctionUsages;
+use il2cpp::{Il2Cpp, Il2CppOptions};
 use il2cpp_metadata_raw::{Il2CppImageDefinition, Metadata};
-use merge_data::CodeTableSizes;
-use parser::{is_included_ty, try_parse_call, FnDecl};
+use merge_data::{CodeTableSizes, MetadataRevision};
+use parser::{
+    find_struct_defs, is_included_ty, parse_items, parse_ty, FieldDef, FnDecl, StructDef,
+};
+use profile::Profile;
 use runtime_metadata::TypeDefinitionsFile;
+use scripts::{ScriptRunner, Stage};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::str::Lines;
-use std::{fs, str};
-use tracing::debug;
+use std::{fs, str, thread};
+use tokenizer::SourceFile;
 
 const CODGEN_HEADER: &str = include_str!("../include/merge/codegen.h");
 
-fn get_function_usages<'a>(usages: &mut HashSet<&'a str>, lines: &mut Lines<'a>) {
-    loop {
-        let line = lines.next().unwrap();
-        if line == "}" {
-            return;
-        }
-        if let Some(name) = try_parse_call(line, false) {
-            if !usages.contains(name) {
-                usages.insert(name);
-            }
-        }
-    }
-}
-
 fn find_method_with_rid(
     metadata: &Metadata,
     image: &Il2CppImageDefinition,
@@ -70,13 +70,32 @@ fn find_image<'md>(metadata: &'md Metadata, find_name: &str) -> Result<&'md Il2C
     bail!("could not find image: {}", find_name);
 }
 
-fn get_numbered_paths(source_paths: &mut Vec<String>, cpp_path: &Path, name: &str) {
-    for i in 0.. {
-        let name = if i > 0 {
+/// The name of the `i`th source il2cpp split `name`'s code into
+fn numbered_name(revision: MetadataRevision, name: &str, i: usize) -> String {
+    if revision.has_code_gen_modules() {
+        if i > 0 {
             format!("{}{}", name, i)
         } else {
             name.to_string()
-        };
+        }
+    } else {
+        // il2cpp before 2019 numbers every source from 0 and prefixes the assemblies' with `Bulk_`
+        match name {
+            "GenericMethods" => format!("{}{}", name, i),
+            "Il2CppCompilerCalculateTypeValues" => format!("{}_{}Table", name, i),
+            _ => format!("Bulk_{}_{}", name, i),
+        }
+    }
+}
+
+fn get_numbered_paths(
+    source_paths: &mut Vec<String>,
+    cpp_path: &Path,
+    name: &str,
+    revision: MetadataRevision,
+) {
+    for i in 0.. {
+        let name = numbered_name(revision, name, i);
         let path = cpp_path.join(format!("{}.cpp", name));
         if path.exists() {
             source_paths.push(name);
@@ -86,58 +105,6 @@ fn get_numbered_paths(source_paths: &mut Vec<String>, cpp_path: &Path, name: &st
     }
 }
 
-pub struct StructDef<'src> {
-    body: String,
-    parent: Option<&'src str>,
-}
-
-fn find_struct_defs(sources: &[String]) -> (HashSet<&str>, HashMap<&str, StructDef>) {
-    let mut struct_fds = HashSet::new();
-    let mut struct_defs = HashMap::new();
-    for src in sources {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            let line = line.trim_end();
-
-            let name = if let Some(name) = line.strip_prefix("struct ") {
-                name
-            } else {
-                continue;
-            };
-            if let Some(name) = name.strip_suffix(';') {
-                struct_fds.insert(name);
-            } else {
-                let without_generic = name
-                    .trim_start_matches("Generic")
-                    .trim_start_matches("Virt")
-                    .trim_start_matches("Interface");
-                if without_generic.starts_with("FuncInvoker")
-                    || without_generic.starts_with("ActionInvoker")
-                {
-                    continue;
-                }
-
-                let parent = name.find(':').map(|pos| &name[pos + 9..]);
-                let name = name.split(':').next().unwrap().trim();
-                struct_defs.entry(name).or_insert_with(|| {
-                    lines.next().unwrap();
-                    let mut body = String::new();
-                    for line in lines.by_ref() {
-                        if line == "};" {
-                            break;
-                        }
-                        body.push_str(line);
-                        body.push('\n');
-                    }
-
-                    StructDef { body, parent }
-                });
-            }
-        }
-    }
-    (struct_fds, struct_defs)
-}
-
 fn add_cpp_ty<'a>(
     writer: &mut String,
     src: &'a str,
@@ -145,13 +112,20 @@ fn add_cpp_ty<'a>(
     fd_structs: &mut HashSet<&'a str>,
     added_structs: &mut HashSet<&'a str>,
 ) -> Result<()> {
-    let mut words = src.trim_start_matches("const ").split_whitespace();
-    let ty = words.next().unwrap().trim_end_matches('*');
-    if ty == "typedef" {
-        panic!()
-    }
+    let ty = parse_ty(src)?;
+    add_struct(writer, ty, struct_defs, fd_structs, added_structs)
+}
+
+fn add_struct<'a>(
+    writer: &mut String,
+    ty: FieldDef<'a>,
+    struct_defs: &'a HashMap<&str, StructDef>,
+    fd_structs: &mut HashSet<&'a str>,
+    added_structs: &mut HashSet<&'a str>,
+) -> Result<()> {
+    let FieldDef { ty, pointer } = ty;
     if !is_included_ty(ty) && !added_structs.contains(ty) {
-        if src.contains('*') {
+        if pointer {
             if fd_structs.contains(ty) {
                 return Ok(());
             }
@@ -162,35 +136,14 @@ fn add_cpp_ty<'a>(
                 .get(ty)
                 .with_context(|| format!("could not find cpp type: {}", ty))?;
             if let Some(parent) = struct_def.parent {
-                if parent == "MethodInfo_t" {
-                    dbg!(parent);
-                }
-                add_cpp_ty(writer, parent, struct_defs, fd_structs, added_structs)?;
-            }
-            for line in struct_def.body.lines() {
-                let line = line.trim();
-                if line.starts_with("union")
-                    || line.starts_with("struct")
-                    || line.starts_with("public:")
-                    || line.starts_with("//")
-                    || line.starts_with('#')
-                    || line.starts_with('}')
-                    || line.starts_with('{')
-                {
-                    continue;
-                }
-                if line.is_empty() {
-                    break;
-                }
-                let line = if line.starts_with("ALIGN_FIELD") {
-                    let end = line.find(')').with_context(|| {
-                        format!("could not find end of ALIGN_FIELD in '{}'", line)
-                    })?;
-                    &line[end + 2..]
-                } else {
-                    line
+                let parent = FieldDef {
+                    ty: parent,
+                    pointer: false,
                 };
-                add_cpp_ty(writer, line, struct_defs, fd_structs, added_structs)?;
+                add_struct(writer, parent, struct_defs, fd_structs, added_structs)?;
+            }
+            for &field in &struct_def.fields {
+                add_struct(writer, field, struct_defs, fd_structs, added_structs)?;
             }
             if let Some(parent) = struct_def.parent {
                 writeln!(
@@ -208,49 +161,58 @@ fn add_cpp_ty<'a>(
     Ok(())
 }
 
-fn convert_codegen_init_method(source: &str, mod_id: &str, write_header: bool) -> Result<String> {
+/// Makes `il2cpp_codegen_initialize_method` calls go through the modloader, which needs to
+/// know what mod the method belongs to
+fn convert_codegen_init_method(
+    file: &SourceFile,
+    mod_id: &str,
+    write_header: bool,
+) -> Result<String> {
+    let src = file.src.trim_start_matches('\u{FEFF}');
+    let tokens = SourceFile::new(file.name, src).tokenize()?;
+
     let mut new_src = String::new();
     if write_header {
         writeln!(new_src, "#include \"merge/codegen.h\"")?;
     }
-    for line in source.lines() {
-        if line.trim().starts_with("il2cpp_codegen_initialize_method") {
-            let mut line = line.replace(
-                "il2cpp_codegen_initialize_method",
-                "merge_codegen_initialize_method",
-            );
-            let paren_idx = line
-                .find('(')
-                .expect("could not find '(' for il2cpp_codegen_initialize_method");
-            line.insert_str(paren_idx + 1, &format!("\"{}\", ", mod_id));
-            writeln!(new_src, "{}", line)?;
-        } else {
-            writeln!(new_src, "{}", line.trim_start_matches('\u{FEFF}'))?;
+    let mut pos = 0;
+    for pair in tokens.windows(2) {
+        if pair[0].is_ident("il2cpp_codegen_initialize_method") && pair[1].is_punct("(") {
+            new_src.push_str(&src[pos..pair[0].offset]);
+            write!(new_src, "merge_codegen_initialize_method(\"{}\", ", mod_id)?;
+            pos = pair[1].end();
         }
     }
+    new_src.push_str(&src[pos..]);
     Ok(new_src)
 }
 
-fn copy_input(mod_config: &Mod, input_dir: String) -> Result<()> {
-    let input_dir = Path::new(&input_dir);
-    let file_name = mod_config.id.clone() + ".dll";
-    let managed_path = PathBuf::from("./build/Managed").join(&file_name);
-    fs::copy(input_dir.join(file_name), managed_path)?;
-
-    Ok(())
-}
+pub fn build(
+    regen_cpp: Option<String>,
+    jobs: Option<usize>,
+    debug: bool,
+    config: &mut Config,
+) -> Result<()> {
+    if csharp::is_nested_build() {
+        // Directory.Build.targets runs `qmerge build` after compiling, but we're already in one
+        println!("qmerge is already building this mod, skipping");
+        return Ok(());
+    }
 
-pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     let manifest = Manifest::load()?;
     let mod_config = &manifest.plugin;
+    config.ensure_settings(&[Setting::NdkPath, Setting::App(&mod_config.app)])?;
     let app = config.get_app(&mod_config.app)?;
+    let metadata_revision = app.metadata_revision()?;
     let unity_install = config.get_unity_install(&app.unity_version)?;
 
     let unity_path = PathBuf::from(unity_install);
 
     let transformed_path = Path::new("./build/sources/transformed");
     let out_path = Path::new("./build/bin/out");
-    let obj_path = Path::new("./build/bin/obj");
+    let profile = Profile::resolve(debug, &manifest.profile)?;
+    // Each profile gets its own objects so switching between them doesn't invalidate the cache
+    let obj_path = &Path::new("./build/bin/obj").join(profile.name);
     fs::create_dir_all(transformed_path)?;
     fs::create_dir_all(out_path)?;
     fs::create_dir_all(obj_path)?;
@@ -262,44 +224,59 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     let output_so_path = out_path.join(format!("{}.so", mod_config.id));
     let target = "aarch64-linux-android21";
     let ndk_path = config.get_ndk_path()?;
-    let mut compile_command = CompileCommand::new(&ndk_path, output_so_path, obj_path, target);
+    let mut compile_command =
+        CompileCommand::new(&ndk_path, output_so_path, obj_path, target, &profile);
     compile_command.add_include_path(unity_path.join("Editor/Data/il2cpp/libil2cpp"));
     compile_command.add_include_path(include_path.into());
+    compile_command.add_native(&manifest.native)?;
 
     let cpp_path = Path::new("./build/sources/cpp");
-    if let Some(input_dir) = regen_cpp {
-        let mut mono_path = unity_path.join("Editor/Data/MonoBleedingEdge/bin/mono");
-        platform_executable(&mut mono_path);
-        let il2cpp_path = unity_path.join("Editor/Data/il2cpp/build/deploy/net471/il2cpp.exe");
-
-        copy_input(mod_config, input_dir)?;
-        if cpp_path.exists() {
-            fs::remove_dir_all(&cpp_path)?;
-        }
-        fs::create_dir_all(&cpp_path)?;
-        let mut command = if config.get_use_system_mono()? {
-            Command::new("mono")
+    let mut mono_path = unity_path.join("Editor/Data/MonoBleedingEdge/bin/mono");
+    platform_executable(&mut mono_path);
+    let il2cpp = Il2Cpp {
+        mono_path: if config.get_use_system_mono()? {
+            PathBuf::from("mono")
         } else {
-            Command::new(mono_path)
-        };
-        // Fix for System.ConsoleDriver type initializer
-        command.env("TERM", "xterm")
-            // Rider adds this which breaks things apparently
-            .env_remove("MONO_GAC_PREFIX")
-            .arg(il2cpp_path)
-            .arg("--convert-to-cpp")
-            .arg("--directory=./build/Managed")
-            .arg("--generatedcppdir=./build/sources/cpp");
-        debug!("il2cpp command: {:?}", &command);
-        command
-            .status()
-            .context("il2cpp command failed")?;
-    }
+            mono_path
+        },
+        il2cpp_path: unity_path.join("Editor/Data/il2cpp/build/deploy/net471/il2cpp.exe"),
+        unity_version: app.unity_version.clone(),
+        options: Il2CppOptions::resolve(&manifest, debug),
+    };
+    let scripts = ScriptRunner::new(
+        &manifest.scripts,
+        &mod_config.id,
+        profile.name,
+        cpp_path,
+        transformed_path,
+        out_path,
+    )?;
+    scripts.run(Stage::PreBuild)?;
+    repository::install_dependencies(&manifest, config)?;
+    // A dll given on the command line was already compiled by the C# project
+    let input_dir = match regen_cpp {
+        Some(input_dir) => Some(PathBuf::from(input_dir)),
+        None => csharp::build(&mod_config.id, debug)?,
+    };
+    il2cpp.regenerate(mod_config, input_dir, cpp_path)?;
+    scripts.run(Stage::PostIl2Cpp)?;
 
     let metadata_data = fs::read(cpp_path.join("Data/Metadata/global-metadata.dat"))
         .context("failed to read generated metadata")?;
+    let (metadata_data, metadata_code) =
+        crate::metadata::read(&metadata_data).context("failed to read generated metadata")?;
+    if metadata_code.is_some() != (metadata_revision == MetadataRevision::V24_1) {
+        bail!(
+            "the generated metadata is not revision {}, override the app's `metadata_revision`",
+            metadata_revision
+        );
+    }
     let metadata = il2cpp_metadata_raw::deserialize(&metadata_data)
         .context("failed to deserialize generated metadata")?;
+    if let Some(metadata_code) = &metadata_code {
+        codegen::write_v24_1_modules(cpp_path, &metadata, metadata_code)
+            .context("error writing code gen modules")?;
+    }
     let mod_image_name = format!("{}.dll", mod_config.id);
     let mod_image = find_image(&metadata, &mod_image_name)?;
 
@@ -316,7 +293,8 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     let gmd_src = fs::read_to_string(cpp_path.join("Il2CppGenericMethodDefinitions.c"))?;
     let generic_method_defs = runtime_metadata::parse_generic_method_defs(&gmd_src)?;
     let gmt_src = fs::read_to_string(cpp_path.join("Il2CppGenericMethodTable.c"))?;
-    let generic_method_table = runtime_metadata::parse_generic_method_table(&gmt_src)?;
+    let generic_method_table =
+        runtime_metadata::parse_generic_method_table(&gmt_src, metadata_revision)?;
 
     let runtime_metadata = RuntimeMetadata {
         types: &types,
@@ -343,7 +321,12 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     .context("error transforming codegen")?;
 
     let mut codegen_source_names = Vec::new();
-    get_numbered_paths(&mut codegen_source_names, cpp_path, &mod_config.id);
+    get_numbered_paths(
+        &mut codegen_source_names,
+        cpp_path,
+        &mod_config.id,
+        metadata_revision,
+    );
     let mut codegen_sources = Vec::new();
     let mut extern_code_source_names = Vec::new();
     for assembly in &metadata.assemblies {
@@ -351,16 +334,22 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
         if name == mod_config.id {
             continue;
         }
-        get_numbered_paths(&mut extern_code_source_names, cpp_path, name);
-        let code_gen_src = fs::read_to_string(cpp_path.join(format!("{}_CodeGen.c", name)))
+        get_numbered_paths(
+            &mut extern_code_source_names,
+            cpp_path,
+            name,
+            metadata_revision,
+        );
+        let code_gen_name = format!("{}_CodeGen.c", name);
+        let code_gen_src = fs::read_to_string(cpp_path.join(&code_gen_name))
             .with_context(|| format!("error opening CodeGen.c file for module {}", name))?;
-        codegen_sources.push((assembly.image_index, code_gen_src))
+        codegen_sources.push((assembly.image_index, code_gen_name, code_gen_src))
     }
 
     // Populate list of all external method pointers by reading CodeGen.c for all modules except the mod's
-    for (image_idx, src) in &codegen_sources {
+    for (image_idx, name, src) in &codegen_sources {
         let image = &metadata.images[*image_idx as usize];
-        let method_pointers = codegen::get_methods(src)?;
+        let method_pointers = codegen::get_methods(name, src)?;
 
         for (idx, method_pointer) in method_pointers.iter().enumerate() {
             if let Some(method_pointer) = method_pointer {
@@ -374,7 +363,12 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     }
 
     let mut mod_source_names = Vec::new();
-    get_numbered_paths(&mut mod_source_names, cpp_path, &mod_config.id);
+    get_numbered_paths(
+        &mut mod_source_names,
+        cpp_path,
+        &mod_config.id,
+        metadata_revision,
+    );
     let mut mod_sources = Vec::new();
     for name in &mod_source_names {
         let src_path = cpp_path.join(name).with_extension("cpp");
@@ -382,44 +376,49 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
         let source = fs::read_to_string(&src_path)?;
         fs::write(
             &new_path,
-            convert_codegen_init_method(&source, &mod_config.id, true)?,
+            convert_codegen_init_method(&SourceFile::new(name, &source), &mod_config.id, true)?,
         )?;
         compile_command.add_source(new_path);
-        mod_sources.push(fs::read_to_string(src_path)?);
+        mod_sources.push(source);
     }
 
+    let mod_items = mod_source_names
+        .iter()
+        .zip(&mod_sources)
+        .map(|(name, src)| parse_items(&SourceFile::new(name, src)))
+        .collect::<Result<Vec<_>>>()?;
     // TODO: populate mod_functions by using CodeGen.c
-    for src in &mod_sources {
-        for line in src.lines() {
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if !line.ends_with(';') {
-                    function_usages.mod_functions.insert(fn_def.name);
-                }
+    for item in mod_items.iter().flatten() {
+        if let Some(fn_decl) = &item.fn_decl {
+            if fn_decl.extern_c && item.is_fn_definition() {
+                function_usages.mod_functions.insert(fn_decl.name);
             }
         }
     }
     let mut metadata_usage_names = HashSet::new();
     let mut mod_usages = HashSet::new();
-    for (src_name, src) in mod_source_names.iter().zip(mod_sources.iter()) {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if line.ends_with(';') {
-                    function_usages
-                        .forward_decls
-                        .insert(fn_def.name, line.trim_end_matches(';'));
-                } else if !fn_def.name.ends_with("_AdjustorThunk") {
-                    lines.next().unwrap();
-                    if fn_def.inline {
-                        metadata_usage_names
-                            .insert(fn_def.name.trim_end_matches("_inline").to_string() + src_name);
-                    } else {
-                        metadata_usage_names.insert(fn_def.name.to_string());
-                    }
-                    get_function_usages(&mut mod_usages, &mut lines);
+    for (src_name, items) in mod_source_names.iter().zip(&mod_items) {
+        for item in items {
+            let fn_decl = match &item.fn_decl {
+                Some(fn_decl) => fn_decl,
+                None => continue,
+            };
+            if !fn_decl.extern_c {
+                if fn_decl.inline && item.is_fn_definition() {
+                    function_usages.add_gshared_proxy(item)?;
                 }
-            } else if line.starts_with("inline") {
-                function_usages.add_gshared_proxy(line, &mut lines);
+            } else if !item.is_fn_definition() {
+                function_usages
+                    .forward_decls
+                    .insert(fn_decl.name, item.head);
+            } else if !fn_decl.name.ends_with("_AdjustorThunk") {
+                if fn_decl.inline {
+                    metadata_usage_names
+                        .insert(fn_decl.name.trim_end_matches("_inline").to_string() + src_name);
+                } else {
+                    metadata_usage_names.insert(fn_decl.name.to_string());
+                }
+                mod_usages.extend(item.calls(false));
             }
         }
     }
@@ -440,33 +439,60 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
 
     // Read generic sources
     let mut generic_source_names = Vec::new();
-    get_numbered_paths(&mut generic_source_names, cpp_path, "GenericMethods");
-    get_numbered_paths(&mut generic_source_names, cpp_path, "Generics");
+    get_numbered_paths(
+        &mut generic_source_names,
+        cpp_path,
+        "GenericMethods",
+        metadata_revision,
+    );
+    get_numbered_paths(
+        &mut generic_source_names,
+        cpp_path,
+        "Generics",
+        metadata_revision,
+    );
     let mut generic_sources = Vec::new();
     for name in &generic_source_names {
         let path = cpp_path.join(name).with_extension("cpp");
         let source = fs::read_to_string(path)?;
-        generic_sources.push(convert_codegen_init_method(&source, &mod_config.id, false)?);
+        generic_sources.push(convert_codegen_init_method(
+            &SourceFile::new(name, &source),
+            &mod_config.id,
+            false,
+        )?);
     }
 
     // Find all struct definitions and fds
     let mut extern_code_sources = Vec::new();
     for name in extern_code_source_names {
-        let src_path = cpp_path.join(format!("{}.cpp", name));
-        extern_code_sources.push(fs::read_to_string(src_path)?);
+        let file_name = format!("{}.cpp", name);
+        let src = fs::read_to_string(cpp_path.join(&file_name))?;
+        extern_code_sources.push((file_name, src));
     }
-    let (_, struct_defs) = find_struct_defs(&extern_code_sources);
+    let (_, struct_defs) = find_struct_defs(
+        extern_code_sources
+            .iter()
+            .map(|(name, src)| (name.as_str(), src.as_str())),
+    )?;
 
     let gen_method_ptrs_src =
         fs::read_to_string(cpp_path.join("Il2CppGenericMethodPointerTable.cpp"))?;
-    let gen_method_ptr_table = generics::read_method_ptr_table(&gen_method_ptrs_src)?;
-
-    let gen_adj_thunks = function_usages.write_generic_adj_thunk_table(
-        &mut compile_command,
-        transformed_path,
-        cpp_path,
+    let gen_method_ptr_table = generics::read_method_ptr_table(
+        "Il2CppGenericMethodPointerTable.cpp",
+        &gen_method_ptrs_src,
     )?;
 
+    let gen_adj_thunks = if metadata_revision.has_generic_adjustor_thunks() {
+        function_usages.write_generic_adj_thunk_table(
+            &mut compile_command,
+            transformed_path,
+            cpp_path,
+        )?
+    } else {
+        HashSet::new()
+    };
+
+    let shims = shims::find_shims(config, mod_config, &metadata, &app, metadata_revision)?;
     let generic_transform_data = generics::transform(
         &mut function_usages,
         &mut data_builder,
@@ -474,7 +500,7 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
         &generic_source_names,
         &generic_sources,
         &gen_method_ptr_table,
-        &app.shims,
+        &shims,
         &gen_adj_thunks,
     )?;
 
@@ -501,7 +527,7 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
     };
 
     data_builder.process_generic_funcs(&mut function_usages);
-    let mod_data = data_builder.build(&manifest, code_table_sizes)?;
+    let mod_data = data_builder.build(&manifest, metadata_revision, code_table_sizes)?;
     // println!("{:#?}", &mod_data);
     function_usages.write_invokers(&mut compile_command, transformed_path, cpp_path)?;
     function_usages.write_generic_func_table(
@@ -515,17 +541,30 @@ pub fn build(regen_cpp: Option<String>, config: &mut Config) -> Result<()> {
         &mut compile_command,
         &usage_fds,
         &generic_source_names,
-        &generic_sources,
         &function_usages,
         gen_adj_thunks,
     )?;
-    type_sizes::transform(&mut compile_command, mod_image, cpp_path, transformed_path)?;
+    type_sizes::transform(
+        &mut compile_command,
+        mod_image,
+        cpp_path,
+        transformed_path,
+        metadata_revision,
+    )?;
 
     fs::write(
         out_path.join(format!("{}.mmd", mod_config.id)),
         mod_data.serialize()?,
     )?;
-    compile_command.run()?;
+    let jobs = match jobs {
+        Some(jobs) => jobs,
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    compile_command.write_compile_commands(Path::new("./build/compile_commands.json"))?;
+    let object_files = compile_command.compile(jobs)?;
+    scripts.run(Stage::PreLink)?;
+    compile_command.link(&object_files)?;
+    scripts.run(Stage::PostBuild)?;
 
     Ok(())
 }
@@ -1,7 +1,17 @@
-use crate::utils::platform_executable;
-use color_eyre::eyre::{anyhow, Result, WrapErr};
+use super::profile::Profile;
+use crate::manifest::NativeSettings;
+use crate::utils::{platform_executable, CacheHasher};
+use color_eyre::eyre::{anyhow, bail, Result, WrapErr};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::iter;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use tracing::debug;
 
 #[cfg(target_os = "linux")]
 const NDK_HOST_TAG: &str = "linux-x86_64";
@@ -12,13 +22,111 @@ const NDK_HOST_TAG: &str = "windows";
 #[cfg(all(target_os = "windows", target_arch = "x86_64"))]
 const NDK_HOST_TAG: &str = "windows-x86_64";
 
+/// Path to the NDK's clang (or clang++) for the current host
+pub fn clang_path(ndk_path: &str, cpp: bool) -> PathBuf {
+    let mut clang_path = PathBuf::from(ndk_path).join("toolchains/llvm/prebuilt");
+    clang_path.push(NDK_HOST_TAG);
+    if cpp {
+        clang_path.push("bin/clang++");
+    } else {
+        clang_path.push("bin/clang");
+    }
+    platform_executable(&mut clang_path);
+    clang_path
+}
+
+/// Path to one of the NDK's LLVM binutils (e.g. `llvm-objcopy`) for the current host
+fn llvm_tool_path(ndk_path: &str, name: &str) -> PathBuf {
+    let mut path = PathBuf::from(ndk_path).join("toolchains/llvm/prebuilt");
+    path.push(NDK_HOST_TAG);
+    path.push("bin");
+    path.push(name);
+    platform_executable(&mut path);
+    path
+}
+
+/// Path to the applier library that mods are linked against
+pub fn applier_path() -> PathBuf {
+    let applier_path = PathBuf::from("../target/aarch64-linux-android/release/libmerge_applier.so");
+    if applier_path.exists() {
+        applier_path
+    } else {
+        PathBuf::from("./build/bin/libs/libmerge_applier.so")
+    }
+}
+
+/// An entry in a `compile_commands.json` compilation database
+#[derive(Serialize)]
+struct CompileCommandsEntry {
+    directory: String,
+    arguments: Vec<String>,
+    file: String,
+    output: String,
+}
+
+/// Reads the files that an object depended on when it was last compiled from the dependency
+/// file that clang wrote next to it
+fn read_deps(dep_path: &Path) -> Vec<PathBuf> {
+    match fs::read_to_string(dep_path) {
+        Ok(deps) => parse_deps(&deps),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parses the prerequisites of the make rule in a dependency file. Paths escape spaces and `#`
+/// with a backslash and `$` by doubling it, and lines are continued with a trailing backslash.
+/// Any other backslash is part of the path, as it is in Windows paths.
+fn parse_deps(deps: &str) -> Vec<PathBuf> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = deps.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(' ' | '#')) => word.push(chars.next().unwrap()),
+            ('\\', Some('\n')) => {
+                chars.next();
+            }
+            ('\\', Some('\r')) => {
+                chars.next();
+                chars.next_if_eq(&'\n');
+            }
+            ('$', Some('$')) => word.push(chars.next().unwrap()),
+            _ if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    // The rule's target is the object file itself
+    match words.iter().position(|word| word.ends_with(':')) {
+        Some(target_end) => words.drain(target_end + 1..).map(PathBuf::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+struct SourceFile {
+    path: PathBuf,
+    /// Whether the source is hand-written and listed in the manifest's `[native]` table
+    native: bool,
+}
+
 pub struct CompileCommand<'a> {
-    source_files: Vec<PathBuf>,
+    source_files: Vec<SourceFile>,
     include_paths: Vec<PathBuf>,
+    /// Flags that only apply to native sources
+    native_flags: Vec<String>,
+    libs: Vec<PathBuf>,
     output_path: PathBuf,
     ndk_path: &'a str,
     obj_path: &'a Path,
     target: &'a str,
+    profile: &'a Profile,
 }
 
 impl<'a> CompileCommand<'a> {
@@ -27,19 +135,62 @@ impl<'a> CompileCommand<'a> {
         output_path: PathBuf,
         obj_path: &'a Path,
         target: &'a str,
+        profile: &'a Profile,
     ) -> Self {
         Self {
             source_files: Vec::new(),
             include_paths: Vec::new(),
+            native_flags: Vec::new(),
+            libs: Vec::new(),
             output_path,
             ndk_path,
             obj_path,
             target,
+            profile,
         }
     }
 
     pub fn add_source(&mut self, path: PathBuf) {
-        self.source_files.push(path);
+        self.source_files.push(SourceFile {
+            path,
+            native: false,
+        });
+    }
+
+    /// Adds the sources, flags and libraries from the manifest's `[native]` table
+    pub fn add_native(&mut self, native: &NativeSettings) -> Result<()> {
+        let mut file_stems = HashMap::new();
+        for source in &native.sources {
+            let path = PathBuf::from(source);
+            if !matches!(
+                path.extension().and_then(OsStr::to_str),
+                Some("c" | "cc" | "cpp" | "cxx")
+            ) {
+                bail!("native source {} is not a C or C++ file", source);
+            }
+            // Objects are named after their source, so these would overwrite each other
+            if let Some(other) = file_stems.insert(path.file_stem().unwrap().to_owned(), source) {
+                bail!(
+                    "native sources {} and {} have the same file name",
+                    other,
+                    source
+                );
+            }
+            self.source_files.push(SourceFile { path, native: true });
+        }
+        if !native.sources.is_empty() {
+            fs::create_dir_all(self.obj_path.join("native"))?;
+        }
+
+        for include_dir in &native.include_dirs {
+            self.native_flags.push(format!("-I{}", include_dir));
+        }
+        for define in &native.defines {
+            self.native_flags.push(format!("-D{}", define));
+        }
+        self.native_flags.extend(native.flags.iter().cloned());
+        self.libs.extend(native.libs.iter().map(PathBuf::from));
+        Ok(())
     }
 
     pub fn add_include_path(&mut self, path: PathBuf) {
@@ -47,80 +198,306 @@ impl<'a> CompileCommand<'a> {
     }
 
     fn base_command(&self, cpp: bool) -> Command {
-        let mut clang_path = PathBuf::from(self.ndk_path).join("toolchains/llvm/prebuilt");
-        clang_path.push(NDK_HOST_TAG);
-        if cpp {
-            clang_path.push("bin/clang++");
-        } else {
-            clang_path.push("bin/clang");
-        }
-        platform_executable(&mut clang_path);
-
-        let mut command = Command::new(clang_path);
+        let mut command = Command::new(clang_path(self.ndk_path, cpp));
         command.args(&["-target", self.target]);
         command
     }
 
-    fn compile_source(&self, path: &Path) -> Result<PathBuf> {
-        let cpp = path.extension().unwrap() == "cpp";
+    /// Hashes everything that affects the output of compiling `source` to `output_path`
+    fn source_hash(&self, source: &SourceFile, output_path: &Path) -> Result<String> {
+        let path = &source.path;
+        let data = fs::read(path)
+            .with_context(|| format!("failed to read source file {}", path.display()))?;
+        let mut hasher = CacheHasher::default();
+        hasher.update(data);
+        for include_path in &self.include_paths {
+            hasher.update_os_str(include_path);
+        }
+        hasher.update(self.target);
+        hasher.update_all(self.profile.compile_flags());
+        hasher.update(self.ndk_path);
+        if source.native {
+            hasher.update_all(&self.native_flags);
+        }
+        // Headers change without the source changing, like `merge/codegen.h` with a new version
+        // of qmerge or a hand-written header of a native source
+        for dep in read_deps(&output_path.with_extension("d")) {
+            hasher.update_os_str(&dep);
+            hasher.update(fs::read(&dep).unwrap_or_default());
+        }
+        Ok(hasher.finish())
+    }
+
+    /// The command that compiles `source`, along with the path of the object file it outputs
+    fn source_command(&self, source: &SourceFile) -> (Command, PathBuf) {
+        let path = &source.path;
+        let name = path.file_stem().unwrap();
+        let output_path = if source.native {
+            self.obj_path.join("native").join(name).with_extension("o")
+        } else {
+            self.obj_path.join(name).with_extension("o")
+        };
+
+        let cpp = path.extension().unwrap() != "c";
         let mut command = self.base_command(cpp);
         command
-            .args(&[
-                "-fpic",
-                "-Wno-missing-declarations",
-                "-Wno-invalid-offsetof",
-                "-Os",
-                "-c",
-            ])
+            .args(self.profile.compile_flags())
+            .arg("-c")
             .arg(path);
 
         for path in &self.include_paths {
             command.arg("-I");
             command.arg(path);
         }
+        if source.native {
+            command.args(&self.native_flags);
+        }
+        command.arg("-MMD");
+        command.arg("-MF");
+        command.arg(output_path.with_extension("d"));
 
-        let name = path.file_stem().unwrap();
-        let output_path = self.obj_path.join(name).with_extension("o");
         command.arg("-o");
         command.arg(&output_path);
+        (command, output_path)
+    }
 
-        let status = command
-            .status()
-            .context("failed to execute compile command")?;
-        if status.success() {
-            Ok(output_path)
+    /// Writes a `compile_commands.json` covering every source so that clangd can be used on the
+    /// generated code
+    pub fn write_compile_commands(&self, path: &Path) -> Result<()> {
+        let directory = std::env::current_dir()?;
+        let entries: Vec<_> = self
+            .source_files
+            .iter()
+            .map(|source_file| {
+                let (command, output_path) = self.source_command(source_file);
+                let arguments = iter::once(command.get_program())
+                    .chain(command.get_args())
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect();
+                CompileCommandsEntry {
+                    directory: directory.to_string_lossy().into_owned(),
+                    arguments,
+                    file: source_file.path.to_string_lossy().into_owned(),
+                    output: output_path.to_string_lossy().into_owned(),
+                }
+            })
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&entries)?)
+            .context("failed to write compile_commands.json")
+    }
+
+    /// Compiles `source` unless an object built from the same inputs is already cached, returns
+    /// the path to the object file and whether it had to be compiled
+    fn compile_source(&self, source: &SourceFile) -> Result<(PathBuf, bool)> {
+        let path = &source.path;
+        let (mut command, output_path) = self.source_command(source);
+        let hash_path = output_path.with_extension("hash");
+
+        let hash = self.source_hash(source, &output_path)?;
+        if output_path.exists() && fs::read_to_string(&hash_path).ok().as_ref() == Some(&hash) {
+            return Ok((output_path, false));
+        }
+        // Make sure a failed compile never leaves a stale hash behind
+        let _ = fs::remove_file(&hash_path);
+
+        let output = command
+            .output()
+            .with_context(|| format!("failed to execute compile command for {}", path.display()))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            if !stderr.is_empty() {
+                // Print in one go so that warnings from parallel compiles don't interleave
+                eprint!("{}", stderr);
+            }
+            // The dependencies may have changed, and the next build will hash the new ones
+            let hash = self.source_hash(source, &output_path)?;
+            fs::write(&hash_path, hash)?;
+            Ok((output_path, true))
         } else {
-            Err(anyhow!("compile command failed: {:?}", command))
+            Err(anyhow!(
+                "failed to compile {}\n{:?}\n{}",
+                path.display(),
+                command,
+                stderr.trim_end()
+            ))
         }
     }
 
-    pub fn run(&self) -> Result<()> {
+    /// Compiles every source on `jobs` threads, in the order they were added
+    fn compile_sources(&self, jobs: usize) -> Vec<Result<(PathBuf, bool)>> {
+        let jobs = jobs.clamp(1, self.source_files.len().max(1));
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            match self.source_files.get(idx) {
+                                Some(source_file) => {
+                                    results.push((idx, self.compile_source(source_file)))
+                                }
+                                None => break results,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        results.sort_by_key(|(idx, _)| *idx);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Compiles every source that isn't cached, returning the object files to link
+    pub fn compile(&self, jobs: usize) -> Result<Vec<PathBuf>> {
         let mut object_files = Vec::new();
-        for source_file in &self.source_files {
-            object_files.push(self.compile_source(source_file)?);
+        let mut compiled = 0;
+        let mut failures = Vec::new();
+        for result in self.compile_sources(jobs) {
+            match result {
+                Ok((object_file, was_compiled)) => {
+                    object_files.push(object_file);
+                    if was_compiled {
+                        compiled += 1;
+                    }
+                }
+                Err(err) => failures.push(err),
+            }
+        }
+        if !failures.is_empty() {
+            for failure in &failures {
+                eprintln!("{:#}\n", failure);
+            }
+            bail!(
+                "{} of {} source files failed to compile",
+                failures.len(),
+                self.source_files.len()
+            );
         }
+        debug!(
+            "compiled {} of {} sources, the rest were cached",
+            compiled,
+            self.source_files.len()
+        );
+        Ok(object_files)
+    }
 
-        let applier_path =
-            PathBuf::from("../target/aarch64-linux-android/release/libmerge_applier.so");
-        let applier_path = if applier_path.exists() {
-            applier_path
-        } else {
-            PathBuf::from("./build/bin/libs/libmerge_applier.so")
-        };
+    /// Links `object_files` into the output library unless none of its inputs changed
+    pub fn link(&self, object_files: &[PathBuf]) -> Result<()> {
+        let applier_path = applier_path();
+        let mut hasher = CacheHasher::default();
+        for object_file in object_files {
+            hasher.update_os_str(object_file);
+            hasher.update(fs::read(object_file)?);
+        }
+        hasher.update(
+            fs::read(&applier_path)
+                .with_context(|| format!("failed to read {}", applier_path.display()))?,
+        );
+        for lib in &self.libs {
+            hasher.update_os_str(lib);
+            hasher.update(
+                fs::read(lib)
+                    .with_context(|| format!("failed to read native library {}", lib.display()))?,
+            );
+        }
+        hasher.update_os_str(&self.output_path);
+        hasher.update_all(self.profile.link_flags());
+        let link_hash = hasher.finish();
+        // Kept next to the output rather than with the objects since every profile links to the
+        // same path
+        let link_hash_path = self.output_path.with_extension("hash");
+        if self.output_path.exists()
+            && fs::read_to_string(&link_hash_path).ok().as_ref() == Some(&link_hash)
+        {
+            debug!("nothing changed, skipping link");
+            return Ok(());
+        }
+        let _ = fs::remove_file(&link_hash_path);
 
         let mut command = self.base_command(true);
         command
-            .args(&["-shared", "-static-libstdc++", "-Wl,--no-undefined"])
+            .args(self.profile.link_flags())
             .arg("-o")
             .arg(&self.output_path)
             .arg(applier_path)
-            .args(&object_files);
+            .args(object_files)
+            .args(&self.libs);
         // dbg!(&command);
         let status = command.status().context("failed to execute link command")?;
+        if !status.success() {
+            return Err(anyhow!("link command failed: {:?}", command));
+        }
+        self.strip()?;
+        fs::write(link_hash_path, link_hash)?;
+        Ok(())
+    }
+
+    fn run_llvm_tool(&self, name: &str, args: &[&OsStr]) -> Result<()> {
+        let mut command = Command::new(llvm_tool_path(self.ndk_path, name));
+        command.args(args);
+        let status = command
+            .status()
+            .with_context(|| format!("failed to execute {} command", name))?;
         if status.success() {
             Ok(())
         } else {
-            Err(anyhow!("link command failed: {:?}", command))
+            Err(anyhow!("{} command failed: {:?}", name, command))
         }
     }
+
+    /// Strips the symbols that aren't needed for relocations from the linked library. With debug
+    /// info, it is moved into a `.debug` file next to the library first, leaving a debuglink
+    /// behind so debuggers can still find it.
+    fn strip(&self) -> Result<()> {
+        let library = self.output_path.as_os_str();
+        let debug_path = self.output_path.with_extension("debug");
+        if self.profile.debug {
+            self.run_llvm_tool(
+                "llvm-objcopy",
+                &["--only-keep-debug".as_ref(), library, debug_path.as_ref()],
+            )?;
+        }
+        self.run_llvm_tool("llvm-strip", &["--strip-unneeded".as_ref(), library])?;
+        if self.profile.debug {
+            let mut debuglink = OsString::from("--add-gnu-debuglink=");
+            debuglink.push(&debug_path);
+            self.run_llvm_tool("llvm-objcopy", &[&debuglink, library])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_deps() {
+        let deps = "obj/Assembly-CSharp.o: src/Assembly-CSharp.cpp \\\n  include/codegen.h \\\r\n  /home/my\\ user/Unity\\ 2019.4/il2cpp-config.h\n";
+        assert_eq!(
+            parse_deps(deps),
+            [
+                "src/Assembly-CSharp.cpp",
+                "include/codegen.h",
+                "/home/my user/Unity 2019.4/il2cpp-config.h",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn unescapes_deps() {
+        let deps = "C:\\obj\\main.o: C:\\src\\main.cpp C:\\Issue\\#1\\$$HOME\\a.h";
+        assert_eq!(
+            parse_deps(deps),
+            ["C:\\src\\main.cpp", "C:\\Issue#1\\$HOME\\a.h"].map(PathBuf::from)
+        );
+        assert!(parse_deps("").is_empty());
+    }
 }
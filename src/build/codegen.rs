@@ -1,29 +1,256 @@
 use super::clang::CompileCommand;
 use super::data::ModDataBuilder;
 use super::function_usages::ModFunctionUsages;
-use color_eyre::eyre::{bail, ContextCompat, Result};
+use super::parser::{element_ident, element_ints, parse_items, SourceParser, SourceRewriter};
+use super::tokenizer::SourceFile;
+use super::{get_str, offset_len};
+use crate::metadata::{MetadataCode, MethodCode};
+use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use il2cpp_metadata_raw::Metadata;
 use std::fmt::Write;
 use std::fs;
 use std::path::Path;
 
-pub fn get_methods(src: &str) -> Result<Vec<Option<&str>>> {
-    let mut methods = Vec::new();
+/// Reads `s_methodPointers` of a code gen module, where methods without code are `NULL`
+pub fn get_methods<'a>(name: &'a str, src: &'a str) -> Result<Vec<Option<&'a str>>> {
+    let parser = SourceParser::new(name, src);
+    let elements = match parser.find_array("static Il2CppMethodPointer", "s_methodPointers")? {
+        Some(elements) => elements,
+        None => return Ok(Vec::new()),
+    };
+    elements
+        .map(|element| match element_ident(element) {
+            Some("NULL") => Ok(None),
+            Some(name) => Ok(Some(name)),
+            None => Err(parser.error_at(element, "malformed method pointer")),
+        })
+        .collect()
+}
+
+/// Parses `s_rgctxValues` into `(owner token, data type, index)` triples. The owner of each value
+/// is the type or method whose range in `s_rgctxIndices` contains it.
+fn parse_rgctx(name: &str, src: &str) -> Result<Vec<(u32, u32, u32)>> {
+    let parser = SourceParser::new(name, src);
+    // Modules without any generics don't have a runtime generic context at all
+    let values = match parser.find_array("static const Il2CppRGCTXDefinition", "s_rgctxValues")? {
+        Some(values) => values,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut owners = Vec::new();
+    for element in parser.parse_array("static const Il2CppTokenRangePair", "s_rgctxIndices")? {
+        match element_ints(element)?[..] {
+            [token, start, len] => owners.push((token as u32, start..start + len)),
+            _ => return Err(parser.error_at(element, "unexpected entry in s_rgctxIndices")),
+        }
+    }
+
+    let mut rgctx = Vec::new();
+    for (i, element) in values.enumerate() {
+        let (data_ty, idx) = match element_ints(element)?[..] {
+            [data_ty, idx] => (data_ty as u32, idx as u32),
+            _ => return Err(parser.error_at(element, "unexpected entry in s_rgctxValues")),
+        };
+        let owner_token = owners
+            .iter()
+            .find(|(_, range)| range.contains(&(i as i64)))
+            .map(|&(token, _)| token)
+            .ok_or_else(|| {
+                parser.error_at(
+                    element,
+                    format!("s_rgctxValues[{}] is not owned by any token", i),
+                )
+            })?;
+        rgctx.push((owner_token, data_ty, idx));
+    }
+    Ok(rgctx)
+}
+
+/// The definitions qmerge needs to write a code gen module, which il2cpp doesn't have for 24.1
+const V24_1_CODE_GEN_MODULE_TYPES: &str = "typedef struct
+{
+\tuint32_t token;
+\tstruct
+\t{
+\t\tint32_t start;
+\t\tint32_t length;
+\t} range;
+} Il2CppTokenRangePair;
+
+typedef struct
+{
+\tconst char* moduleName;
+\tuint32_t methodPointerCount;
+\tconst Il2CppMethodPointer* methodPointers;
+\tconst int32_t* invokerIndices;
+\tuint32_t rgctxRangesCount;
+\tconst Il2CppTokenRangePair* rgctxRanges;
+\tuint32_t rgctxsCount;
+\tconst Il2CppRGCTXDefinition* rgctxs;
+} MergeCodeGenModule;
+";
+
+/// 24.1 keeps the method pointers of the whole game in `g_MethodPointers` and the rest of the
+/// code information in the metadata. This splits them back up into a `<assembly>_CodeGen.c` for
+/// every assembly, in the same form as 24.2's, so the rest of the build doesn't have to care.
+pub fn write_v24_1_modules(
+    cpp_path: &Path,
+    metadata: &Metadata,
+    code: &MetadataCode,
+) -> Result<()> {
+    let table_name = "Il2CppMethodPointerTable.cpp";
+    let table_src = fs::read_to_string(cpp_path.join(table_name))
+        .with_context(|| format!("error opening {}", table_name))?;
+    let method_pointers: Vec<&str> = SourceParser::new(table_name, &table_src)
+        .parse_array("const Il2CppMethodPointer", "g_MethodPointers")?
+        .collect();
+    let includes: String = fs::read_to_string(cpp_path.join("Il2CppGenericMethodTable.c"))?
+        .lines()
+        .filter(|line| line.starts_with("#include"))
+        .map(|line| format!("{}\n", line))
+        .collect();
 
-    if let Some(arr_start) = src.find("static Il2CppMethodPointer s_methodPointers") {
-        for line in src[arr_start..].lines().skip(2) {
-            if line.starts_with('}') {
-                break;
+    for assembly in &metadata.assemblies {
+        let image = &metadata.images[assembly.image_index as usize];
+        let name = get_str(metadata.string, assembly.aname.name_index as usize)?;
+        let image_name = get_str(metadata.string, image.name_index as usize)?;
+
+        // Code gen modules are indexed by the method's rid
+        let mut methods = Vec::new();
+        let mut rgctx_owners = Vec::new();
+        for ty_idx in offset_len(image.type_start, image.type_count) {
+            let ty_def = &metadata.type_definitions[ty_idx];
+            rgctx_owners.push((ty_def.token, code.type_rgctxs[ty_idx]));
+            for method_idx in offset_len(ty_def.method_start, ty_def.method_count as u32) {
+                let token = metadata.methods[method_idx].token;
+                let method_code = code.methods[method_idx];
+                let rid = (token & 0x00FFFFFF) as usize;
+                if rid == 0 {
+                    bail!("method {:08x} in {} has no rid", token, image_name);
+                }
+                if methods.len() < rid {
+                    methods.resize(rid, None);
+                }
+                methods[rid - 1] = Some(method_code);
+                rgctx_owners.push((token, method_code.rgctx));
             }
-            let name = line.trim().trim_end_matches(',');
-            if name == "NULL" {
-                methods.push(None);
-            } else {
-                methods.push(Some(name));
+        }
+
+        let mut src = includes.clone();
+        writeln!(src, "{}", V24_1_CODE_GEN_MODULE_TYPES)?;
+        let mut method_names = Vec::new();
+        for method_code in &methods {
+            let name = match method_code {
+                Some(MethodCode { method_index, .. }) if *method_index >= 0 => {
+                    let name = *method_pointers
+                        .get(*method_index as usize)
+                        .with_context(|| {
+                            format!("method index {} is out of bounds", method_index)
+                        })?;
+                    if name != "NULL" {
+                        writeln!(src, "extern void {} ();", name)?;
+                    }
+                    name
+                }
+                _ => "NULL",
+            };
+            method_names.push(name);
+        }
+
+        writeln!(
+            src,
+            "static Il2CppMethodPointer s_methodPointers[{}] = ",
+            methods.len()
+        )?;
+        writeln!(src, "{{")?;
+        for name in &method_names {
+            writeln!(src, "\t{},", name)?;
+        }
+        writeln!(src, "}};")?;
+
+        writeln!(
+            src,
+            "static const int32_t s_InvokerIndices[{}] = ",
+            methods.len()
+        )?;
+        writeln!(src, "{{")?;
+        for method_code in &methods {
+            let invoker_idx = method_code.map_or(-1, |method_code| method_code.invoker_index);
+            writeln!(src, "\t{},", invoker_idx)?;
+        }
+        writeln!(src, "}};")?;
+
+        let mut rgctx_ranges = Vec::new();
+        let mut rgctx_values = Vec::new();
+        for (token, (start, len)) in rgctx_owners {
+            if start < 0 || len <= 0 {
+                continue;
             }
+            let entries = code
+                .rgctx_entries
+                .get(start as usize..(start + len) as usize)
+                .with_context(|| {
+                    format!("runtime generic context of {:08x} is out of bounds", token)
+                })?;
+            rgctx_ranges.push((token, rgctx_values.len(), entries.len()));
+            rgctx_values.extend_from_slice(entries);
         }
-    };
+        let rgctxs = if rgctx_values.is_empty() {
+            "0, NULL,\n\t0, NULL".to_string()
+        } else {
+            writeln!(
+                src,
+                "static const Il2CppTokenRangePair s_rgctxIndices[{}] = ",
+                rgctx_ranges.len()
+            )?;
+            writeln!(src, "{{")?;
+            for (token, start, len) in &rgctx_ranges {
+                writeln!(src, "\t{{ 0x{:08X}, {{ {}, {} }} }},", token, start, len)?;
+            }
+            writeln!(src, "}};")?;
+            writeln!(
+                src,
+                "static const Il2CppRGCTXDefinition s_rgctxValues[{}] = ",
+                rgctx_values.len()
+            )?;
+            writeln!(src, "{{")?;
+            for (data_ty, idx) in &rgctx_values {
+                writeln!(src, "\t{{ (Il2CppRGCTXDataType){}, {} }},", data_ty, idx)?;
+            }
+            writeln!(src, "}};")?;
+            format!(
+                "{}, s_rgctxIndices,\n\t{}, s_rgctxValues",
+                rgctx_ranges.len(),
+                rgctx_values.len()
+            )
+        };
+
+        let module_name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        writeln!(
+            src,
+            "extern const MergeCodeGenModule g_{}CodeGenModule;",
+            module_name
+        )?;
+        writeln!(
+            src,
+            "const MergeCodeGenModule g_{}CodeGenModule = ",
+            module_name
+        )?;
+        writeln!(src, "{{")?;
+        writeln!(src, "\t\"{}\",", image_name)?;
+        writeln!(src, "\t{},", methods.len())?;
+        writeln!(src, "\ts_methodPointers,")?;
+        writeln!(src, "\ts_InvokerIndices,")?;
+        writeln!(src, "\t{},", rgctxs)?;
+        writeln!(src, "}};")?;
 
-    Ok(methods)
+        fs::write(cpp_path.join(format!("{}_CodeGen.c", name)), src)?;
+    }
+
+    Ok(())
 }
 
 pub fn transform(
@@ -36,114 +263,118 @@ pub fn transform(
 ) -> Result<()> {
     let file_name = format!("{}_CodeGen.c", id);
     let src = fs::read_to_string(cpp_path.join(&file_name))?;
-    let mut new_src = String::new();
-    let mut lines = src.lines();
-
-    // map from token to s_rgctxValues range
-    let mut rgctx_indices: Vec<(u32, (usize, usize))> = Vec::new();
-
-    while let Some(line) = lines.next() {
-        if let Some(rest) = line.strip_prefix("static const int32_t s_InvokerIndices[") {
-            let len: usize = rest.trim_end_matches("] = ").parse().unwrap();
-            writeln!(new_src, "static int32_t s_InvokerIndices[{}] = ", len)?;
-            lines
-                .next()
-                .context("file ended reading s_InvokerIndices (skip '{')")?;
-            writeln!(new_src, "{{")?;
-            loop {
-                let line = lines
-                    .next()
-                    .context("file ended reading s_InvokerIndices")?;
-                if line == "};" {
-                    break;
-                }
-                let num_str = line.trim().trim_end_matches(',');
-                if num_str == "-1" {
-                    writeln!(new_src, "{}", line)?;
-                    continue;
-                }
-                let num: usize = num_str.parse()?;
-                let new_num = function_usages.add_invoker(num);
-                writeln!(new_src, "    {},", new_num)?;
-            }
-            writeln!(new_src, "}};")?;
-        } else if line.starts_with("static const Il2CppTokenRangePair s_rgctxIndices") {
-            writeln!(new_src, "{}", line)?;
-            lines
-                .next()
-                .context("file ended reading s_rgctxIndices (skip '{')")?;
-            writeln!(new_src, "{{")?;
-            loop {
-                let line = lines.next().context("file ended reading s_rgctxIndices")?;
-                writeln!(new_src, "{}", line)?;
-                if line == "};" {
-                    break;
+    let rgctx_values = parse_rgctx(&file_name, &src)?;
+
+    let mut rewriter = SourceRewriter::new(&src);
+    for item in parse_items(&SourceFile::new(&file_name, &src))? {
+        if item.body().is_none() {
+            continue;
+        }
+        match item.name {
+            Some("s_InvokerIndices") => {
+                let indices = item.array_elements()?;
+                // The loader fixes up the indices, so this can't be const either
+                let mut new_indices = format!(
+                    "static int32_t s_InvokerIndices[{}] = \n{{\n",
+                    indices.len()
+                );
+                for element in indices {
+                    let idx = match element_ints(element)?[..] {
+                        [idx] => idx,
+                        _ => return Err(item.error(format!("invalid invoker index {}", element))),
+                    };
+                    let new_idx = if idx < 0 {
+                        idx
+                    } else {
+                        function_usages.add_invoker(idx as usize) as i64
+                    };
+                    writeln!(new_indices, "    {},", new_idx)?;
                 }
-                let len_str = line
-                    .split_whitespace()
-                    .nth(4)
-                    .context("value in s_rgctxValues has wrong word count")?;
-                let len = str::parse(len_str)?;
-                let start_str = line
-                    .split_whitespace()
-                    .nth(3)
-                    .unwrap()
-                    .trim_end_matches(',');
-                let start = str::parse(start_str)?;
-                let token_str = line
-                    .split_whitespace()
-                    .nth(1)
-                    .unwrap()
-                    .trim_end_matches(',')
-                    .trim_start_matches("0x");
-                let token = u32::from_str_radix(token_str, 16)?;
-                rgctx_indices.push((token, (start, len)));
+                new_indices.push_str("};");
+                rewriter.replace(&item, &new_indices);
             }
-        } else if line.starts_with("static const Il2CppRGCTXDefinition s_rgctxValues") {
-            writeln!(new_src, "{}", line)?;
-            lines
-                .next()
-                .context("file ended reading s_rgctxValues (skip '{')")?;
-            writeln!(new_src, "{{")?;
-            loop {
-                let line = lines.next().context("file ended reading s_rgctxValues")?;
-                if line == "};" {
-                    break;
+            Some("s_rgctxValues") => {
+                // The loader fills in the real indices, so this can't be const
+                let mut new_values = format!(
+                    "static Il2CppRGCTXDefinition s_rgctxValues[{}] = \n{{\n",
+                    rgctx_values.len()
+                );
+                for &(owner_token, data_ty, idx) in &rgctx_values {
+                    let new_idx = data_builder.add_rgctx_value(owner_token, data_ty, idx)?;
+                    writeln!(
+                        new_values,
+                        "    {{ (Il2CppRGCTXDataType){}, {} }},",
+                        data_ty, new_idx
+                    )?;
                 }
-                let idx_str = line
-                    .split_whitespace()
-                    .nth(2)
-                    .context("value in s_rgctxValues has wrong word count")?;
-                let idx = str::parse(idx_str)?;
-                let data_ty_str = line
-                    .split_whitespace()
-                    .nth(1)
-                    .unwrap()
-                    .trim_start_matches("(Il2CppRGCTXDataType)")
-                    .trim_end_matches(',');
-                let data_ty = str::parse(data_ty_str)?;
-
-                let new_idx = match data_ty {
-                    1 => data_builder.add_type(idx)?,
-                    2 => data_builder.add_type_def(idx)?,
-                    3 => data_builder.add_method(idx)?,
-                    _ => bail!("unsupported runtime generic context data type: {}", data_ty),
-                };
-                writeln!(
-                    new_src,
-                    "    {{ (Il2CppRGCTXDataType){}, {} }},",
-                    data_ty, new_idx
-                )?;
+                new_values.push_str("};");
+                rewriter.replace(&item, &new_values);
             }
-            writeln!(new_src, "}};")?;
-        } else {
-            writeln!(new_src, "{}", line)?;
+            _ => {}
         }
     }
 
     let new_path = transformed_path.join(&file_name);
-    fs::write(&new_path, new_src)?;
+    fs::write(&new_path, rewriter.finish())?;
     compile_command.add_source(new_path);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE_GEN: &str = include_str!("fixtures/Mod_CodeGen.c");
+
+    #[test]
+    fn parses_rgctx() {
+        let rgctx = parse_rgctx("Mod_CodeGen.c", CODE_GEN).unwrap();
+        assert_eq!(
+            rgctx,
+            [
+                (0x02000004, 2, 2210),
+                (0x02000004, 1, 2210),
+                (0x02000004, 4, 2211),
+                (0x02000004, 3, 12522),
+                (0x06000004, 3, 12523),
+                (0x06000006, 1, 2214),
+                (0x06000006, 2, 2215),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_module_without_rgctx() {
+        let start = CODE_GEN.find("static const Il2CppTokenRangePair").unwrap();
+        let end = CODE_GEN.find("extern const Il2CppCodeGenModule").unwrap();
+        let src = format!("{}{}", &CODE_GEN[..start], &CODE_GEN[end..]);
+        assert!(parse_rgctx("Mod_CodeGen.c", &src).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_unowned_rgctx() {
+        let src = CODE_GEN.replace("{ 0x06000006, { 5, 2 } }", "{ 0x06000006, { 5, 1 } }");
+        let err = parse_rgctx("Mod_CodeGen.c", &src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Mod_CodeGen.c:55: s_rgctxValues[6] is not owned by any token"
+        );
+    }
+
+    #[test]
+    fn reads_method_pointers() {
+        let methods = get_methods("Mod_CodeGen.c", CODE_GEN).unwrap();
+        assert_eq!(
+            methods,
+            [
+                Some("Plugin_Load_m6A1E2F0B5D4C3B2A19087F6E5D4C3B2A19087F6E"),
+                Some("Plugin__ctor_m0A9B8C7D6E5F4A3B2C1D0E9F8A7B6C5D4E3F2A1B"),
+                None,
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use color_eyre::eyre::{bail, Result, WrapErr};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+/// Set while qmerge is building the C# project, so that the `qmerge build` that
+/// `Directory.Build.targets` runs after compiling doesn't start another build
+pub const NESTED_BUILD_VAR: &str = "QMERGE_CSHARP_BUILD";
+
+const OUTPUT_PATH: &str = "./build/csharp";
+
+pub fn is_nested_build() -> bool {
+    env::var_os(NESTED_BUILD_VAR).is_some()
+}
+
+/// Finds the project's `.csproj`, preferring one named after the mod if there are several
+fn find_project(id: &str) -> Result<Option<PathBuf>> {
+    let preferred = PathBuf::from(format!("{}.csproj", id));
+    if preferred.exists() {
+        return Ok(Some(preferred));
+    }
+
+    let mut projects = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "csproj") {
+            projects.push(path);
+        }
+    }
+    match projects.len() {
+        0 => Ok(None),
+        1 => Ok(projects.pop()),
+        _ => bail!(
+            "found multiple C# projects, rename the one for this mod to {}.csproj",
+            id
+        ),
+    }
+}
+
+fn build_command(program: &str, project: &Path, configuration: &str) -> Command {
+    let mut command = Command::new(program);
+    if program == "dotnet" {
+        command.arg("build");
+    } else {
+        command.arg("-restore");
+    }
+    command
+        .arg(project)
+        .arg("-nologo")
+        .arg("-verbosity:minimal")
+        // The errors are already printed as they happen
+        .arg("-consoleLoggerParameters:NoSummary")
+        .arg(format!("-property:Configuration={}", configuration))
+        .arg(format!("-property:OutDir={}/", OUTPUT_PATH))
+        .env(NESTED_BUILD_VAR, "1");
+    command
+}
+
+/// Compiles the mod's C# project with `dotnet` or `msbuild` and returns the directory containing
+/// the resulting dll, or `None` if the project has no `.csproj`
+pub fn build(id: &str, debug: bool) -> Result<Option<PathBuf>> {
+    let project = match find_project(id)? {
+        Some(project) => project,
+        None => return Ok(None),
+    };
+    let configuration = if debug { "Debug" } else { "Release" };
+
+    println!("Compiling {}", project.display());
+    let mut status = None;
+    for program in ["dotnet", "msbuild"] {
+        let mut command = build_command(program, &project, configuration);
+        debug!("C# build command: {:?}", &command);
+        match command.status() {
+            Ok(result) => {
+                status = Some(result);
+                break;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to execute {}", program));
+            }
+        }
+    }
+
+    let status = match status {
+        Some(status) => status,
+        None => bail!("could not find `dotnet` or `msbuild` to compile the C# project"),
+    };
+    if !status.success() {
+        bail!("C# compilation of {} failed", project.display());
+    }
+
+    let dll_path = Path::new(OUTPUT_PATH).join(format!("{}.dll", id));
+    if !dll_path.exists() {
+        bail!(
+            "C# compilation did not produce {}, is the AssemblyName in {} set to {}?",
+            dll_path.display(),
+            project.display(),
+            id
+        );
+    }
+    Ok(Some(OUTPUT_PATH.into()))
+}
@@ -1,9 +1,8 @@
-use crate::build::parser::try_parse_call;
-
 use super::clang::CompileCommand;
 use super::convert_codegen_init_method;
 use super::function_usages::ModFunctionUsages;
-use super::parser::{FnDecl, SourceParser};
+use super::parser::{element_ident, parse_items, SourceParser, SourceRewriter};
+use super::tokenizer::SourceFile;
 use color_eyre::eyre::Result;
 use il2cpp_metadata_raw::Il2CppImageDefinition;
 use std::collections::HashSet;
@@ -20,80 +19,71 @@ pub fn transform<'src>(
     metadata_usage_names: &mut HashSet<String>,
     function_usages: &mut ModFunctionUsages<'src>,
 ) -> Result<()> {
-    let parser = SourceParser::new(src);
+    let file = SourceFile::new("Il2CppAttributes.cpp", src);
+    let parser = SourceParser::new(file.name, src);
 
     let mut generator_names = Vec::new();
     let mut names_set = HashSet::new();
 
-    parser
-        .parse_array(
-            "const CustomAttributesCacheGenerator",
-            "g_AttributeGenerators",
-        )?
-        .for_each(|str| {
-            let name = str.trim_start_matches('&');
-            generator_names.push(name);
-            names_set.insert(name);
-            metadata_usage_names.insert(name.to_string());
-        });
+    for element in parser.parse_array(
+        "const CustomAttributesCacheGenerator",
+        "g_AttributeGenerators",
+    )? {
+        let name = element_ident(element)
+            .ok_or_else(|| parser.error_at(element, "malformed attribute generator"))?;
+        generator_names.push(name);
+        names_set.insert(name);
+        metadata_usage_names.insert(name.to_string());
+    }
 
-    let mut new_src = String::new();
-    let mut lines = src.lines();
-    while let Some(line) = lines.next() {
-        if line.starts_with("static void") {
-            let name = line
-                .trim_start_matches("static void ")
-                .split('(')
-                .next()
-                .unwrap();
-            let copy = names_set.contains(name);
-            if copy {
-                writeln!(new_src, "{}", line)?;
+    let mut rewriter = SourceRewriter::new(src);
+    for item in parse_items(&file)? {
+        let name = match item.name {
+            Some(name) => name,
+            None => continue,
+        };
+        match &item.fn_decl {
+            Some(fn_decl) if !item.is_fn_definition() => {
+                if fn_decl.extern_c {
+                    function_usages.forward_decls.insert(name, item.head);
+                }
             }
-            loop {
-                let line = lines.next().unwrap();
-                if copy {
-                    if let Some(name) = try_parse_call(line, false) {
-                        function_usages.process_function_usage(name)?;
+            // The generators are `static void <name>(CustomAttributesCache* cache)`
+            Some(fn_decl) if !fn_decl.extern_c && fn_decl.return_ty == "void" => {
+                if names_set.contains(name) {
+                    for usage in item.calls(false) {
+                        function_usages
+                            .process_function_usage(usage)
+                            .map_err(|err| item.error(err))?;
                     }
-                    writeln!(new_src, "{}", line)?;
+                } else {
+                    rewriter.remove(&item);
                 }
-                if line == "}" {
-                    break;
-                }
-            }
-        } else if line
-            .starts_with("extern const CustomAttributesCacheGenerator g_AttributeGenerators")
-        {
-            writeln!(new_src, "{}", line)?;
-            writeln!(
-                new_src,
-                "const CustomAttributesCacheGenerator g_AttributeGenerators[{}] =",
-                generator_names.len()
-            )?;
-
-            writeln!(new_src, "{{")?;
-            for name in generator_names {
-                writeln!(new_src, "    &{},", name)?;
             }
-            writeln!(new_src, "}};")?;
-            break;
-        } else {
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if line.ends_with(';') {
-                    function_usages
-                        .forward_decls
-                        .insert(fn_def.name, line.trim_end_matches(';'));
+            Some(_) => {}
+            None if name == "g_AttributeGenerators" && item.body().is_some() => {
+                let mut generators = format!(
+                    "const CustomAttributesCacheGenerator g_AttributeGenerators[{}] =\n{{\n",
+                    generator_names.len()
+                );
+                for name in &generator_names {
+                    writeln!(generators, "    &{},", name)?;
                 }
+                generators.push_str("};");
+                rewriter.replace(&item, &generators);
             }
-            writeln!(new_src, "{}", line)?;
+            None => {}
         }
     }
 
     let new_path = transformed_path.join("Il2CppAttributes.cpp");
     fs::write(
         &new_path,
-        convert_codegen_init_method(&new_src, mod_id, true)?,
+        convert_codegen_init_method(
+            &SourceFile::new(file.name, &rewriter.finish()),
+            mod_id,
+            true,
+        )?,
     )?;
     compile_command.add_source(new_path);
 
@@ -12,9 +12,10 @@ use il2cpp_metadata_raw::{
 use merge_data::{
     AddedAssembly, AddedEvent, AddedField, AddedGenericContainer, AddedGenericParameter,
     AddedImage, AddedMetadataUsagePair, AddedMethod, AddedParameter, AddedProperty,
-    AddedTypeDefinition, CodeTableSizes, CustomAttributeTypeRange, EncodedMethodIndex,
-    FieldDescription, GenericClassInst, GenericContainerOwner, GenericContext, GenericInst,
-    GenericMethodFunctions, GenericMethodInst, ImageDescription, MergeModData, MethodDescription,
+    AddedTypeDefinition, ArrayTypeDescription, CodeTableSizes, CustomAttributeTypeRange,
+    DeclaringType, EncodedMethodIndex, FieldDescription, GenericClassInst, GenericContainerOwner,
+    GenericContext, GenericInst, GenericMethodFunctions, GenericMethodInst, ImageDescription,
+    MergeModData, MetadataRevision, MethodDescription, RgctxData, RgctxDefinition,
     TypeDefDescription, TypeDescription, TypeDescriptionData,
 };
 use std::collections::{HashMap, HashSet};
@@ -83,6 +84,41 @@ struct ModDefinitions {
     added_ca_ranges: Vec<CustomAttributeTypeRange>,
 }
 
+/// Decodes an encoded method index from the metadata. The variant's index is still the one in
+/// the game's metadata, which has to be added to the mod data.
+fn decode_encoded(encoded_idx: u32) -> Result<EncodedMethodIndex> {
+    let ty = (encoded_idx & 0xE0000000) >> 29;
+    let idx = (encoded_idx & 0x1FFFFFFF) as usize;
+    Ok(match ty {
+        0 => EncodedMethodIndex::Invalid,
+        1 => EncodedMethodIndex::Il2CppClass(idx),
+        2 => EncodedMethodIndex::Il2CppType(idx),
+        3 => EncodedMethodIndex::MethodInfo(idx),
+        4 => EncodedMethodIndex::FieldInfo(idx),
+        5 => EncodedMethodIndex::StringLiteral(idx),
+        6 => EncodedMethodIndex::MethodRef(idx),
+        // Field RVAs (7) only got their own usage type in metadata v27
+        _ => bail!(
+            "unsupported encoded method index 0x{:08x} with type {}",
+            encoded_idx,
+            ty
+        ),
+    })
+}
+
+/// Decodes an `Il2CppRGCTXDataType` and the index it comes with, which is still the one in the
+/// game's metadata
+fn decode_rgctx(data_ty: u32, idx: u32) -> Result<RgctxData> {
+    let idx = idx as usize;
+    Ok(match data_ty {
+        1 => RgctxData::Type(idx),
+        2 => RgctxData::Class(idx),
+        3 => RgctxData::Method(idx),
+        4 => RgctxData::Array(idx),
+        _ => bail!("unsupported runtime generic context data type: {}", data_ty),
+    })
+}
+
 pub struct ModDataBuilder<'md, 'ty> {
     pub metadata: &'md Metadata<'md>,
     runtime_metadata: RuntimeMetadata<'ty>,
@@ -116,6 +152,7 @@ pub struct ModDataBuilder<'md, 'ty> {
     mod_definitions: Option<ModDefinitions>,
     added_usage_lists: Vec<Vec<AddedMetadataUsagePair>>,
     added_string_literals: Vec<String>,
+    rgctx_values: Vec<RgctxDefinition>,
 
     default_value_lens: HashMap<u32, u32>,
     param_dv_locs: HashMap<u32, u32>,
@@ -196,6 +233,7 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
             mod_definitions: None,
             added_usage_lists: Vec::new(),
             added_string_literals: Vec::new(),
+            rgctx_values: Vec::new(),
             default_value_lens,
             field_dv_locs,
             param_dv_locs,
@@ -314,7 +352,7 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
             byval_type: self.add_type(ty_def.byval_type_index)?,
             byref_type: self.add_type(ty_def.byref_type_index)?,
 
-            declaring_type_def: self.add_decl_ty(ty_def.declaring_type_index)?,
+            declaring_type_def: self.add_decl_ty_def(ty_def.declaring_type_index)?,
             declaring_type: self.add_type_optional(ty_def.declaring_type_index)?,
             parent_type: self.add_type_optional(ty_def.parent_index)?,
             element_type: self.add_type(ty_def.element_type_index)?,
@@ -417,18 +455,41 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
         Ok(desc_idx)
     }
 
-    fn add_decl_ty(&mut self, idx: u32) -> Result<Option<usize>> {
-        Ok(match idx as i32 {
-            -1 => None,
-            _ => Some({
-                let ty = &self.runtime_metadata.types[idx as usize];
-                match ty.data {
-                    Il2CppTypeData::TypeDefIdx(idx) => self.add_type_def(idx as u32)?,
-                    // TODO: Is this possible?
-                    _ => todo!("declaing type {:?}", ty.data),
-                }
-            }),
-        })
+    fn add_decl_ty(&mut self, idx: u32) -> Result<Option<DeclaringType>> {
+        if idx as i32 == -1 {
+            return Ok(None);
+        }
+        let ty = &self.runtime_metadata.types[idx as usize];
+        Ok(Some(match ty.data {
+            Il2CppTypeData::TypeDefIdx(idx) => {
+                DeclaringType::TypeDef(self.add_type_def(idx as u32)?)
+            }
+            Il2CppTypeData::Il2CppGenericClass(name) => {
+                let idx = self.runtime_metadata.gc_name_map[name];
+                DeclaringType::GenericClass(self.add_generic_class(idx as u32)?)
+            }
+            _ => bail!("unsupported declaring type {:?}", ty.data),
+        }))
+    }
+
+    /// The type definition of a declaring type, which for a generic class instance is the
+    /// generic type definition
+    fn add_decl_ty_def(&mut self, idx: u32) -> Result<Option<usize>> {
+        if idx as i32 == -1 {
+            return Ok(None);
+        }
+        let ty = &self.runtime_metadata.types[idx as usize];
+        let ty_def_idx = match ty.data {
+            Il2CppTypeData::TypeDefIdx(idx) => idx,
+            Il2CppTypeData::Il2CppGenericClass(name) => {
+                let gc_idx = self.runtime_metadata.gc_name_map[name];
+                self.runtime_metadata.generic_classes[gc_idx]
+                    .ty_def_idx
+                    .with_context(|| format!("declaring generic class {} has no type", name))?
+            }
+            _ => bail!("unsupported declaring type {:?}", ty.data),
+        };
+        Ok(Some(self.add_type_def(ty_def_idx as u32)?))
     }
 
     pub fn add_type_def(&mut self, idx: u32) -> Result<usize> {
@@ -498,7 +559,15 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
                 let idx = self.runtime_metadata.ty_name_map[name];
                 TypeDescriptionData::TypeIdx(self.add_type(idx as u32)?)
             }
-            Il2CppTypeData::Il2CppArrayType(_) => todo!("non-sz arrays"),
+            Il2CppTypeData::Il2CppArrayType(ref array) => {
+                let idx = self.runtime_metadata.ty_name_map[array.etype];
+                TypeDescriptionData::Array(ArrayTypeDescription {
+                    elem_ty: self.add_type(idx as u32)?,
+                    rank: array.rank,
+                    sizes: array.sizes.clone(),
+                    lower_bounds: array.lower_bounds.clone(),
+                })
+            }
             Il2CppTypeData::Il2CppGenericClass(name) => {
                 let idx = self.runtime_metadata.gc_name_map[name];
                 TypeDescriptionData::GenericClass(self.add_generic_class(idx as u32)?)
@@ -632,6 +701,7 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
     pub fn build(
         mut self,
         manifest: &Manifest,
+        metadata_revision: MetadataRevision,
         code_table_sizes: CodeTableSizes,
     ) -> Result<MergeModData> {
         let config = &manifest.plugin;
@@ -645,6 +715,7 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
             .mod_definitions
             .context("tried to build mod data without mod defintions")?;
         Ok(MergeModData {
+            metadata_revision,
             code_table_sizes,
 
             image_descriptions: self.images,
@@ -670,6 +741,7 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
                 .generic_funcs
                 .context("tried to build mod data without processing generic funcs")?,
             generic_class_insts: self.generic_classes,
+            rgctx_values: self.rgctx_values,
         })
     }
 
@@ -809,39 +881,50 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
     }
 
     fn add_encoded(&mut self, encoded_idx: u32) -> Result<EncodedMethodIndex> {
-        let ty = (encoded_idx & 0xE0000000) >> 29;
-        let idx = encoded_idx & 0x1FFFFFFF;
-        Ok(match ty {
-            1 => EncodedMethodIndex::Il2CppClass(self.add_type(idx)?),
-            2 => EncodedMethodIndex::Il2CppType(self.add_type(idx)?),
-            3 => EncodedMethodIndex::MethodInfo(self.add_method(idx)?),
-            4 => EncodedMethodIndex::FieldInfo(self.add_field_ref(idx)?),
-            5 => EncodedMethodIndex::StringLiteral(self.add_string_literal(idx)?),
-            6 => EncodedMethodIndex::MethodRef(self.add_generic_method(idx)?),
-            _ => bail!("Unsupported encoded method index with type {}", ty),
+        Ok(match decode_encoded(encoded_idx)? {
+            EncodedMethodIndex::Invalid => EncodedMethodIndex::Invalid,
+            EncodedMethodIndex::Il2CppClass(idx) => {
+                EncodedMethodIndex::Il2CppClass(self.add_type(idx as u32)?)
+            }
+            EncodedMethodIndex::Il2CppType(idx) => {
+                EncodedMethodIndex::Il2CppType(self.add_type(idx as u32)?)
+            }
+            EncodedMethodIndex::MethodInfo(idx) => {
+                EncodedMethodIndex::MethodInfo(self.add_method(idx as u32)?)
+            }
+            EncodedMethodIndex::FieldInfo(idx) => {
+                EncodedMethodIndex::FieldInfo(self.add_field_ref(idx as u32)?)
+            }
+            EncodedMethodIndex::StringLiteral(idx) => {
+                EncodedMethodIndex::StringLiteral(self.add_string_literal(idx as u32)?)
+            }
+            EncodedMethodIndex::MethodRef(idx) => {
+                EncodedMethodIndex::MethodRef(self.add_generic_method(idx as u32)?)
+            }
         })
     }
 
+    /// Adds an entry of the codegen module's `s_rgctxValues`. `data_ty` is the
+    /// `Il2CppRGCTXDataType` and `idx` the type or generic method index it refers to.
+    pub fn add_rgctx_value(&mut self, owner_token: u32, data_ty: u32, idx: u32) -> Result<usize> {
+        let data = match decode_rgctx(data_ty, idx)? {
+            RgctxData::Type(idx) => RgctxData::Type(self.add_type(idx as u32)?),
+            RgctxData::Class(idx) => RgctxData::Class(self.add_type(idx as u32)?),
+            RgctxData::Method(idx) => RgctxData::Method(self.add_generic_method(idx as u32)?),
+            RgctxData::Array(idx) => RgctxData::Array(self.add_type(idx as u32)?),
+        };
+        let new_idx = self.rgctx_values.len();
+        self.rgctx_values
+            .push(RgctxDefinition { owner_token, data });
+        Ok(new_idx)
+    }
+
     pub fn check_for_shims<'a>(
         &self,
         funcs: HashSet<&'a str>,
         method_ptrs: &[&str],
-        shims: &HashSet<String>,
+        shims: &[bool],
     ) -> Result<HashMap<&'a str, bool>> {
-        // TODO: There has to be a faster way to do this
-        let mut method_map = vec![false; self.metadata.methods.len()];
-        for image in &self.metadata.images {
-            let name = self.get_str(image.name_index)?;
-            let is_shim = shims.contains(name);
-            let ty_def_range = offset_len(image.type_start, image.type_count);
-            for ty_def in &self.metadata.type_definitions[ty_def_range] {
-                let method_range = offset_len(ty_def.method_start, ty_def.method_count as u32);
-                method_map[method_range]
-                    .iter_mut()
-                    .for_each(|is| *is = is_shim);
-            }
-        }
-
         let mut map = HashMap::new();
         for func in funcs {
             if func.ends_with("_inline") {
@@ -861,8 +944,76 @@ impl<'md, 'ty> ModDataBuilder<'md, 'ty> {
                 })
                 .context("could not find use of generic func")?;
             let method_idx = self.runtime_metadata.generic_methods[gen_method_idx].method_def;
-            map.insert(func, method_map[method_idx]);
+            map.insert(func, shims[method_idx]);
         }
         Ok(map)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::{element_ints, SourceParser};
+    use super::*;
+
+    #[test]
+    fn decodes_rgctx_data_types() {
+        let parser = SourceParser::new("Mod_CodeGen.c", include_str!("fixtures/Mod_CodeGen.c"));
+        let values: Vec<_> = parser
+            .parse_array("static const Il2CppRGCTXDefinition", "s_rgctxValues")
+            .unwrap()
+            .map(|element| match element_ints(element).unwrap()[..] {
+                [data_ty, idx] => decode_rgctx(data_ty as u32, idx as u32).unwrap(),
+                _ => panic!("malformed rgctx value {}", element),
+            })
+            .collect();
+        assert!(matches!(
+            values[..],
+            [
+                RgctxData::Class(2210),
+                RgctxData::Type(2210),
+                RgctxData::Array(2211),
+                RgctxData::Method(12522),
+                RgctxData::Method(12523),
+                RgctxData::Type(2214),
+                RgctxData::Class(2215),
+            ]
+        ));
+
+        // IL2CPP_RGCTX_DATA_INVALID never shows up in generated code
+        assert!(decode_rgctx(0, 0).is_err());
+        assert!(decode_rgctx(5, 0).is_err());
+    }
+
+    #[test]
+    fn decodes_encoded_method_indices() {
+        assert!(matches!(
+            decode_encoded(0).unwrap(),
+            EncodedMethodIndex::Invalid
+        ));
+        assert!(matches!(
+            decode_encoded(0x200008A2).unwrap(),
+            EncodedMethodIndex::Il2CppClass(0x8A2)
+        ));
+        assert!(matches!(
+            decode_encoded(0x400008A6).unwrap(),
+            EncodedMethodIndex::Il2CppType(0x8A6)
+        ));
+        assert!(matches!(
+            decode_encoded(0x60003A01).unwrap(),
+            EncodedMethodIndex::MethodInfo(0x3A01)
+        ));
+        assert!(matches!(
+            decode_encoded(0x80000310).unwrap(),
+            EncodedMethodIndex::FieldInfo(0x310)
+        ));
+        assert!(matches!(
+            decode_encoded(0xA0000C4D).unwrap(),
+            EncodedMethodIndex::StringLiteral(0xC4D)
+        ));
+        assert!(matches!(
+            decode_encoded(0xC00030EA).unwrap(),
+            EncodedMethodIndex::MethodRef(0x30EA)
+        ));
+        assert!(decode_encoded(0xE0000001).is_err());
+    }
+}
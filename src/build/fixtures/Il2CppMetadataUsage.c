@@ -0,0 +1,35 @@
+﻿#include "pch-c.h"
+#ifndef _MSC_VER
+# include <alloca.h>
+#else
+# include <malloc.h>
+#endif
+
+
+#include "codegen/il2cpp-codegen-metadata.h"
+
+
+
+
+
+// System.Collections.Generic.List`1<System.String>
+RuntimeClass* List_1_t6C9F81EDBF0F4A31A9B0DA372D2EF34BDA3A1AF3_il2cpp_TypeInfo_var;
+// System.String Mod.Plugin::Name
+RuntimeField* Plugin_t4B2C1E0A9D8F7E6D5C4B3A291807F6E5D4C3B2A1____Name_FieldInfo_var;
+// System.Void System.Collections.Generic.List`1<System.String>::.ctor()
+const RuntimeMethod* List_1__ctor_mFEB2301A6F28290A828A979BA9CC847B16B3D538_RuntimeMethod_var;
+// System.Type
+const RuntimeType* Type_t2D8F2DA8B5B5F0E4B3D1E0F9B71B0E1D3C6F8A2E_0_0_0_var;
+String_t* _stringLiteral9BA1B5E0B2F4E5C3A40E7C8D6F2B1A0C9E8D7F6A;
+extern const uint32_t Plugin_Load_m6A1E2F0B5D4C3B2A19087F6E5D4C3B2A19087F6E_MetadataUsageId;
+extern const uint32_t Plugin__ctor_m0A9B8C7D6E5F4A3B2C1D0E9F8A7B6C5D4E3F2A1B_MetadataUsageId;
+void** const g_MetadataUsages[5] = 
+{
+	(void**)(&List_1_t6C9F81EDBF0F4A31A9B0DA372D2EF34BDA3A1AF3_il2cpp_TypeInfo_var),
+	(void**)(&Plugin_t4B2C1E0A9D8F7E6D5C4B3A291807F6E5D4C3B2A1____Name_FieldInfo_var),
+	(void**)(&List_1__ctor_mFEB2301A6F28290A828A979BA9CC847B16B3D538_RuntimeMethod_var),
+	(void**)(&Type_t2D8F2DA8B5B5F0E4B3D1E0F9B71B0E1D3C6F8A2E_0_0_0_var),
+	(void**)(&_stringLiteral9BA1B5E0B2F4E5C3A40E7C8D6F2B1A0C9E8D7F6A),
+};
+const uint32_t Plugin_Load_m6A1E2F0B5D4C3B2A19087F6E5D4C3B2A19087F6E_MetadataUsageId = 0;
+const uint32_t Plugin__ctor_m0A9B8C7D6E5F4A3B2C1D0E9F8A7B6C5D4E3F2A1B_MetadataUsageId = 1;
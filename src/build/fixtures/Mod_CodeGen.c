@@ -0,0 +1,71 @@
+﻿#include "pch-c.h"
+#ifndef _MSC_VER
+# include <alloca.h>
+#else
+# include <malloc.h>
+#endif
+
+
+#include "codegen/il2cpp-codegen-metadata.h"
+
+
+
+
+
+// 0x00000001 System.Void Mod.Plugin::Load()
+extern void Plugin_Load_m6A1E2F0B5D4C3B2A19087F6E5D4C3B2A19087F6E ();
+// 0x00000002 System.Void Mod.Plugin::.ctor()
+extern void Plugin__ctor_m0A9B8C7D6E5F4A3B2C1D0E9F8A7B6C5D4E3F2A1B ();
+// 0x00000003 T Mod.Registry`1::Get(System.Int32)
+// 0x00000004 System.Void Mod.Registry`1::Add(T)
+// 0x00000005 System.Void Mod.Registry`1::.ctor()
+// 0x00000006 TResult Mod.Plugin::Convert(TSource)
+static Il2CppMethodPointer s_methodPointers[6] = 
+{
+	Plugin_Load_m6A1E2F0B5D4C3B2A19087F6E5D4C3B2A19087F6E,
+	Plugin__ctor_m0A9B8C7D6E5F4A3B2C1D0E9F8A7B6C5D4E3F2A1B,
+	NULL,
+	NULL,
+	NULL,
+	NULL,
+};
+static const int32_t s_InvokerIndices[6] = 
+{
+	23,
+	23,
+	-1,
+	-1,
+	-1,
+	-1,
+};
+static const Il2CppTokenRangePair s_rgctxIndices[3] = 
+{
+	{ 0x02000004, { 0, 4 } },
+	{ 0x06000004, { 4, 1 } },
+	{ 0x06000006, { 5, 2 } },
+};
+static const Il2CppRGCTXDefinition s_rgctxValues[7] = 
+{
+	{ (Il2CppRGCTXDataType)2, 2210 },
+	{ (Il2CppRGCTXDataType)1, 2210 },
+	{ (Il2CppRGCTXDataType)4, 2211 },
+	{ (Il2CppRGCTXDataType)3, 12522 },
+	{ (Il2CppRGCTXDataType)3, 12523 },
+	{ (Il2CppRGCTXDataType)1, 2214 },
+	{ (Il2CppRGCTXDataType)2, 2215 },
+};
+extern const Il2CppCodeGenModule g_ModCodeGenModule;
+const Il2CppCodeGenModule g_ModCodeGenModule = 
+{
+	"Mod.dll",
+	6,
+	s_methodPointers,
+	s_InvokerIndices,
+	0,
+	NULL,
+	3,
+	s_rgctxIndices,
+	7,
+	s_rgctxValues,
+	NULL,
+};
@@ -1,5 +1,7 @@
 use super::clang::CompileCommand;
 use super::data::ModDataBuilder;
+use super::parser::{parse_items, Item, SourceParser, SourceRewriter};
+use super::tokenizer::{SourceFile, TokenKind};
 use super::StructDef;
 use crate::build::{add_cpp_ty, FnDecl};
 use color_eyre::eyre::{bail, ContextCompat, Result};
@@ -7,7 +9,6 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::fs;
 use std::path::Path;
-use std::str::Lines;
 
 #[derive(Default)]
 pub struct ModFunctionUsages<'a> {
@@ -58,18 +59,23 @@ impl<'a> ModFunctionUsages<'a> {
         Ok(())
     }
 
-    pub fn parse_gshared_proxy_decl(&self, line: &'a str) -> &'a str {
-        let words = line.split_whitespace().collect::<Vec<_>>();
-        words[words.iter().position(|w| w.starts_with('(')).unwrap() - 1]
-    }
-
-    pub fn add_gshared_proxy(&mut self, line: &'a str, lines: &mut Lines<'a>) {
-        let proxy_name = self.parse_gshared_proxy_decl(line);
-        lines.next().unwrap();
-        let line = lines.next().unwrap().trim();
-        let name_start = line.find("))").unwrap() + 2;
-        let name = line[name_start..].split(')').next().unwrap();
+    /// Adds a proxy like `inline void Foo_m...(...) { ((void (*) (...))Foo_gshared)(...); }` that
+    /// forwards a generic method instance to its shared implementation
+    pub fn add_gshared_proxy(&mut self, item: &Item<'a>) -> Result<()> {
+        let proxy_name = item.name.context("gshared proxy without a name")?;
+        let name = item
+            .body()
+            .and_then(|body| {
+                body.windows(3).find_map(|tokens| {
+                    let is_target = tokens[0].is_punct(")")
+                        && tokens[1].is_punct(")")
+                        && tokens[2].kind == TokenKind::Ident;
+                    is_target.then(|| tokens[2].text)
+                })
+            })
+            .ok_or_else(|| item.error(format!("could not find target of {}", proxy_name)))?;
         self.generic_proxies.insert(proxy_name, name);
+        Ok(())
     }
 
     pub fn write_external(
@@ -98,8 +104,12 @@ impl<'a> ModFunctionUsages<'a> {
         for (this_idx, external) in self.using_external.iter().enumerate() {
             let orig_idx = self.external_methods[external];
             let idx = data_builder.add_method(orig_idx as u32)?;
-            let decl = &self.forward_decls[external];
-            let fn_def = FnDecl::try_parse(decl).unwrap();
+            let decl = self
+                .forward_decls
+                .get(external)
+                .with_context(|| format!("could not find declaration of {}", external))?;
+            let fn_def = FnDecl::try_parse(decl)
+                .with_context(|| format!("could not parse function declaration '{}'", decl))?;
             add_cpp_ty(
                 &mut external_src,
                 fn_def.return_ty,
@@ -122,21 +132,12 @@ impl<'a> ModFunctionUsages<'a> {
                     &mut fd_structs,
                     &mut added_structs,
                 )?;
-                let words = param.trim_start_matches("const ").split_whitespace();
-                params.push(words.last().unwrap().to_string());
+                let name = param
+                    .split_whitespace()
+                    .last()
+                    .with_context(|| format!("malformed parameters in '{}'", decl))?;
+                params.push(name);
             }
-
-            let params: Vec<String> = fn_def
-                .params
-                .trim_start_matches('(')
-                .trim_end_matches(')')
-                .split(',')
-                .map(|param| param.split_whitespace().last())
-                .collect::<Option<Vec<&str>>>()
-                .unwrap()
-                .into_iter()
-                .map(String::from)
-                .collect();
             let params = params.join(", ");
 
             writeln!(
@@ -221,57 +222,43 @@ impl<'a> ModFunctionUsages<'a> {
         cpp_path: &Path,
     ) -> Result<()> {
         let src = fs::read_to_string(cpp_path.join("Il2CppInvokerTable.cpp"))?;
+        let file = SourceFile::new("Il2CppInvokerTable.cpp", &src);
+        let items = parse_items(&file)?;
 
-        let mut invokers = Vec::new();
-        let arr_start = src
-            .find("const InvokerMethod g_Il2CppInvokerPointers")
+        let table = items
+            .iter()
+            .find(|item| item.name == Some("g_Il2CppInvokerPointers") && item.body().is_some())
             .context("could not find g_Il2CppInvokerPointers")?;
-        for line in src[arr_start..].lines().skip(3) {
-            if line.starts_with('}') {
-                break;
-            }
-            let name = line.trim().trim_end_matches(',');
-            invokers.push(name);
-        }
+        let invokers = table.array_elements()?;
 
         let mut keep_invokers = HashSet::new();
         for &idx in &self.required_invokers {
-            keep_invokers.insert(invokers[idx]);
+            let invoker = invokers
+                .get(idx)
+                .ok_or_else(|| table.error(format!("invoker index {} is out of bounds", idx)))?;
+            keep_invokers.insert(*invoker);
         }
 
-        let mut new_src = String::new();
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if line.starts_with("void*") {
-                let name = line.split_whitespace().nth(1).context("weird fn def")?;
-                let keep = keep_invokers.contains(name);
-                if keep {
-                    writeln!(new_src, "{}", line)?;
-                }
-                for line in lines.by_ref() {
-                    if keep {
-                        writeln!(new_src, "{}", line)?;
-                    }
-                    if line.is_empty() {
-                        break;
-                    }
-                }
-            } else if line.starts_with("const InvokerMethod g_Il2CppInvokerPointers") {
-                writeln!(
-                    new_src,
-                    "const InvokerMethod g_Il2CppInvokerPointers[{}] =",
+        let mut rewriter = SourceRewriter::new(&src);
+        for item in &items {
+            if std::ptr::eq(item, table) {
+                let mut new_table = format!(
+                    "const InvokerMethod g_Il2CppInvokerPointers[{}] =\n{{\n",
                     self.required_invokers.len()
-                )?;
-                writeln!(new_src, "{{")?;
+                );
                 for &idx in &self.required_invokers {
-                    writeln!(new_src, "    {},", invokers[idx])?;
+                    writeln!(new_table, "    {},", invokers[idx])?;
+                }
+                new_table.push_str("};");
+                rewriter.replace(item, &new_table);
+            } else if let Some(fn_decl) = &item.fn_decl {
+                let is_invoker = item.is_fn_definition() && fn_decl.return_ty == "void*";
+                if is_invoker && !keep_invokers.contains(fn_decl.name) {
+                    rewriter.remove(item);
                 }
-                writeln!(new_src, "}};")?;
-                break;
-            } else {
-                writeln!(new_src, "{}", line)?;
             }
         }
+        let new_src = rewriter.finish();
 
         let new_path = transformed_path.join("Il2CppInvokerTable.cpp");
         fs::write(&new_path, new_src)?;
@@ -324,18 +311,11 @@ impl<'a> ModFunctionUsages<'a> {
     ) -> Result<HashSet<String>> {
         let src = fs::read_to_string(cpp_path.join("Il2CppGenericAdjustorThunkTable.cpp"))?;
 
-        let mut methods = Vec::new();
-
-        if let Some(arr_start) = src.find("const Il2CppMethodPointer g_Il2CppGenericAdjustorThunks")
-        {
-            for line in src[arr_start..].lines().skip(3) {
-                if line.starts_with('}') {
-                    break;
-                }
-                let name = line.trim().trim_end_matches(',');
-                methods.push(name);
-            }
-        };
+        let methods: Vec<_> = SourceParser::new("Il2CppGenericAdjustorThunkTable.cpp", &src)
+            .find_array("const Il2CppMethodPointer", "g_Il2CppGenericAdjustorThunks")?
+            .into_iter()
+            .flatten()
+            .collect();
 
         let mut new_src = String::new();
         writeln!(new_src, "#include \"codegen/il2cpp-codegen.h\"")?;
@@ -1,48 +1,38 @@
 use super::clang::CompileCommand;
 use super::data::ModDataBuilder;
 use super::function_usages::ModFunctionUsages;
-use super::{add_cpp_ty, find_struct_defs, FnDecl, StructDef};
-use crate::build::try_parse_call;
+use super::parser::{element_ident, find_struct_templates, parse_items, Item, SourceParser};
+use super::tokenizer::SourceFile;
+use super::{add_cpp_ty, find_struct_defs, StructDef};
 use color_eyre::eyre::{bail, ContextCompat, Result};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::fs;
 use std::path::Path;
 
-pub fn read_method_ptr_table(src: &str) -> Result<Vec<&str>> {
-    let mut methods = Vec::new();
-
-    if let Some(arr_start) = src.find("const Il2CppMethodPointer g_Il2CppGenericMethodPointers") {
-        for line in src[arr_start..].lines().skip(3) {
-            if line.starts_with('}') {
-                break;
-            }
-            let name = line
-                .trim()
-                .trim_start_matches("(Il2CppMethodPointer)&")
-                .split('/')
-                .next()
-                .context("malformed generic method pointer table")?;
-            methods.push(name);
-        }
-    };
-
-    Ok(methods)
+pub fn read_method_ptr_table<'a>(name: &'a str, src: &'a str) -> Result<Vec<&'a str>> {
+    let parser = SourceParser::new(name, src);
+    let elements =
+        match parser.find_array("const Il2CppMethodPointer", "g_Il2CppGenericMethodPointers")? {
+            Some(elements) => elements,
+            None => return Ok(Vec::new()),
+        };
+    elements
+        .map(|element| {
+            element_ident(element)
+                .ok_or_else(|| parser.error_at(element, "malformed generic method pointer"))
+        })
+        .collect()
 }
 
-fn process_line<'a>(
-    line: &'a str,
+fn process_usage<'a>(
+    usage: &'a str,
     src_idx: usize,
     function_usages: &mut ModFunctionUsages<'a>,
     visited: &HashSet<&str>,
     gshared_queue: &mut Vec<&'a str>,
     inline_queue: &mut Vec<(&'a str, usize)>,
 ) -> Result<()> {
-    let usage = if let Some(name) = try_parse_call(line, true) {
-        name
-    } else {
-        return Ok(());
-    };
     if visited.contains(usage) {
         return Ok(());
     }
@@ -64,39 +54,23 @@ fn process_line<'a>(
 }
 
 fn process_fn<'a>(
-    src: &'a str,
-    name: &'a str,
+    item: &Item<'a>,
     src_idx: usize,
     function_usages: &mut ModFunctionUsages<'a>,
     visited: &HashSet<&str>,
     gshared_queue: &mut Vec<&'a str>,
     inline_queue: &mut Vec<(&'a str, usize)>,
 ) -> Result<()> {
-    let mut lines = src.lines();
-    while let Some(line) = lines.next() {
-        if line.ends_with(';') {
-            continue;
-        }
-        if let Some(fn_def) = FnDecl::try_parse(line) {
-            if fn_def.name == name {
-                lines.next().unwrap();
-                loop {
-                    let line = lines.next().unwrap();
-                    if line == "}" {
-                        break;
-                    }
-                    process_line(
-                        line,
-                        src_idx,
-                        function_usages,
-                        visited,
-                        gshared_queue,
-                        inline_queue,
-                    )?;
-                }
-                break;
-            }
-        }
+    for usage in item.calls(true) {
+        process_usage(
+            usage,
+            src_idx,
+            function_usages,
+            visited,
+            gshared_queue,
+            inline_queue,
+        )
+        .map_err(|err| item.error(err))?;
     }
     Ok(())
 }
@@ -114,7 +88,10 @@ pub struct GenericTransformData<'a> {
     struct_fds: HashSet<&'a str>,
     struct_defs: HashMap<&'a str, StructDef<'a>>,
     generic_invoker_templates: HashMap<&'a str, String>,
-    def_src_map: HashMap<&'a str, usize>,
+    /// The items of every generic source
+    items: Vec<Vec<Item<'a>>>,
+    /// Where each function is defined, as indices into `items`
+    fn_defs: HashMap<&'a str, (usize, usize)>,
     funcs: HashMap<&'a str, bool>,
 }
 
@@ -125,32 +102,33 @@ pub fn transform<'a>(
     source_names: &'a [String],
     sources: &'a [String],
     method_ptrs: &[&str],
-    shims: &HashSet<String>,
+    shims: &[bool],
     gen_adj_thunks: &HashSet<String>,
 ) -> Result<GenericTransformData<'a>> {
-    let mut inline_functions = HashSet::new();
-    let mut def_src_map = HashMap::new();
+    let mut items = Vec::new();
+    let mut fn_defs = HashMap::new();
     let mut visited = HashSet::new();
 
-    for (src_idx, src) in sources.iter().enumerate() {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if line.ends_with(';') {
+    for (src_idx, (name, src)) in source_names.iter().zip(sources).enumerate() {
+        let src_items = parse_items(&SourceFile::new(name, src))?;
+        for (item_idx, item) in src_items.iter().enumerate() {
+            let fn_decl = match &item.fn_decl {
+                Some(fn_decl) => fn_decl,
+                None => continue,
+            };
+            if !item.is_fn_definition() {
+                if fn_decl.extern_c {
                     function_usages
                         .forward_decls
-                        .insert(fn_def.name, line.trim_end_matches(';'));
-                } else {
-                    lines.next().unwrap();
-                    if fn_def.inline {
-                        inline_functions.insert(fn_def.name);
-                    }
-                    def_src_map.insert(fn_def.name, src_idx);
+                        .insert(fn_decl.name, item.head);
                 }
-            } else if line.starts_with("inline") {
-                function_usages.add_gshared_proxy(line, &mut lines);
+            } else if fn_decl.extern_c {
+                fn_defs.insert(fn_decl.name, (src_idx, item_idx));
+            } else if fn_decl.inline {
+                function_usages.add_gshared_proxy(item)?;
             }
         }
+        items.push(src_items);
     }
 
     let mut gshared_queue = function_usages
@@ -160,31 +138,21 @@ pub fn transform<'a>(
         .collect::<Vec<_>>();
     let mut inline_queue = Vec::new();
 
-    for (src_idx, src) in sources.iter().enumerate() {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if line.ends_with(';') {
-                continue;
-            }
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if line.ends_with(';') {
-                    continue;
-                }
-                if gen_adj_thunks.contains(fn_def.name) {
-                    for _ in 0..3 {
-                        lines.next();
-                    }
-                    let line = lines.next().unwrap();
-                    let call = line.trim().trim_start_matches("return ");
-                    process_line(
-                        call,
-                        src_idx,
-                        function_usages,
-                        &visited,
-                        &mut gshared_queue,
-                        &mut inline_queue,
-                    )?;
-                }
+    for (src_idx, src_items) in items.iter().enumerate() {
+        for item in src_items {
+            let is_adj_thunk = item.is_fn_definition()
+                && item
+                    .name
+                    .map_or(false, |name| gen_adj_thunks.contains(name));
+            if is_adj_thunk {
+                process_fn(
+                    item,
+                    src_idx,
+                    function_usages,
+                    &visited,
+                    &mut gshared_queue,
+                    &mut inline_queue,
+                )?;
             }
         }
     }
@@ -200,11 +168,11 @@ pub fn transform<'a>(
             visited.insert(gshared);
             // source name can be empty because this will never be inline
             metadata_usage_names.insert(get_md_usage_name(gshared, ""));
-            let src_idx = def_src_map[gshared];
-            let src = &sources[src_idx];
+            let &(src_idx, item_idx) = fn_defs
+                .get(gshared)
+                .with_context(|| format!("could not find definition of {}", gshared))?;
             process_fn(
-                src,
-                gshared,
+                &items[src_idx][item_idx],
                 src_idx,
                 function_usages,
                 &visited,
@@ -216,10 +184,20 @@ pub fn transform<'a>(
             visited.insert(inline);
             let src_name = &source_names[src_idx];
             metadata_usage_names.insert(get_md_usage_name(inline, src_name));
-            let src = &sources[src_idx];
+            let item = items[src_idx]
+                .iter()
+                .find(|item| {
+                    item.is_fn_definition()
+                        && item
+                            .fn_decl
+                            .as_ref()
+                            .map_or(false, |fn_decl| fn_decl.extern_c && fn_decl.name == inline)
+                })
+                .with_context(|| {
+                    format!("{}: could not find definition of {}", src_name, inline)
+                })?;
             process_fn(
-                src,
-                inline,
+                item,
                 src_idx,
                 function_usages,
                 &visited,
@@ -231,33 +209,26 @@ pub fn transform<'a>(
 
     // Find all code for generic invokers
     let mut generic_invoker_templates = HashMap::new();
-    for src in sources {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if line.starts_with("template") {
-                let mut body = String::new();
-                writeln!(body, "{}", line)?;
-                let name = lines.next().unwrap();
-                writeln!(body, "{}", name)?;
-                for line in lines.by_ref() {
-                    writeln!(body, "{}", line)?;
-                    if line == "};" {
-                        break;
-                    }
-                }
-                generic_invoker_templates.insert(name.trim_start_matches("struct "), body);
-            }
+    for (source_name, src) in source_names.iter().zip(sources) {
+        for (name, body) in find_struct_templates(source_name, src)? {
+            generic_invoker_templates.insert(name, body.to_string());
         }
     }
 
-    let (struct_fds, struct_defs) = find_struct_defs(sources);
+    let (struct_fds, struct_defs) = find_struct_defs(
+        source_names
+            .iter()
+            .map(String::as_str)
+            .zip(sources.iter().map(String::as_str)),
+    )?;
 
     Ok(GenericTransformData {
         struct_fds,
         struct_defs,
         generic_invoker_templates,
         funcs: data_builder.check_for_shims(visited, method_ptrs, shims)?,
-        def_src_map,
+        items,
+        fn_defs,
     })
 }
 
@@ -267,7 +238,6 @@ pub fn write(
     compile_command: &mut CompileCommand,
     usage_fds: &[String],
     source_names: &[String],
-    sources: &[String],
     function_usages: &ModFunctionUsages,
     required_adj_thunks: HashSet<String>,
 ) -> Result<()> {
@@ -281,7 +251,8 @@ pub fn write(
         mut struct_fds,
         generic_invoker_templates,
         funcs,
-        def_src_map,
+        items,
+        fn_defs,
     } = transform_data;
 
     // TODO: don't just add all struct definitions
@@ -307,7 +278,10 @@ pub fn write(
         writeln!(external_src, "IL2CPP_EXTERN_C {}", usage_fd)?;
     }
     for &usage in &function_usages.generic_using_fns {
-        let fd = function_usages.forward_decls[usage];
+        let fd = function_usages
+            .forward_decls
+            .get(usage)
+            .with_context(|| format!("could not find declaration of {}", usage))?;
         writeln!(external_src, "{};", fd)?;
     }
     writeln!(external_src)?;
@@ -320,65 +294,43 @@ pub fn write(
     let mut written_proxes = HashSet::new();
     let mut written_gshared_inline = HashSet::new();
 
-    for src in sources {
-        let mut lines = src.lines();
-        while let Some(line) = lines.next() {
-            if line.ends_with(';') {
-                continue;
-            }
-            if let Some(fn_def) = FnDecl::try_parse(line) {
-                if let Some(&stub) = funcs.get(fn_def.name) {
-                    if fn_def.name.ends_with("_inline") {
-                        if written_gshared_inline.contains(fn_def.name) {
-                            continue;
-                        } else {
-                            written_gshared_inline.insert(fn_def.name);
-                        }
-                    }
-                    let source_name = &source_names[def_src_map[fn_def.name]];
-                    writeln!(
-                        external_src,
-                        "IL2CPP_EXTERN_C const uint32_t {}_MetadataUsageId;",
-                        get_md_usage_name(fn_def.name, source_name)
-                    )?;
-                    writeln!(external_src, "{}", line)?;
-                    if stub {
-                        write_shim(&mut external_src, fn_def)?;
-                    } else {
-                        loop {
-                            let line = lines.next().unwrap();
-                            writeln!(external_src, "{}", line)?;
-                            if line == "}" {
-                                break;
-                            }
-                        }
-                    }
-                } else if required_adj_thunks.contains(fn_def.name) {
-                    writeln!(external_src, "{}", line)?;
-                    loop {
-                        let line = lines.next().unwrap();
-                        writeln!(external_src, "{}", line)?;
-                        if line == "}" {
-                            break;
-                        }
-                    }
+    for item in items.iter().flatten() {
+        let fn_decl = match &item.fn_decl {
+            Some(fn_decl) if item.is_fn_definition() => fn_decl,
+            _ => continue,
+        };
+        if !fn_decl.extern_c {
+            let proxy_name = fn_decl.name;
+            if let Some(&name) = function_usages.generic_proxies.get(proxy_name) {
+                if funcs.contains_key(name) && !written_proxes.contains(proxy_name) {
+                    written_proxes.insert(proxy_name);
+                    writeln!(external_src, "{}", item.text)?;
                 }
-            } else if line.starts_with("inline") {
-                let proxy_name = function_usages.parse_gshared_proxy_decl(line);
-                if let Some(&name) = function_usages.generic_proxies.get(proxy_name) {
-                    if funcs.contains_key(name) && !written_proxes.contains(proxy_name) {
-                        written_proxes.insert(proxy_name);
-                        writeln!(external_src, "{}", line)?;
-                        loop {
-                            let line = lines.next().unwrap();
-                            writeln!(external_src, "{}", line)?;
-                            if line == "}" {
-                                break;
-                            }
-                        }
-                    }
+            }
+        } else if let Some(&stub) = funcs.get(fn_decl.name) {
+            if fn_decl.name.ends_with("_inline") {
+                if written_gshared_inline.contains(fn_decl.name) {
+                    continue;
+                } else {
+                    written_gshared_inline.insert(fn_decl.name);
                 }
             }
+            let &(src_idx, _) = fn_defs
+                .get(fn_decl.name)
+                .with_context(|| format!("could not find definition of {}", fn_decl.name))?;
+            writeln!(
+                external_src,
+                "IL2CPP_EXTERN_C const uint32_t {}_MetadataUsageId;",
+                get_md_usage_name(fn_decl.name, &source_names[src_idx])
+            )?;
+            if stub {
+                writeln!(external_src, "{}", item.head)?;
+                write_shim(&mut external_src, item)?;
+            } else {
+                writeln!(external_src, "{}", item.text)?;
+            }
+        } else if required_adj_thunks.contains(fn_decl.name) {
+            writeln!(external_src, "{}", item.text)?;
         }
     }
 
@@ -389,7 +341,8 @@ pub fn write(
     Ok(())
 }
 
-fn write_shim(str: &mut String, fn_decl: FnDecl) -> Result<()> {
+fn write_shim(str: &mut String, item: &Item) -> Result<()> {
+    let fn_decl = item.fn_decl.as_ref().context("shim for a non-function")?;
     writeln!(str, "{{")?;
     let params = fn_decl
         .params
@@ -398,10 +351,7 @@ fn write_shim(str: &mut String, fn_decl: FnDecl) -> Result<()> {
         .split(',')
         .map(|param| param.split_whitespace().last())
         .collect::<Option<Vec<&str>>>()
-        .unwrap()
-        .into_iter()
-        .map(String::from)
-        .collect::<Vec<_>>()
+        .ok_or_else(|| item.error(format!("malformed parameters {}", fn_decl.params)))?
         .join(", ");
     writeln!(
         str,
@@ -0,0 +1,204 @@
+use crate::manifest::{Il2CppSettings, Manifest, Mod};
+use crate::utils::CacheHasher;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+const MANAGED_PATH: &str = "./build/Managed";
+const STATE_PATH: &str = "./build/il2cpp.json";
+
+/// What the last il2cpp run was given, so that it only has to be run again when something changes
+#[derive(Serialize, Deserialize, Default)]
+struct Il2CppState {
+    /// The directory that the mod's dll gets copied from
+    input_dir: Option<PathBuf>,
+    /// The fingerprint of the inputs that generated the current cpp sources
+    fingerprint: Option<String>,
+}
+
+impl Il2CppState {
+    fn load() -> Result<Self> {
+        let path = Path::new(STATE_PATH);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        let str = fs::read_to_string(path).context("failed to read il2cpp state")?;
+        // The state is only a cache, so starting over is better than failing the build
+        Ok(serde_json::from_str(&str).unwrap_or_default())
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(STATE_PATH, serde_json::to_string_pretty(self)?)
+            .context("failed to write il2cpp state")
+    }
+}
+
+/// il2cpp code generation options, resolved from the manifest's `[il2cpp]` table and the
+/// build profile's overrides
+#[derive(Debug)]
+pub struct Il2CppOptions {
+    pub null_checks: bool,
+    pub array_bounds_checks: bool,
+    pub divide_by_zero_checks: bool,
+    pub generic_sharing: bool,
+    pub extra_args: Vec<String>,
+}
+
+impl Il2CppOptions {
+    pub fn resolve(manifest: &Manifest, debug: bool) -> Self {
+        let profile = if debug {
+            &manifest.profile.debug.il2cpp
+        } else {
+            &manifest.profile.release.il2cpp
+        };
+        let base = &manifest.il2cpp;
+        let option = |get: fn(&Il2CppSettings) -> Option<bool>, default| {
+            get(profile).or_else(|| get(base)).unwrap_or(default)
+        };
+
+        Self {
+            null_checks: option(|s| s.null_checks, false),
+            array_bounds_checks: option(|s| s.array_bounds_checks, false),
+            divide_by_zero_checks: option(|s| s.divide_by_zero_checks, false),
+            generic_sharing: option(|s| s.generic_sharing, true),
+            extra_args: base
+                .extra_args
+                .iter()
+                .chain(&profile.extra_args)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+pub struct Il2Cpp {
+    pub mono_path: PathBuf,
+    pub il2cpp_path: PathBuf,
+    pub unity_version: String,
+    pub options: Il2CppOptions,
+}
+
+impl Il2Cpp {
+    fn args(&self, cpp_path: &Path) -> Vec<OsString> {
+        let mut generated_dir = OsString::from("--generatedcppdir=");
+        generated_dir.push(cpp_path);
+        let mut args = vec![
+            "--convert-to-cpp".into(),
+            format!("--directory={}", MANAGED_PATH).into(),
+            generated_dir,
+        ];
+
+        let options = &self.options;
+        if options.null_checks {
+            args.push("--emit-null-checks".into());
+        }
+        if options.array_bounds_checks {
+            args.push("--enable-array-bounds-check".into());
+        }
+        if options.divide_by_zero_checks {
+            args.push("--enable-divide-by-zero-check".into());
+        }
+        if !options.generic_sharing {
+            args.push("--disable-generic-sharing".into());
+        }
+        args.extend(options.extra_args.iter().map(OsString::from));
+        args
+    }
+
+    /// Hashes everything that affects the generated sources: the managed assemblies, which
+    /// include the mod's dll, the unity version and the il2cpp arguments, which include the
+    /// code generation options
+    fn fingerprint(&self, args: &[OsString]) -> Result<String> {
+        let mut entries = fs::read_dir(MANAGED_PATH)
+            .context("failed to read build/Managed")?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut hasher = CacheHasher::default();
+        for entry in entries {
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let data = fs::read(entry.path())
+                .with_context(|| format!("failed to read {}", entry.path().display()))?;
+            hasher.update_os_str(entry.file_name());
+            hasher.update(data);
+        }
+        hasher.update(&self.unity_version);
+        hasher.update_os_str(&self.il2cpp_path);
+        hasher.update_all(args.iter().map(|arg| arg.to_string_lossy().into_owned()));
+        Ok(hasher.finish())
+    }
+
+    /// Copies the mod's dll into build/Managed and runs il2cpp if anything changed since the
+    /// last time it generated `cpp_path`. `input_dir` is remembered for the next build when
+    /// given.
+    pub fn regenerate(
+        &self,
+        mod_config: &Mod,
+        input_dir: Option<PathBuf>,
+        cpp_path: &Path,
+    ) -> Result<()> {
+        let mut state = Il2CppState::load()?;
+        if let Some(input_dir) = input_dir {
+            state.input_dir = Some(input_dir);
+            state.save()?;
+        }
+
+        match &state.input_dir {
+            Some(input_dir) => copy_input(mod_config, input_dir)?,
+            None if !cpp_path.exists() => bail!(
+                "there is no C# project to compile, pass `--regen-cpp <dir>` with the directory containing {}.dll",
+                mod_config.id
+            ),
+            None => {}
+        }
+
+        let args = self.args(cpp_path);
+        let fingerprint = self.fingerprint(&args)?;
+        if cpp_path.exists() && state.fingerprint.as_ref() == Some(&fingerprint) {
+            debug!("il2cpp inputs are unchanged, skipping regeneration");
+            return Ok(());
+        }
+
+        println!("Running il2cpp");
+        if cpp_path.exists() {
+            fs::remove_dir_all(cpp_path)?;
+        }
+        fs::create_dir_all(cpp_path)?;
+        // Forget the old fingerprint in case il2cpp fails halfway through
+        state.fingerprint = None;
+        state.save()?;
+
+        let mut command = Command::new(&self.mono_path);
+        // Fix for System.ConsoleDriver type initializer
+        command
+            .env("TERM", "xterm")
+            // Rider adds this which breaks things apparently
+            .env_remove("MONO_GAC_PREFIX")
+            .arg(&self.il2cpp_path)
+            .args(&args);
+        debug!("il2cpp command: {:?}", &command);
+        let status = command.status().context("il2cpp command failed")?;
+        if !status.success() {
+            bail!("il2cpp exited with {}", status);
+        }
+
+        state.fingerprint = Some(fingerprint);
+        state.save()
+    }
+}
+
+fn copy_input(mod_config: &Mod, input_dir: &Path) -> Result<()> {
+    let file_name = mod_config.id.clone() + ".dll";
+    let src_path = input_dir.join(&file_name);
+    let managed_path = Path::new(MANAGED_PATH).join(&file_name);
+    fs::copy(&src_path, managed_path)
+        .with_context(|| format!("failed to copy {}", src_path.display()))?;
+
+    Ok(())
+}
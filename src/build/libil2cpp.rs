@@ -0,0 +1,378 @@
+//! Finds out which generic methods the game's `libil2cpp.so` has code for, by following its
+//! `g_MetadataRegistration` the same way Il2CppDumper does
+
+use color_eyre::eyre::{bail, ContextCompat, Result};
+use merge_data::MetadataRevision;
+use std::collections::{HashMap, HashSet};
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_W: u32 = 2;
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const R_AARCH64_RELATIVE: u32 = 1027;
+
+// Every field of Il2CppMetadataRegistration is a count padded to 8 bytes followed by a pointer
+const GENERIC_METHOD_TABLE_FIELD: u64 = 4;
+const METHOD_SPECS_FIELD: u64 = 8;
+const FIELD_OFFSETS_FIELD: u64 = 10;
+const TYPE_DEFINITION_SIZES_FIELD: u64 = 12;
+
+const METHOD_SPEC_SIZE: u64 = 12;
+
+/// Tables in libil2cpp.so are never this big, so a count that is must belong to something else
+const MAX_TABLE_LEN: i32 = 1 << 24;
+
+fn read_bytes<const N: usize>(data: &[u8], offset: u64) -> Result<[u8; N]> {
+    let start = usize::try_from(offset)?;
+    let bytes = data
+        .get(start..)
+        .and_then(|data| data.get(..N))
+        .context("libil2cpp.so is truncated")?;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: u64) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u32(data: &[u8], offset: u64) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u64(data: &[u8], offset: u64) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(data, offset)?))
+}
+
+struct Segment {
+    vaddr: u64,
+    offset: u64,
+    file_size: u64,
+    writable: bool,
+}
+
+/// Just enough of an ELF reader to follow pointers in a shared library that hasn't been loaded
+struct Elf<'a> {
+    data: &'a [u8],
+    segments: Vec<Segment>,
+    /// The pointers that the dynamic linker fills in, by their address. Pointers in a position
+    /// independent library are 0 in the file.
+    relocations: HashMap<u64, u64>,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(b"\x7fELF") {
+            bail!("libil2cpp.so is not an ELF file");
+        }
+        if data.get(4..6) != Some(&[2, 1]) {
+            bail!("libil2cpp.so is not a little endian 64-bit library");
+        }
+
+        let ph_offset = read_u64(data, 0x20)?;
+        let ph_size = read_u16(data, 0x36)? as u64;
+        let ph_count = read_u16(data, 0x38)? as u64;
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        for i in 0..ph_count {
+            let ph = ph_offset + i * ph_size;
+            let offset = read_u64(data, ph + 8)?;
+            let vaddr = read_u64(data, ph + 16)?;
+            let file_size = read_u64(data, ph + 32)?;
+            match read_u32(data, ph)? {
+                PT_LOAD => segments.push(Segment {
+                    vaddr,
+                    offset,
+                    file_size,
+                    writable: read_u32(data, ph + 4)? & PF_W != 0,
+                }),
+                PT_DYNAMIC => dynamic = Some((offset, file_size)),
+                _ => {}
+            }
+        }
+
+        let mut elf = Self {
+            data,
+            segments,
+            relocations: HashMap::new(),
+        };
+        let (dyn_offset, dyn_size) = dynamic.context("libil2cpp.so has no dynamic section")?;
+        let mut rela = None;
+        let mut rela_size = 0;
+        for entry in (dyn_offset..dyn_offset.saturating_add(dyn_size)).step_by(16) {
+            match read_u64(data, entry)? {
+                DT_NULL => break,
+                DT_RELA => rela = Some(read_u64(data, entry + 8)?),
+                DT_RELASZ => rela_size = read_u64(data, entry + 8)?,
+                _ => {}
+            }
+        }
+        if let Some(rela) = rela {
+            let rela_offset = elf.offset(rela, rela_size)?;
+            for entry in (rela_offset..rela_offset + rela_size).step_by(24) {
+                if read_u64(data, entry + 8)? as u32 == R_AARCH64_RELATIVE {
+                    let addr = read_u64(data, entry)?;
+                    elf.relocations.insert(addr, read_u64(data, entry + 16)?);
+                }
+            }
+        }
+        Ok(elf)
+    }
+
+    /// The file offset of `len` bytes at `vaddr`
+    fn offset(&self, vaddr: u64, len: u64) -> Result<u64> {
+        self.segments
+            .iter()
+            .find(|segment| {
+                vaddr >= segment.vaddr
+                    && vaddr.saturating_add(len) <= segment.vaddr + segment.file_size
+            })
+            .map(|segment| vaddr - segment.vaddr + segment.offset)
+            .with_context(|| format!("address {:#x} is not in libil2cpp.so", vaddr))
+    }
+
+    fn read_i32(&self, vaddr: u64) -> Result<i32> {
+        Ok(read_u32(self.data, self.offset(vaddr, 4)?)? as i32)
+    }
+
+    fn read_u64(&self, vaddr: u64) -> Result<u64> {
+        read_u64(self.data, self.offset(vaddr, 8)?)
+    }
+
+    fn read_ptr(&self, vaddr: u64) -> Result<u64> {
+        match self.relocations.get(&vaddr) {
+            Some(&ptr) => Ok(ptr),
+            None => self.read_u64(vaddr),
+        }
+    }
+
+    /// Every aligned address in the writable segments, which is where the registrations are
+    fn data_addresses(&self) -> impl Iterator<Item = u64> + '_ {
+        self.segments
+            .iter()
+            .filter(|segment| segment.writable)
+            .flat_map(|segment| {
+                let start = (segment.vaddr + 7) & !7;
+                let end = segment.vaddr + segment.file_size;
+                (start..end.saturating_sub(7)).step_by(8)
+            })
+    }
+}
+
+/// A count and a pointer to its table in the metadata registration
+struct Table {
+    len: u64,
+    ptr: u64,
+}
+
+fn read_table(elf: &Elf, registration: u64, field: u64) -> Result<Table> {
+    let len = elf.read_i32(registration + field * 8)?;
+    if !(0..MAX_TABLE_LEN).contains(&len) {
+        bail!("invalid table size {}", len);
+    }
+    let ptr = elf.read_ptr(registration + field * 8 + 8)?;
+    Ok(Table {
+        len: len as u64,
+        ptr,
+    })
+}
+
+/// Searches the library for `g_MetadataRegistration`. Both `fieldOffsetsCount` and
+/// `typeDefinitionsSizesCount` are the number of type definitions, which is rare enough for a
+/// pair of values that only the registration matches.
+fn find_metadata_registration(elf: &Elf, type_def_count: usize) -> Result<u64> {
+    let type_def_count = type_def_count as u64;
+    let gap = (TYPE_DEFINITION_SIZES_FIELD - FIELD_OFFSETS_FIELD) * 8;
+    let mut candidates = Vec::new();
+    for addr in elf.data_addresses() {
+        if elf.read_u64(addr)? != type_def_count
+            || elf.read_u64(addr + gap).ok() != Some(type_def_count)
+        {
+            continue;
+        }
+        let registration = match addr.checked_sub(FIELD_OFFSETS_FIELD * 8) {
+            Some(registration) => registration,
+            None => continue,
+        };
+        let tables_valid = [GENERIC_METHOD_TABLE_FIELD, METHOD_SPECS_FIELD]
+            .iter()
+            .all(|&field| read_table(elf, registration, field).is_ok());
+        if tables_valid {
+            candidates.push(registration);
+        }
+    }
+    match candidates[..] {
+        [registration] => Ok(registration),
+        [] => bail!("could not find the metadata registration in libil2cpp.so, make sure it is from the same version of the game as its global-metadata.dat"),
+        _ => bail!(
+            "found {} possible metadata registrations in libil2cpp.so",
+            candidates.len()
+        ),
+    }
+}
+
+/// The method definitions that the game's `libil2cpp.so` has generic code for, with any type
+/// arguments. `type_def_count` is the number of type definitions in the game's metadata.
+pub fn compiled_generic_methods(
+    data: &[u8],
+    type_def_count: usize,
+    revision: MetadataRevision,
+) -> Result<HashSet<u32>> {
+    let elf = Elf::parse(data)?;
+    let registration = find_metadata_registration(&elf, type_def_count)?;
+    let generic_methods = read_table(&elf, registration, GENERIC_METHOD_TABLE_FIELD)?;
+    let method_specs = read_table(&elf, registration, METHOD_SPECS_FIELD)?;
+
+    // Il2CppGenericMethodFunctionsDefinitions is the generic method index followed by the
+    // indices of its method, invoker and, since 24.5, adjustor thunk
+    let entry_size = if revision.has_generic_adjustor_thunks() {
+        16
+    } else {
+        12
+    };
+    let mut methods = HashSet::new();
+    for i in 0..generic_methods.len {
+        let generic_method_idx = elf.read_i32(generic_methods.ptr + i * entry_size)?;
+        if !(0..method_specs.len as i32).contains(&generic_method_idx) {
+            bail!(
+                "generic method table entry {} has invalid method spec {}",
+                i,
+                generic_method_idx
+            );
+        }
+        let method_spec = method_specs.ptr + generic_method_idx as u64 * METHOD_SPEC_SIZE;
+        let method_def_idx = elf.read_i32(method_spec)?;
+        if method_def_idx >= 0 {
+            methods.insert(method_def_idx as u32);
+        }
+    }
+    Ok(methods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x10000;
+    const TYPE_DEF_COUNT: u64 = 3;
+
+    fn push_u64(data: &mut Vec<u8>, value: u64) {
+        data.extend(value.to_le_bytes());
+    }
+
+    fn push_i32s(data: &mut Vec<u8>, values: &[i32]) {
+        for value in values {
+            data.extend(value.to_le_bytes());
+        }
+    }
+
+    /// A library with one writable segment, which holds a metadata registration with two method
+    /// specs and a generic method table that uses the second one twice. Pointers are relocated
+    /// like in a real library.
+    fn write_library(entry_size: usize) -> Vec<u8> {
+        // The data segment starts after the ELF header and two program headers
+        let data_offset = 64 + 2 * 56;
+        let mut segment = Vec::new();
+        // Padding with another copy of the type definition count, which isn't the registration
+        push_u64(&mut segment, TYPE_DEF_COUNT);
+
+        let specs_addr = BASE + segment.len() as u64;
+        push_i32s(&mut segment, &[7, -1, 0, 9, -1, 0]);
+        let table_addr = BASE + segment.len() as u64;
+        for _ in 0..2 {
+            let mut entry = Vec::new();
+            push_i32s(&mut entry, &[1, 0, 0, -1]);
+            segment.extend(&entry[..entry_size]);
+        }
+        while segment.len() % 8 != 0 {
+            segment.push(0);
+        }
+
+        let registration = BASE + segment.len() as u64;
+        let fields = [
+            (0, 0),
+            (0, 0),
+            (2, table_addr),
+            (0, 0),
+            (2, specs_addr),
+            (TYPE_DEF_COUNT, 0),
+            (TYPE_DEF_COUNT, 0),
+            (0, 0),
+        ];
+        let mut relocations = Vec::new();
+        for (i, (count, ptr)) in fields.into_iter().enumerate() {
+            push_u64(&mut segment, count);
+            if ptr != 0 {
+                relocations.push((registration + i as u64 * 16 + 8, ptr));
+            }
+            push_u64(&mut segment, 0);
+        }
+
+        let rela_addr = BASE + segment.len() as u64;
+        for (addr, ptr) in &relocations {
+            push_u64(&mut segment, *addr);
+            push_u64(&mut segment, R_AARCH64_RELATIVE as u64);
+            push_u64(&mut segment, *ptr);
+        }
+        let dynamic_addr = BASE + segment.len() as u64;
+        for value in [
+            DT_RELA,
+            rela_addr,
+            DT_RELASZ,
+            relocations.len() as u64 * 24,
+            0,
+            0,
+        ] {
+            push_u64(&mut segment, value);
+        }
+        let dynamic_offset = data_offset + (dynamic_addr - BASE);
+
+        let mut data = Vec::new();
+        data.extend(b"\x7fELF\x02\x01\x01");
+        data.resize(0x20, 0);
+        push_u64(&mut data, 64);
+        data.resize(0x36, 0);
+        data.extend(56u16.to_le_bytes());
+        data.extend(2u16.to_le_bytes());
+        data.resize(64, 0);
+        for (p_type, offset, vaddr) in [
+            (PT_LOAD, data_offset, BASE),
+            (PT_DYNAMIC, dynamic_offset, dynamic_addr),
+        ] {
+            let size = if p_type == PT_LOAD { segment.len() } else { 48 } as u64;
+            data.extend(p_type.to_le_bytes());
+            data.extend((PF_W | 4).to_le_bytes());
+            push_u64(&mut data, offset);
+            push_u64(&mut data, vaddr);
+            push_u64(&mut data, vaddr);
+            push_u64(&mut data, size);
+            push_u64(&mut data, size);
+            push_u64(&mut data, 8);
+        }
+        data.extend(segment);
+        data
+    }
+
+    #[test]
+    fn finds_generic_methods() {
+        let library = write_library(12);
+        let methods =
+            compiled_generic_methods(&library, TYPE_DEF_COUNT as usize, MetadataRevision::V24_4)
+                .unwrap();
+        assert_eq!(methods, HashSet::from([9]));
+
+        let library = write_library(16);
+        let methods =
+            compiled_generic_methods(&library, TYPE_DEF_COUNT as usize, MetadataRevision::V24_5)
+                .unwrap();
+        assert_eq!(methods, HashSet::from([9]));
+    }
+
+    #[test]
+    fn requires_matching_metadata() {
+        let library = write_library(12);
+        assert!(compiled_generic_methods(&library, 4, MetadataRevision::V24_4).is_err());
+        assert!(compiled_generic_methods(&library[..32], 3, MetadataRevision::V24_4).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use super::clang::CompileCommand;
 use super::data::ModDataBuilder;
+use super::parser::{element_ident, parse_items, Item, SourceRewriter};
+use super::tokenizer::{SourceFile, TokenKind};
 use color_eyre::eyre::{ContextCompat, Result};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
@@ -15,18 +17,23 @@ pub fn transform(
     mod_functions: &HashSet<String>,
 ) -> Result<(Vec<String>, usize)> {
     let src = fs::read_to_string(cpp_path.join("Il2CppMetadataUsage.c"))?;
+    let items = parse_items(&SourceFile::new("Il2CppMetadataUsage.c", &src))?;
 
     // The names and indices of the metadata usage ranges
     let mut required_usage_ids = Vec::new();
-    for line in src.lines() {
-        if line.starts_with("const uint32_t") {
-            let words: Vec<&str> = line.split_whitespace().collect();
-            let name = words[2];
-            let fn_name = &name[..name.len() - 16];
-            if mod_functions.contains(fn_name) {
-                let id = words[4].trim_end_matches(';').parse::<u32>()?;
-                required_usage_ids.push((name, id));
+    for item in &items {
+        let (name, value) = match (item.name, item.body()) {
+            (Some(name), Some(value)) if name.ends_with("_MetadataUsageId") => (name, value),
+            _ => continue,
+        };
+        let fn_name = name.trim_end_matches("_MetadataUsageId");
+        if mod_functions.contains(fn_name) {
+            let id = match value {
+                [id] if id.kind == TokenKind::Number => id.text.parse::<u32>().ok(),
+                _ => None,
             }
+            .ok_or_else(|| item.error("expected a metadata usage id"))?;
+            required_usage_ids.push((name, id));
         }
     }
 
@@ -40,69 +47,49 @@ pub fn transform(
         writeln!(new_usage_ids, "const uint32_t {} = {};", name, new_idx)?;
     }
 
+    let usages = items
+        .iter()
+        .find(|item| item.name == Some("g_MetadataUsages") && item.body().is_some())
+        .context("could not find g_MetadataUsages")?;
+    let usage_elements = usages.array_elements()?;
     let mut using_names = HashSet::new();
     let mut new_list = Vec::new();
-
-    let arr_start = src
-        .find("const g_MetadataUsages")
-        .context("could not find g_MetadataUsages")?;
     for usage in usage_list {
-        let line = src[arr_start..]
-            .lines()
-            .nth(3 + usage)
-            .context("metadata usage out of range")?;
-        let name = line
-            .trim()
-            .trim_start_matches("(void**)(&")
-            .trim_end_matches("),");
+        let name = usage_elements
+            .get(usage)
+            .and_then(|element| element_ident(element))
+            .ok_or_else(|| usages.error(format!("metadata usage {} is out of range", usage)))?;
         new_list.push(name);
         using_names.insert(name);
     }
 
     let mut usage_fds = Vec::new();
-    let mut new_src = String::new();
-    let mut lines = src.lines();
-    while let Some(line) = lines.next() {
-        if line.starts_with("const RuntimeType*")
-            || line.starts_with("RuntimeClass*")
-            || line.starts_with("const RuntimeMethod*")
-            || line.starts_with("RuntimeField*")
-            || line.starts_with("String_t*")
-        {
-            let name = line
-                .trim_start_matches("const ")
-                .trim_end_matches(';')
-                .split_whitespace()
-                .nth(1)
-                .unwrap();
-            if using_names.contains(&name) {
-                writeln!(new_src, "{}", line)?;
-                usage_fds.push(line.to_string());
-            }
-        } else if line.starts_with("void** const g_MetadataUsages") {
-            writeln!(
-                new_src,
-                "void** const g_MetadataUsages[{}] = ",
-                new_list.len()
-            )?;
-            writeln!(new_src, "{{")?;
+    let mut rewriter = SourceRewriter::new(&src);
+    for item in &items {
+        let name = match item.name {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.ends_with("_MetadataUsageId") {
+            rewriter.remove(item);
+        } else if std::ptr::eq(item, usages) {
+            let mut new_usages =
+                format!("void** const g_MetadataUsages[{}] = \n{{\n", new_list.len());
             for name in &new_list {
-                writeln!(new_src, "    (void**)(&{}),", name)?;
+                writeln!(new_usages, "    (void**)(&{}),", name)?;
             }
-            writeln!(new_src, "}};")?;
-
-            for line in lines.by_ref() {
-                if line == "};" {
-                    break;
-                }
+            new_usages.push_str("};");
+            rewriter.replace(item, &new_usages);
+        } else if item.fn_decl.is_none() && item.body().is_none() && is_usage_decl(item) {
+            if using_names.contains(name) {
+                usage_fds.push(item.text.to_string());
+            } else {
+                rewriter.remove(item);
             }
-        } else if !line
-            .trim_start_matches("extern ")
-            .starts_with("const uint32_t")
-        {
-            writeln!(new_src, "{}", line)?;
         }
     }
+    let mut new_src = rewriter.finish();
+    new_src.push('\n');
     new_src.push_str(&new_usage_ids);
 
     let new_path = transformed_path.join("Il2CppMetadataUsage.c");
@@ -111,3 +98,54 @@ pub fn transform(
 
     Ok((usage_fds, new_list.len()))
 }
+
+/// Whether an item declares one of the variables that metadata usages point to
+fn is_usage_decl(item: &Item) -> bool {
+    let ty = item
+        .tokens()
+        .iter()
+        .find(|token| token.kind == TokenKind::Ident && token.text != "const");
+    matches!(
+        ty.map(|token| token.text),
+        Some("RuntimeType" | "RuntimeClass" | "RuntimeMethod" | "RuntimeField" | "String_t")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_usages() {
+        let src = include_str!("fixtures/Il2CppMetadataUsage.c");
+        let items = parse_items(&SourceFile::new("Il2CppMetadataUsage.c", src)).unwrap();
+
+        let decls: Vec<_> = items
+            .iter()
+            .filter(|item| item.fn_decl.is_none() && item.body().is_none() && is_usage_decl(item))
+            .filter_map(|item| item.name)
+            .collect();
+        let usages = items
+            .iter()
+            .find(|item| item.name == Some("g_MetadataUsages"))
+            .unwrap();
+        let usages: Vec<_> = usages
+            .array_elements()
+            .unwrap()
+            .into_iter()
+            .map(|element| element_ident(element).unwrap())
+            .collect();
+        assert_eq!(decls.len(), 5);
+        assert_eq!(decls, usages);
+
+        let ids: Vec<_> = items
+            .iter()
+            .filter(|item| {
+                item.name
+                    .map_or(false, |name| name.ends_with("_MetadataUsageId"))
+            })
+            .map(|item| item.body().map(|value| value[0].text))
+            .collect();
+        assert_eq!(ids, [None, None, Some("0"), Some("1")]);
+    }
+}
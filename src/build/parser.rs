@@ -1,4 +1,6 @@
-use color_eyre::eyre::{bail, Result};
+use super::tokenizer::{matching_close, split_list, SourceFile, Token, TokenKind, Tokenizer};
+use color_eyre::eyre::{bail, eyre, ContextCompat, Report, Result};
+use std::collections::{HashMap, HashSet};
 
 pub fn is_included_ty(name: &str) -> bool {
     matches!(
@@ -40,108 +42,870 @@ pub fn is_included_ty(name: &str) -> bool {
 pub struct FnDecl<'a> {
     pub return_ty: &'a str,
     pub name: &'a str,
+    /// The parameter list, including its parentheses
     pub params: &'a str,
     pub inline: bool,
+    /// Declared with `IL2CPP_EXTERN_C`, or `extern "C"` by il2cpp before 2019
+    pub extern_c: bool,
 }
 
 impl<'a> FnDecl<'a> {
-    pub fn try_parse(line: &'a str) -> Option<Self> {
-        let (line, inline) = if let Some(line) =
-            line.strip_prefix("IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR")
-        {
-            (line, false)
-        } else if let Some(line) = line.strip_prefix("IL2CPP_EXTERN_C inline IL2CPP_METHOD_ATTR") {
-            (line, true)
-        } else if let Some(line) = line.strip_prefix("IL2CPP_EXTERN_C inline  IL2CPP_METHOD_ATTR") {
-            (line, true)
-        } else {
-            (line.strip_prefix("IL2CPP_EXTERN_C")?, false)
-        };
-
-        let param_start = line.find('(')?;
-        let params = &line[param_start..];
-        let rest = line[..param_start].trim();
+    /// Parses the tokens of a function declaration or definition such as
+    /// `IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR void Foo_m...(int32_t ___x0, const RuntimeMethod* method)`
+    fn from_tokens(src: &'a str, tokens: &[Token<'a>]) -> Option<Self> {
+        let mut tokens = tokens;
+        let mut inline = false;
+        let mut extern_c = false;
+        loop {
+            tokens = match tokens {
+                [first, rest @ ..] if first.is_ident("IL2CPP_EXTERN_C") => {
+                    extern_c = true;
+                    rest
+                }
+                [first, second, rest @ ..]
+                    if first.is_ident("extern")
+                        && second.kind == TokenKind::Literal
+                        && second.text == "\"C\"" =>
+                {
+                    extern_c = true;
+                    rest
+                }
+                [first, rest @ ..] if first.is_ident("inline") => {
+                    inline = true;
+                    rest
+                }
+                [first, rest @ ..]
+                    if matches!(
+                        first.text,
+                        "static" | "IL2CPP_METHOD_ATTR" | "IL2CPP_NO_INLINE"
+                    ) && first.kind == TokenKind::Ident =>
+                {
+                    rest
+                }
+                _ => break,
+            };
+        }
 
-        let name = rest.split_whitespace().last()?;
-        let return_ty = rest[..rest.len() - name.len()].trim();
+        let param_start = tokens.iter().position(|token| token.is_punct("("))?;
+        let param_end = matching_close(tokens, param_start)?;
+        let (name, return_ty) = match &tokens[..param_start] {
+            [return_ty @ .., name] if name.kind == TokenKind::Ident && !return_ty.is_empty() => {
+                (name, return_ty)
+            }
+            _ => return None,
+        };
+        let return_ty = &src[return_ty[0].offset..return_ty[return_ty.len() - 1].end()];
 
         Some(FnDecl {
             return_ty,
-            name,
-            params,
+            name: name.text,
+            params: &src[tokens[param_start].offset..tokens[param_end].end()],
             inline,
+            extern_c,
         })
     }
+
+    /// Parses a single `IL2CPP_EXTERN_C` function declaration, like the ones collected in
+    /// `ModFunctionUsages::forward_decls`
+    pub fn try_parse(decl: &'a str) -> Option<Self> {
+        let tokens: Vec<_> = Tokenizer::new(decl).map_while(|token| token.ok()).collect();
+        Self::from_tokens(decl, &tokens).filter(|fn_decl| fn_decl.extern_c)
+    }
+}
+
+/// Whether `name` looks like the name il2cpp gives to method definitions, `<name>_m<40 hex>`
+fn is_method_name(name: &str) -> bool {
+    let len = name.len();
+    len > 42
+        && &name[len - 42..len - 40] == "_m"
+        && name[len - 40..]
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+/// Macros that il2cpp puts between declarations on their own
+const STANDALONE_MACROS: &[&str] = &["IL2CPP_EXTERN_C_BEGIN", "IL2CPP_EXTERN_C_END"];
+
+/// A top level declaration or definition in a source. It ends at its `;`, or at the `}` of its
+/// body for function definitions.
+pub struct Item<'src> {
+    file: SourceFile<'src>,
+    /// The name of the function or variable that is declared
+    pub name: Option<&'src str>,
+    /// Set when the item declares or defines a function
+    pub fn_decl: Option<FnDecl<'src>>,
+    /// The whole item as written in the source
+    pub text: &'src str,
+    /// The item up to the function body, the `=` of its initializer or its `;`
+    pub head: &'src str,
+    /// Byte offset of the item in the source
+    pub offset: usize,
+    /// Line of the item's first token
+    pub line: usize,
+    tokens: Vec<Token<'src>>,
+    /// Index into `tokens` of the `{` of a function body or the `=` of an initializer
+    body_start: Option<usize>,
+}
+
+impl<'src> Item<'src> {
+    pub fn error(&self, msg: impl std::fmt::Display) -> Report {
+        self.file.error(self.line, msg)
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.text.len()
+    }
+
+    /// Whether this defines a function, as opposed to only declaring it
+    pub fn is_fn_definition(&self) -> bool {
+        self.fn_decl.is_some() && self.body_start.is_some()
+    }
+
+    pub fn tokens(&self) -> &[Token<'src>] {
+        &self.tokens
+    }
+
+    /// The tokens inside of a function body or after the `=` of an initializer, leaving out the
+    /// closing `}` or `;`
+    pub fn body(&self) -> Option<&[Token<'src>]> {
+        let start = self.body_start?;
+        Some(&self.tokens[start + 1..self.tokens.len() - 1])
+    }
+
+    /// The il2cpp methods called in a function body. Functions ending in `_inline` are defined in
+    /// the same source, so they're only included with `include_inline`.
+    pub fn calls(&self, include_inline: bool) -> Vec<&'src str> {
+        let body = match (&self.fn_decl, self.body()) {
+            (Some(_), Some(body)) => body,
+            _ => return Vec::new(),
+        };
+        body.windows(2)
+            .filter(|pair| pair[0].kind == TokenKind::Ident && pair[1].is_punct("("))
+            .map(|pair| pair[0].text)
+            .filter(|name| include_inline || !name.ends_with("_inline"))
+            .filter(|name| is_method_name(name.trim_end_matches("_inline")))
+            .collect()
+    }
+
+    /// The elements of a brace initializer like `= { a, b, c };`
+    pub fn array_elements(&self) -> Result<Vec<&'src str>> {
+        let body = match self.body() {
+            Some([open, elements @ .., close]) if open.is_punct("{") && close.is_punct("}") => {
+                elements
+            }
+            _ => return Err(self.error("expected a brace initializer")),
+        };
+        Ok(split_list(body)
+            .into_iter()
+            .map(|element| &self.file.src[element[0].offset..element[element.len() - 1].end()])
+            .collect())
+    }
 }
 
-pub fn try_parse_call(line: &str, include_inline: bool) -> Option<&str> {
-    let possible_name = if let Some(pos) = line.find("= ") {
-        &line[pos + 2..]
+/// Splits a source into its top level items. The insides of `extern "C"` and namespace blocks
+/// are treated as if they were at the top level.
+pub fn parse_items<'src>(file: &SourceFile<'src>) -> Result<Vec<Item<'src>>> {
+    let tokens = file.tokenize()?;
+    let mut items = Vec::new();
+
+    let mut start = 0;
+    // The first `(`, `[`, `=`, `{` or `;` of the item, which tells what it is
+    let mut first_punct: Option<usize> = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if i == start
+            && (token.kind == TokenKind::Ident && STANDALONE_MACROS.contains(&token.text)
+                || token.is_punct("}"))
+        {
+            // Either a macro or the end of a block that was opened at the top level
+            i += 1;
+            start = i;
+            continue;
+        }
+        if token.kind != TokenKind::Punct || !matches!(token.text, "(" | "[" | "=" | "{" | ";") {
+            i += 1;
+            continue;
+        }
+        let first = *first_punct.get_or_insert(i);
+
+        if token.is_punct("{") {
+            let is_block = match &tokens[start..i] {
+                [] => true,
+                [ext, c] if ext.is_ident("extern") && c.text == "\"C\"" => true,
+                [namespace, ..] if namespace.is_ident("namespace") => true,
+                _ => false,
+            };
+            if is_block {
+                i += 1;
+                start = i;
+                first_punct = None;
+                continue;
+            }
+            let close =
+                matching_close(&tokens, i).ok_or_else(|| file.error(token.line, "unclosed '{'"))?;
+            let is_fn_body = tokens[first].is_punct("(")
+                && !tokens[first..i].iter().any(|token| token.is_punct("="));
+            if is_fn_body {
+                items.push(make_item(file, &tokens[start..=close], first - start));
+                i = close + 1;
+                start = i;
+                first_punct = None;
+            } else {
+                i = close + 1;
+            }
+        } else if token.is_punct("(") || token.is_punct("[") {
+            i = matching_close(&tokens, i)
+                .ok_or_else(|| file.error(token.line, format!("unclosed '{}'", token.text)))?
+                + 1;
+        } else if token.is_punct(";") {
+            items.push(make_item(file, &tokens[start..=i], first - start));
+            i += 1;
+            start = i;
+            first_punct = None;
+        } else {
+            i += 1;
+        }
+    }
+    if start < tokens.len() {
+        return Err(file.error(tokens[start].line, "declaration is missing a `;`"));
+    }
+    Ok(items)
+}
+
+/// Builds an item from its tokens, `first` being the index of its first `(`, `[`, `=`, `{` or `;`
+fn make_item<'src>(file: &SourceFile<'src>, tokens: &[Token<'src>], first: usize) -> Item<'src> {
+    let src = file.src;
+    let last = &tokens[tokens.len() - 1];
+    let text = &src[tokens[0].offset..last.end()];
+
+    let fn_decl = if tokens[first].is_punct("(") {
+        FnDecl::from_tokens(src, tokens)
     } else {
-        line.trim()
+        None
     };
-    let possible_name = possible_name.split('(').next().unwrap();
-    if possible_name.ends_with("_inline") && !include_inline {
-        // Inlined functions will be defined in the same file anyways
-        return None;
-    }
-    let test_name = possible_name.trim_end_matches("_inline");
-    let len = test_name.len();
-    if test_name.len() <= 42 {
-        return None;
-    }
-    if &test_name[len - 42..len - 40] == "_m" {
-        let valid_id = test_name[len - 40..]
-            .chars()
-            .all(|c| ('A'..='Z').contains(&c) || ('0'..='9').contains(&c));
-        if valid_id {
-            return Some(possible_name);
+    let body_start = if fn_decl.is_some() {
+        tokens
+            .iter()
+            .position(|token| token.is_punct("{"))
+            .filter(|_| last.is_punct("}"))
+    } else {
+        tokens.iter().position(|token| token.is_punct("="))
+    };
+    let head_end = match body_start {
+        Some(body_start) => body_start,
+        None => tokens.len() - 1,
+    };
+    let head = &src[tokens[0].offset..tokens[head_end.max(1) - 1].end()];
+
+    // Only for struct definitions and declarations, not variables like `struct Foo_t* x;`
+    let tag = tokens[..first].iter().position(|token| {
+        token.is_ident("struct") || token.is_ident("class") || token.is_ident("union")
+    });
+    let tag = tag.filter(|&tag| {
+        tokens
+            .get(tag + 2)
+            .map_or(false, |token| matches!(token.text, "{" | ":" | ";"))
+    });
+    let name = match (&fn_decl, tag) {
+        (Some(fn_decl), _) => Some(fn_decl.name),
+        // The last identifier of `struct Foo_t : public RuntimeObject` is the base
+        (None, Some(tag)) => tokens
+            .get(tag + 1)
+            .filter(|token| token.kind == TokenKind::Ident)
+            .map(|token| token.text),
+        (None, None) => tokens[..first.max(1)]
+            .iter()
+            .rev()
+            .find(|token| token.kind == TokenKind::Ident)
+            .map(|token| token.text),
+    };
+
+    Item {
+        file: *file,
+        name,
+        fn_decl,
+        text,
+        head,
+        offset: tokens[0].offset,
+        line: tokens[0].line,
+        tokens: tokens.to_vec(),
+        body_start,
+    }
+}
+
+/// Writes a new version of a source where some of its items are left out or replaced. Everything
+/// between the items, like preprocessor directives, is kept as is.
+pub struct SourceRewriter<'src> {
+    src: &'src str,
+    pos: usize,
+    out: String,
+}
+
+impl<'src> SourceRewriter<'src> {
+    pub fn new(src: &'src str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            out: String::new(),
+        }
+    }
+
+    /// Leaves `item` out of the new source
+    pub fn remove(&mut self, item: &Item) {
+        self.out.push_str(&self.src[self.pos..item.offset]);
+        self.pos = item.end();
+    }
+
+    /// Writes `text` in place of `item`
+    pub fn replace(&mut self, item: &Item, text: &str) {
+        self.remove(item);
+        self.out.push_str(text);
+    }
+
+    pub fn finish(mut self) -> String {
+        self.out.push_str(&self.src[self.pos..]);
+        self.out
+    }
+}
+
+/// Reads the integers out of an array element like `{ 0x02000004, { 0, -1 } }`, skipping over
+/// everything else such as casts
+pub fn element_ints(element: &str) -> Result<Vec<i64>> {
+    let tokens = Tokenizer::new(element)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|(_, msg)| eyre!("could not parse '{}': {}", element, msg))?;
+    let mut ints = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind != TokenKind::Number {
+            continue;
+        }
+        let text = token.text.trim_end_matches(['u', 'U', 'l', 'L']);
+        let int = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => text.parse(),
         }
+        .map_err(|_| eyre!("invalid integer {} in '{}'", token.text, element))?;
+        let negative = i > 0 && tokens[i - 1].is_punct("-");
+        ints.push(if negative { -int } else { int });
     }
+    Ok(ints)
+}
 
-    None
+/// The last identifier in an array element, which is the name in elements like `&Foo` or
+/// `(Il2CppMethodPointer)&Foo`
+pub fn element_ident(element: &str) -> Option<&str> {
+    Tokenizer::new(element)
+        .map_while(|token| token.ok())
+        .filter(|token| token.kind == TokenKind::Ident)
+        .last()
+        .map(|token| token.text)
 }
 
 pub struct SourceArrIterator<'src> {
-    lines: std::str::Lines<'src>,
+    src: &'src str,
+    elements: std::vec::IntoIter<(usize, usize)>,
 }
 
 impl<'src> Iterator for SourceArrIterator<'src> {
     type Item = &'src str;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.lines
+        self.elements
             .next()
-            .filter(|line| !line.starts_with('}'))
-            .map(|line| line.trim().trim_end_matches(','))
+            .map(|(start, end)| self.src[start..end].trim())
     }
 }
 
 pub struct SourceParser<'src> {
-    src: &'src str,
+    file: SourceFile<'src>,
 }
 
 impl<'src> SourceParser<'src> {
-    pub fn new(src: &'src str) -> Self {
-        Self { src }
+    pub fn new(name: &'src str, src: &'src str) -> Self {
+        Self {
+            file: SourceFile::new(name, src),
+        }
+    }
+
+    /// An error at the line of `element`, which has to be a slice of the source such as the
+    /// elements returned by `parse_array`
+    pub fn error_at(&self, element: &str, msg: impl std::fmt::Display) -> Report {
+        let offset = element.as_ptr() as usize - self.file.src.as_ptr() as usize;
+        let line = self.file.src[..offset].matches('\n').count() + 1;
+        self.file.error(line, msg)
     }
 
+    /// Finds an array definition like `<ty> <name>[N] = { ... };` and iterates over its
+    /// elements
     pub fn parse_array(&self, ty: &str, name: &str) -> Result<SourceArrIterator<'src>> {
-        let mut lines = self.src.lines();
+        self.find_array(ty, name)?
+            .with_context(|| format!("{}: could not find arr: {}", self.file.name, name))
+    }
 
-        let header = format!("{ty} {name}");
-        loop {
-            let Some(line) = lines.next() else {
-                bail!("Could not find arr: {}", name);
-            };
-            if line.starts_with(&header) {
+    /// Like `parse_array`, but for arrays that il2cpp leaves out when they would be empty
+    pub fn find_array(&self, ty: &str, name: &str) -> Result<Option<SourceArrIterator<'src>>> {
+        let tokens = self.file.tokenize()?;
+        let ty: Vec<_> = Tokenizer::new(ty).map_while(|token| token.ok()).collect();
+
+        // Only a definition, `<ty> <name>[N] = {`, has the body we're looking for
+        let open = match (ty.len()..tokens.len()).find_map(|i| {
+            if !tokens[i].is_ident(name)
+                || !tokens[i - ty.len()..i]
+                    .iter()
+                    .zip(&ty)
+                    .all(|(a, b)| a.text == b.text)
+                || !tokens.get(i + 1)?.is_punct("[")
+            {
+                return None;
+            }
+            let bracket_close = matching_close(&tokens, i + 1)?;
+            let is_def = tokens.get(bracket_close + 1)?.is_punct("=")
+                && tokens.get(bracket_close + 2)?.is_punct("{");
+            is_def.then(|| bracket_close + 2)
+        }) {
+            Some(open) => open,
+            None => return Ok(None),
+        };
+        let close = matching_close(&tokens, open).ok_or_else(|| {
+            self.file
+                .error(tokens[open].line, "array body is not closed")
+        })?;
+
+        let elements: Vec<_> = split_list(&tokens[open + 1..close])
+            .into_iter()
+            .map(|element| (element[0].offset, element[element.len() - 1].end()))
+            .collect();
+
+        Ok(Some(SourceArrIterator {
+            src: self.file.src,
+            elements: elements.into_iter(),
+        }))
+    }
+}
+
+/// A field of a struct definition
+#[derive(Clone, Copy)]
+pub struct FieldDef<'src> {
+    /// The field's type with qualifiers and pointers removed
+    pub ty: &'src str,
+    pub pointer: bool,
+}
+
+pub struct StructDef<'src> {
+    pub body: String,
+    pub parent: Option<&'src str>,
+    pub fields: Vec<FieldDef<'src>>,
+}
+
+/// Parses the member declarations of a struct body and collects its fields, skipping access
+/// specifiers, method definitions and unwrapping anonymous unions and structs
+fn parse_fields<'src>(
+    file: &SourceFile<'src>,
+    tokens: &[Token<'src>],
+    fields: &mut Vec<FieldDef<'src>>,
+) -> Result<()> {
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_punct(";") {
+            i += 1;
+            continue;
+        }
+        if matches!(token.text, "public" | "private" | "protected")
+            && tokens.get(i + 1).map_or(false, |t| t.is_punct(":"))
+        {
+            i += 2;
+            continue;
+        }
+        if (token.is_ident("union") || token.is_ident("struct"))
+            && tokens.get(i + 1).map_or(false, |t| t.is_punct("{"))
+        {
+            let close = matching_close(tokens, i + 1)
+                .ok_or_else(|| file.error(token.line, "unclosed anonymous struct or union"))?;
+            parse_fields(file, &tokens[i + 2..close], fields)?;
+            i = close + 1;
+            while i < tokens.len() && !tokens[i].is_punct(";") {
+                i += 1;
+            }
+            continue;
+        }
+
+        if token.is_ident("ALIGN_FIELD") && tokens.get(i + 1).map_or(false, |t| t.is_punct("(")) {
+            // `ALIGN_FIELD (8) int32_t ___x;`, the alignment doesn't matter to us
+            i = matching_close(tokens, i + 1)
+                .ok_or_else(|| file.error(token.line, "unclosed ALIGN_FIELD"))?
+                + 1;
+            continue;
+        }
+
+        // Find the end of the declaration, which is either a `;` or a method body
+        let mut end = i;
+        let mut is_method = false;
+        while end < tokens.len() {
+            let token = &tokens[end];
+            if token.is_punct(";") {
+                break;
+            }
+            if token.is_punct("{") {
+                is_method = true;
+                end = matching_close(tokens, end)
+                    .ok_or_else(|| file.error(token.line, "unclosed method body"))?;
                 break;
             }
+            if token.is_punct("(") {
+                is_method = true;
+            }
+            end += 1;
+        }
+        if end >= tokens.len() {
+            return Err(file.error(token.line, "struct member is missing a `;`"));
+        }
+
+        if !is_method {
+            let decl = &tokens[i..end];
+            let ty = decl
+                .iter()
+                .find(|t| t.kind == TokenKind::Ident && !matches!(t.text, "const" | "volatile"))
+                .ok_or_else(|| file.error(token.line, "struct field has no type"))?;
+            fields.push(FieldDef {
+                ty: ty.text,
+                pointer: decl.iter().any(|t| t.is_punct("*")),
+            });
+        }
+        i = end + 1;
+    }
+    Ok(())
+}
+
+/// Parses a type like `const Foo_t* ___x0` into its name and whether it is a pointer
+pub fn parse_ty<'src>(src: &'src str) -> Result<FieldDef<'src>> {
+    let tokens: Vec<_> = Tokenizer::new(src)
+        .collect::<Result<_, _>>()
+        .map_err(|(_, msg)| eyre!("could not parse cpp type '{}': {}", src, msg))?;
+    let ty = tokens
+        .iter()
+        .find(|t| t.kind == TokenKind::Ident && !matches!(t.text, "const" | "volatile"))
+        .with_context(|| format!("could not parse cpp type '{}'", src))?;
+    if ty.text == "typedef" {
+        bail!("unexpected typedef in cpp type '{}'", src);
+    }
+    Ok(FieldDef {
+        ty: ty.text,
+        pointer: tokens.iter().any(|t| t.is_punct("*")),
+    })
+}
+
+/// Finds every top level `template <...> struct Name { ... };` in a source and returns the name
+/// of each struct along with its full definition
+pub fn find_struct_templates<'src>(
+    name: &'src str,
+    src: &'src str,
+) -> Result<Vec<(&'src str, &'src str)>> {
+    let file = SourceFile::new(name, src);
+    let tokens = file.tokenize()?;
+
+    let mut templates = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_punct("{") {
+            i = matching_close(&tokens, i).ok_or_else(|| file.error(token.line, "unclosed '{'"))?
+                + 1;
+            continue;
+        }
+        if !token.is_ident("template") || !tokens.get(i + 1).map_or(false, |t| t.is_punct("<")) {
+            i += 1;
+            continue;
+        }
+
+        let params_close = matching_close(&tokens, i + 1)
+            .ok_or_else(|| file.error(token.line, "unclosed template parameters"))?;
+        let (struct_name, open) = match tokens.get(params_close + 1..params_close + 4) {
+            Some([keyword, name, open])
+                if keyword.is_ident("struct")
+                    && name.kind == TokenKind::Ident
+                    && open.is_punct("{") =>
+            {
+                (name.text, params_close + 3)
+            }
+            _ => {
+                i = params_close + 1;
+                continue;
+            }
+        };
+        let close = matching_close(&tokens, open)
+            .ok_or_else(|| file.error(tokens[open].line, "struct body is not closed"))?;
+        let end = match tokens.get(close + 1) {
+            Some(semi) if semi.is_punct(";") => semi.end(),
+            _ => return Err(file.error(tokens[close].line, "expected `;` after struct")),
+        };
+        templates.push((struct_name, &src[token.offset..end]));
+        i = close + 2;
+    }
+    Ok(templates)
+}
+
+/// The invoker helpers are written out separately, see `generics::transform`
+fn is_invoker(name: &str) -> bool {
+    let without_generic = name
+        .trim_start_matches("Generic")
+        .trim_start_matches("Virt")
+        .trim_start_matches("Interface");
+    without_generic.starts_with("FuncInvoker") || without_generic.starts_with("ActionInvoker")
+}
+
+/// Finds every top level struct forward declaration and definition in the sources, which are
+/// given as (file name, source) pairs. Definitions are only taken from the first file they appear
+/// in.
+pub fn find_struct_defs<'src>(
+    sources: impl IntoIterator<Item = (&'src str, &'src str)>,
+) -> Result<(HashSet<&'src str>, HashMap<&'src str, StructDef<'src>>)> {
+    let mut struct_fds = HashSet::new();
+    let mut struct_defs = HashMap::new();
+    for (name, src) in sources {
+        let file = SourceFile::new(name, src);
+        let tokens = file.tokenize()?;
+
+        let mut i = 0;
+        let mut is_template = false;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if token.is_ident("template") && tokens.get(i + 1).map_or(false, |t| t.is_punct("<")) {
+                is_template = true;
+                i = matching_close(&tokens, i + 1)
+                    .ok_or_else(|| file.error(token.line, "unclosed template parameters"))?
+                    + 1;
+                continue;
+            }
+            if token.is_punct("{") {
+                // Skip over function bodies and anything else at the top level with a body
+                i = matching_close(&tokens, i)
+                    .ok_or_else(|| file.error(token.line, "unclosed '{'"))?
+                    + 1;
+                is_template = false;
+                continue;
+            }
+            if !token.is_ident("struct") {
+                if token.is_punct(";") {
+                    is_template = false;
+                }
+                i += 1;
+                continue;
+            }
+
+            let name = match tokens.get(i + 1) {
+                Some(name) if name.kind == TokenKind::Ident => name,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            match tokens.get(i + 2) {
+                Some(t) if t.is_punct(";") => {
+                    struct_fds.insert(name.text);
+                    i += 3;
+                }
+                Some(t) if t.is_punct(":") || t.is_punct("{") => {
+                    let open = tokens[i + 2..]
+                        .iter()
+                        .position(|t| t.is_punct("{"))
+                        .map(|pos| i + 2 + pos)
+                        .ok_or_else(|| file.error(name.line, "struct has no body"))?;
+                    let close = matching_close(&tokens, open)
+                        .ok_or_else(|| file.error(name.line, "struct body is not closed"))?;
+                    // `struct Foo : public Bar`, the parent is the last token before the body
+                    let parent = if t.is_punct(":") {
+                        Some(tokens[open - 1].text)
+                    } else {
+                        None
+                    };
+
+                    if !is_template
+                        && !is_invoker(name.text)
+                        && !struct_defs.contains_key(name.text)
+                    {
+                        let body = &src[tokens[open].end()..tokens[close].offset];
+                        let body = body
+                            .strip_prefix("\r\n")
+                            .or_else(|| body.strip_prefix('\n'))
+                            .unwrap_or(body);
+                        // Leave out the indentation in front of the closing brace
+                        let body = body.trim_end_matches(|c| c == ' ' || c == '\t');
+
+                        let mut fields = Vec::new();
+                        parse_fields(&file, &tokens[open + 1..close], &mut fields)?;
+                        struct_defs.insert(
+                            name.text,
+                            StructDef {
+                                body: body.to_string(),
+                                parent,
+                                fields,
+                            },
+                        );
+                    }
+                    is_template = false;
+                    i = close + 1;
+                }
+                // Something like `struct Foo* x;`
+                _ => i += 2,
+            }
         }
+    }
+    Ok((struct_fds, struct_defs))
+}
 
-        // skip opening bracket
-        let _ = lines.next();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHOD: &str = "Foo_Bar_m0123456789ABCDEF0123456789ABCDEF01234567";
+
+    fn items(src: &str) -> Vec<Item> {
+        parse_items(&SourceFile::new("Bulk_Mod_0.cpp", src)).unwrap()
+    }
+
+    #[test]
+    fn parses_fn_decl() {
+        let decl = "IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR RuntimeObject * Foo_m0 (int32_t ___x0, const RuntimeMethod* method)";
+        let fn_decl = FnDecl::try_parse(decl).unwrap();
+        assert_eq!(fn_decl.return_ty, "RuntimeObject *");
+        assert_eq!(fn_decl.name, "Foo_m0");
+        assert_eq!(
+            fn_decl.params,
+            "(int32_t ___x0, const RuntimeMethod* method)"
+        );
+        assert!(!fn_decl.inline);
+
+        let fn_decl =
+            FnDecl::try_parse("extern \"C\" inline IL2CPP_METHOD_ATTR void Foo_inline ()").unwrap();
+        assert!(fn_decl.inline);
+        assert_eq!(fn_decl.params, "()");
+
+        assert!(FnDecl::try_parse("inline void Foo (int32_t ___x0)").is_none());
+        assert!(FnDecl::try_parse("const uint32_t Foo_MetadataUsageId;").is_none());
+    }
+
+    #[test]
+    fn splits_items() {
+        let src = format!(
+            "#include \"codegen/il2cpp-codegen.h\"
+IL2CPP_EXTERN_C_BEGIN
+IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR void {method} (RuntimeObject * __this,
+    const RuntimeMethod* method);
+IL2CPP_EXTERN_C_END
+struct Foo_t : public RuntimeObject
+{{
+    int32_t ___x;
+}};
+// System.Void Foo::Bar()
+IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR void {method} (RuntimeObject * __this, const RuntimeMethod* method) {{
+    if (true) {{ {method}(__this, method); }}
+}}
+extern \"C\" {{
+const uint32_t Foo_MetadataUsageId = 12;
+}}
+",
+            method = METHOD
+        );
+        let items = items(&src);
+        assert_eq!(items.len(), 4);
+
+        assert_eq!(items[0].name, Some(METHOD));
+        assert_eq!(items[0].line, 3);
+        assert!(!items[0].is_fn_definition());
+        assert!(items[0].head.ends_with("const RuntimeMethod* method)"));
+        assert!(items[0].fn_decl.as_ref().unwrap().extern_c);
+
+        assert_eq!(items[1].name, Some("Foo_t"));
+        assert!(items[1].fn_decl.is_none());
+        assert!(items[1].text.ends_with("};"));
+
+        assert_eq!(items[2].line, 11);
+        assert!(items[2].is_fn_definition());
+        assert!(items[2].head.ends_with("method)"));
+        assert!(items[2].text.ends_with("}\n}"));
+
+        assert_eq!(items[3].name, Some("Foo_MetadataUsageId"));
+        assert!(items[3].fn_decl.is_none());
+        assert_eq!(items[3].head, "const uint32_t Foo_MetadataUsageId");
+        assert_eq!(items[3].body().unwrap()[0].text, "12");
+    }
+
+    #[test]
+    fn finds_calls() {
+        let src = format!(
+            "IL2CPP_EXTERN_C IL2CPP_METHOD_ATTR int32_t Foo_m0 (const RuntimeMethod* method)
+{{
+    int32_t L_0 = {method}(NULL, method);
+    {method}_inline(NULL, il2cpp_codegen_get_method(method));
+    Il2CppMethodPointer ptr = (Il2CppMethodPointer)&{method};
+    return L_0;
+}}",
+            method = METHOD
+        );
+        let items = items(&src);
+        let inline = format!("{}_inline", METHOD);
+        assert_eq!(items[0].calls(false), [METHOD]);
+        assert_eq!(items[0].calls(true), [METHOD, inline.as_str()]);
+    }
+
+    #[test]
+    fn reads_array_elements() {
+        let src = "static const Il2CppRGCTXDefinition s_rgctxValues[2] = \n{\n    { (Il2CppRGCTXDataType)2, 1234 },\n    { (Il2CppRGCTXDataType)3, 0x10 },\n};";
+        let items = items(src);
+        let elements = items[0].array_elements().unwrap();
+        assert_eq!(
+            elements,
+            [
+                "{ (Il2CppRGCTXDataType)2, 1234 }",
+                "{ (Il2CppRGCTXDataType)3, 0x10 }"
+            ]
+        );
+        assert_eq!(element_ints(elements[1]).unwrap(), [3, 16]);
+        assert_eq!(element_ints("{ 5, -1, 7u }").unwrap(), [5, -1, 7]);
+        assert_eq!(
+            element_ident("(Il2CppMethodPointer)&Foo_m0"),
+            Some("Foo_m0")
+        );
+
+        let parser = SourceParser::new("Bulk_Mod_0.cpp", src);
+        let element = parser
+            .parse_array("static const Il2CppRGCTXDefinition", "s_rgctxValues")
+            .unwrap()
+            .nth(1)
+            .unwrap();
+        assert_eq!(
+            parser.error_at(element, "bad").to_string(),
+            "Bulk_Mod_0.cpp:4: bad"
+        );
+        assert!(parser
+            .find_array("static const Il2CppRGCTXDefinition", "s_rgctxIndices")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rewrites_items() {
+        let src = "#include \"a.h\"\nint32_t a;\nint32_t b;\nint32_t c[1] = { 1 };\n";
+        let items = items(src);
+        let mut rewriter = SourceRewriter::new(src);
+        rewriter.remove(&items[1]);
+        rewriter.replace(&items[2], "int32_t c[1] = { 2 };");
+        assert_eq!(
+            rewriter.finish(),
+            "#include \"a.h\"\nint32_t a;\n\nint32_t c[1] = { 2 };\n"
+        );
+    }
 
-        Ok(SourceArrIterator { lines })
+    #[test]
+    fn reports_unterminated_items() {
+        let err = parse_items(&SourceFile::new(
+            "Bulk_Mod_0.cpp",
+            "int32_t a;\nvoid Foo() {\n",
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Bulk_Mod_0.cpp:2: unclosed '{'");
     }
 }
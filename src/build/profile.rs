@@ -0,0 +1,100 @@
+use crate::manifest::{ProfileSettings, Profiles};
+use color_eyre::eyre::{bail, Result};
+
+/// Compiler settings for a build, resolved from the built-in defaults and the manifest's
+/// `[profile.release]` or `[profile.debug]` table
+#[derive(Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub opt_level: String,
+    /// Whether to generate debug info, which gets split off into a separate `.debug` file
+    pub debug: bool,
+    pub lto: bool,
+    pub defines: Vec<String>,
+}
+
+impl Profile {
+    pub fn resolve(debug: bool, profiles: &Profiles) -> Result<Self> {
+        let (mut profile, settings) = if debug {
+            let profile = Profile {
+                name: "debug",
+                opt_level: "0".to_string(),
+                debug: true,
+                lto: false,
+                defines: Vec::new(),
+            };
+            (profile, &profiles.debug)
+        } else {
+            let profile = Profile {
+                name: "release",
+                opt_level: "s".to_string(),
+                debug: false,
+                lto: false,
+                defines: Vec::new(),
+            };
+            (profile, &profiles.release)
+        };
+
+        let ProfileSettings {
+            opt_level,
+            debug,
+            lto,
+            defines,
+            il2cpp: _,
+        } = settings;
+        if let Some(opt_level) = opt_level {
+            if !matches!(opt_level.as_str(), "0" | "1" | "2" | "3" | "s" | "z") {
+                bail!(
+                    "invalid opt_level `{}` in [profile.{}], expected one of 0, 1, 2, 3, s or z",
+                    opt_level,
+                    profile.name
+                );
+            }
+            profile.opt_level = opt_level.clone();
+        }
+        if let Some(debug) = debug {
+            profile.debug = *debug;
+        }
+        if let Some(lto) = lto {
+            profile.lto = *lto;
+        }
+        profile.defines.extend(defines.iter().cloned());
+
+        Ok(profile)
+    }
+
+    pub fn compile_flags(&self) -> Vec<String> {
+        let mut flags = vec![
+            "-fpic".to_string(),
+            "-Wno-missing-declarations".to_string(),
+            "-Wno-invalid-offsetof".to_string(),
+            format!("-O{}", self.opt_level),
+        ];
+        if self.debug {
+            flags.push("-g".to_string());
+        }
+        if self.lto {
+            flags.push("-flto".to_string());
+        }
+        for define in &self.defines {
+            flags.push(format!("-D{}", define));
+        }
+        flags
+    }
+
+    pub fn link_flags(&self) -> Vec<String> {
+        let mut flags = vec![
+            "-shared".to_string(),
+            "-static-libstdc++".to_string(),
+            "-Wl,--no-undefined".to_string(),
+        ];
+        if self.lto {
+            flags.push("-flto".to_string());
+            flags.push(format!("-O{}", self.opt_level));
+        }
+        if self.debug {
+            flags.push("-g".to_string());
+        }
+        flags
+    }
+}
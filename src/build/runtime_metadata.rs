@@ -1,7 +1,9 @@
-use color_eyre::eyre::{bail, ContextCompat, Result};
+use color_eyre::eyre::{bail, Result};
+use merge_data::MetadataRevision;
 use std::collections::HashMap;
 
-use super::parser::SourceParser;
+use super::parser::{element_ident, element_ints, parse_items, Item, SourceParser};
+use super::tokenizer::{matching_close, split_list, SourceFile, Token, TokenKind, Tokenizer};
 
 #[derive(Clone, Copy, Debug)]
 pub enum Il2CppTypeEnum {
@@ -108,11 +110,20 @@ pub enum Il2CppTypeData<'src> {
     /// for PTR and SZARRAY
     Il2CppType(&'src str),
     /// for ARRAY
-    Il2CppArrayType(&'src str),
+    Il2CppArrayType(ArrayType<'src>),
     /// for GENERICINST
     Il2CppGenericClass(&'src str),
 }
 
+/// An `Il2CppArrayType`, which describes the element type and shape of a multi-dimensional array
+#[derive(Debug, Clone)]
+pub struct ArrayType<'src> {
+    pub etype: &'src str,
+    pub rank: u8,
+    pub sizes: Vec<i32>,
+    pub lower_bounds: Vec<i32>,
+}
+
 #[derive(Debug)]
 pub struct Il2CppType<'src> {
     pub data: Il2CppTypeData<'src>,
@@ -134,101 +145,147 @@ pub struct TypeDefinitionsFile<'src> {
     pub gc_name_map: HashMap<&'src str, usize>,
 }
 
+/// The type of a definition like `const Il2CppType Foo_0_0_0 = { ... };`
+fn defined_ty<'src>(item: &Item<'src>) -> Option<&'src str> {
+    let tokens = item.tokens();
+    let tokens = match tokens {
+        [first, rest @ ..] if first.is_ident("const") => rest,
+        _ => tokens,
+    };
+    match tokens {
+        [ty, name, eq, ..]
+            if ty.kind == TokenKind::Ident && name.kind == TokenKind::Ident && eq.is_punct("=") =>
+        {
+            Some(ty.text)
+        }
+        _ => None,
+    }
+}
+
+/// Parses a field holding a single integer, such as `0` or `(void*)-1`
+fn field_int(item: &Item, field: &str) -> Result<i64> {
+    match element_ints(field)?[..] {
+        [value] => Ok(value),
+        _ => Err(item.error(format!("expected an integer, found '{}'", field))),
+    }
+}
+
+/// The name referenced by a field like `(void*)&Foo_0_0_0` or `{ &GenInst_Foo, NULL }`, or `None`
+/// if it doesn't reference anything
+fn field_reference(field: &str) -> Option<&str> {
+    let tokens: Vec<_> = Tokenizer::new(field)
+        .map_while(|token| token.ok())
+        .collect();
+    tokens
+        .windows(2)
+        .find(|pair| pair[0].is_punct("&") && pair[1].kind == TokenKind::Ident)
+        .map(|pair| pair[1].text)
+}
+
+fn parse_type<'src>(
+    item: &Item<'src>,
+    array_types: &HashMap<&str, ArrayType<'src>>,
+) -> Result<Il2CppType<'src>> {
+    let fields = item.array_elements()?;
+    let (data, attrs, ty, byref, pinned) = match fields[..] {
+        [data, attrs, ty, _num_mods, byref, pinned] => (data, attrs, ty, byref, pinned),
+        _ => return Err(item.error("expected 6 fields in Il2CppType")),
+    };
+    let attrs = u16::try_from(field_int(item, attrs)?)
+        .map_err(|_| item.error(format!("invalid attributes {}", attrs)))?;
+    let ty = Il2CppTypeEnum::from_name(ty).map_err(|err| item.error(err))?;
+    let byref = field_int(item, byref)? != 0;
+    let pinned = field_int(item, pinned)? != 0;
+
+    let reference = || field_reference(data).ok_or_else(|| item.error("expected a type reference"));
+    let index = || {
+        usize::try_from(field_int(item, data)?)
+            .map_err(|_| item.error(format!("invalid index {}", data)))
+    };
+    let data = match ty {
+        Il2CppTypeEnum::Var | Il2CppTypeEnum::Mvar => Il2CppTypeData::GenericParamIdx(index()?),
+        Il2CppTypeEnum::Ptr | Il2CppTypeEnum::Szarray => Il2CppTypeData::Il2CppType(reference()?),
+        Il2CppTypeEnum::Array => {
+            let name = reference()?;
+            let array_type = array_types
+                .get(name)
+                .ok_or_else(|| item.error(format!("could not find array type {}", name)))?;
+            Il2CppTypeData::Il2CppArrayType(array_type.clone())
+        }
+        Il2CppTypeEnum::Genericinst => Il2CppTypeData::Il2CppGenericClass(reference()?),
+        _ => Il2CppTypeData::TypeDefIdx(index()?),
+    };
+
+    Ok(Il2CppType {
+        data,
+        attrs,
+        ty,
+        byref,
+        pinned,
+    })
+}
+
+fn parse_generic_class<'src>(item: &Item<'src>) -> Result<GenericClass<'src>> {
+    match item.array_elements()?[..] {
+        [ty_def_idx, class_inst, ..] => Ok(GenericClass {
+            ty_def_idx: usize::try_from(field_int(item, ty_def_idx)?).ok(),
+            class_inst: field_reference(class_inst),
+        }),
+        _ => Err(item.error("expected a type definition and instance in Il2CppGenericClass")),
+    }
+}
+
 /// Parse Il2CppTypeDefinitions.c and Il2CppGenericClassTable.c
 pub fn parse<'src>(src: &'src str, gct_src: &'src str) -> Result<TypeDefinitionsFile<'src>> {
+    let array_types = parse_array_types(src)?;
     let mut types = HashMap::new();
     let mut generic_classes = HashMap::new();
-    for line in src.lines() {
-        if line.starts_with("const Il2CppType ") {
-            let words: Vec<&str> = line.split_whitespace().collect();
-            let name = words[2];
-            let data = words[5].trim_end_matches(',').trim_start_matches("(void*)");
-            let attrs: u16 = words[6].trim_end_matches(',').parse()?;
-            let ty = Il2CppTypeEnum::from_name(words[7].trim_end_matches(','))?;
-            let byref = words[9].trim_end_matches(',').parse::<u8>()? != 0;
-            let pinned = words[10].parse::<u8>()? != 0;
-
-            let data = match ty {
-                Il2CppTypeEnum::Var | Il2CppTypeEnum::Mvar => {
-                    Il2CppTypeData::GenericParamIdx(data.parse()?)
-                }
-                Il2CppTypeEnum::Ptr | Il2CppTypeEnum::Szarray => {
-                    Il2CppTypeData::Il2CppType(data.trim_start_matches('&'))
-                }
-                Il2CppTypeEnum::Array => {
-                    Il2CppTypeData::Il2CppArrayType(data.trim_start_matches('&'))
-                }
-                Il2CppTypeEnum::Genericinst => {
-                    Il2CppTypeData::Il2CppGenericClass(data.trim_start_matches('&'))
-                }
-                _ => Il2CppTypeData::TypeDefIdx(data.parse()?),
-            };
-
-            let ty = Il2CppType {
-                data,
-                attrs,
-                ty,
-                byref,
-                pinned,
-            };
-            types.insert(name, ty);
-        } else if line.starts_with("Il2CppGenericClass ") {
-            let words: Vec<&str> = line.split_whitespace().collect();
-            let name = words[1];
-            let ty_def_idx = words[4].trim_end_matches(',').parse::<isize>()?;
-            let class_inst = words[6].trim_start_matches('&').trim_end_matches(',');
-            generic_classes.insert(
-                name,
-                GenericClass {
-                    ty_def_idx: if ty_def_idx != -1 {
-                        Some(ty_def_idx as usize)
-                    } else {
-                        None
-                    },
-                    class_inst: if class_inst == "NULL" {
-                        None
-                    } else {
-                        Some(class_inst)
-                    },
-                },
-            );
+    let items = parse_items(&SourceFile::new("Il2CppTypeDefinitions.c", src))?;
+    let gct_items = parse_items(&SourceFile::new("Il2CppGenericClassTable.c", gct_src))?;
+    for item in items.iter().chain(&gct_items) {
+        let name = match item.name {
+            Some(name) => name,
+            None => continue,
+        };
+        match defined_ty(item) {
+            Some("Il2CppType") => {
+                types.insert(name, parse_type(item, &array_types)?);
+            }
+            Some("Il2CppGenericClass") => {
+                generic_classes.insert(name, parse_generic_class(item)?);
+            }
+            _ => {}
         }
     }
 
     let mut ty_name_map = HashMap::new();
     let mut types_arr = Vec::new();
-    let arr_start = src
-        .find("const Il2CppType* const  g_Il2CppTypeTable")
-        .context("could not find g_Il2CppTypeTable")?;
-    for (i, line) in src[arr_start..].lines().skip(3).enumerate() {
-        if line.starts_with('}') {
-            break;
-        }
-        let name = line.trim().trim_start_matches('&').trim_end_matches(',');
+    let parser = SourceParser::new("Il2CppTypeDefinitions.c", src);
+    for (i, element) in parser
+        .parse_array("const Il2CppType* const", "g_Il2CppTypeTable")?
+        .enumerate()
+    {
+        let ty = element_ident(element).and_then(|name| Some((name, types.remove(name)?)));
+        let (name, ty) =
+            ty.ok_or_else(|| parser.error_at(element, "type table contained non-existant type"))?;
         ty_name_map.insert(name, i);
-        types_arr.push(
-            types
-                .remove(name)
-                .context("type table contained non-existant type")?,
-        );
+        types_arr.push(ty);
     }
 
     let mut gc_name_map = HashMap::new();
     let mut gc_arr = Vec::new();
-    let gc_arr_start = gct_src
-        .find("Il2CppGenericClass* const s_Il2CppGenericTypes")
-        .context("could not find s_Il2CppGenericTypes")?;
-    for (i, line) in gct_src[gc_arr_start..].lines().skip(3).enumerate() {
-        if line.starts_with('}') {
-            break;
-        }
-        let name = line.trim().trim_start_matches('&').trim_end_matches(',');
+    let parser = SourceParser::new("Il2CppGenericClassTable.c", gct_src);
+    for (i, element) in parser
+        .parse_array("Il2CppGenericClass* const", "s_Il2CppGenericTypes")?
+        .enumerate()
+    {
+        let gc =
+            element_ident(element).and_then(|name| Some((name, generic_classes.remove(name)?)));
+        let (name, gc) = gc.ok_or_else(|| {
+            parser.error_at(element, "gc table contained non-existant generic class")
+        })?;
         gc_name_map.insert(name, i);
-        gc_arr.push(
-            generic_classes
-                .remove(name)
-                .context("gc table contained non-existant generic class")?,
-        );
+        gc_arr.push(gc);
     }
 
     Ok(TypeDefinitionsFile {
@@ -240,54 +297,147 @@ pub fn parse<'src>(src: &'src str, gct_src: &'src str) -> Result<TypeDefinitions
     })
 }
 
+/// Parses an integer literal, which may be negative
+fn parse_int(file: &SourceFile, tokens: &[Token]) -> Result<i32> {
+    let (negative, number) = match tokens {
+        [minus, number] if minus.is_punct("-") => (true, number),
+        [number] => (false, number),
+        _ => return Err(file.error(tokens[0].line, "expected an integer")),
+    };
+    let value: i32 = number
+        .text
+        .parse()
+        .map_err(|_| file.error(number.line, format!("invalid integer {}", number.text)))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parses the `Il2CppArrayType` definitions in Il2CppTypeDefinitions.c, which look like
+/// `Il2CppArrayType Foo_ArrayType = { &Single_t_0_0_0, 2, 0, 0, NULL, NULL };`. The sizes and
+/// lower bounds point to `int32_t name[] = { ... };` arrays when present.
+fn parse_array_types(src: &str) -> Result<HashMap<&str, ArrayType>> {
+    let file = SourceFile::new("Il2CppTypeDefinitions.c", src);
+    let tokens = file.tokenize()?;
+
+    let puncts_at = |start: usize, puncts: &[&str]| {
+        puncts
+            .iter()
+            .enumerate()
+            .all(|(i, punct)| tokens.get(start + i).map_or(false, |t| t.is_punct(punct)))
+    };
+
+    let mut int_arrays = HashMap::new();
+    let mut array_defs = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == TokenKind::Ident && puncts_at(i + 1, &["[", "]", "=", "{"]) {
+            let open = i + 4;
+            let close = matching_close(&tokens, open)
+                .ok_or_else(|| file.error(token.line, "array body is not closed"))?;
+            let values = split_list(&tokens[open + 1..close])
+                .into_iter()
+                .map(|value| parse_int(&file, value))
+                .collect::<Result<Vec<_>>>();
+            // Plenty of other tables look like this, only int arrays matter here
+            if let Ok(values) = values {
+                int_arrays.insert(token.text, values);
+            }
+        } else if token.is_ident("Il2CppArrayType")
+            && tokens
+                .get(i + 1)
+                .map_or(false, |t| t.kind == TokenKind::Ident)
+        {
+            let name = &tokens[i + 1];
+            let open = i + 3;
+            if !puncts_at(i + 2, &["=", "{"]) {
+                continue;
+            }
+            let close = matching_close(&tokens, open)
+                .ok_or_else(|| file.error(name.line, "array type body is not closed"))?;
+            let fields = split_list(&tokens[open + 1..close]);
+            let (etype, rank, sizes, lower_bounds) = match fields.as_slice() {
+                [etype, rank, _num_sizes, _num_lower_bounds, sizes, lower_bounds] => {
+                    (etype, rank, sizes, lower_bounds)
+                }
+                _ => return Err(file.error(name.line, "expected 6 fields in Il2CppArrayType")),
+            };
+            // The element type may be behind a cast, so take the name that gets referenced
+            let etype = match etype.iter().rev().find(|t| t.kind == TokenKind::Ident) {
+                Some(ident) if etype.iter().any(|t| t.is_punct("&")) => ident.text,
+                _ => return Err(file.error(name.line, "expected a type reference")),
+            };
+            let rank = parse_int(&file, rank)?;
+            let rank = u8::try_from(rank)
+                .map_err(|_| file.error(name.line, format!("invalid array rank {}", rank)))?;
+            array_defs.push((name, etype, rank, *sizes, *lower_bounds));
+        }
+    }
+
+    let int_array = |tokens: &[Token]| -> Result<Vec<i32>> {
+        match tokens.iter().rev().find(|t| t.kind == TokenKind::Ident) {
+            Some(null) if null.is_ident("NULL") => Ok(Vec::new()),
+            Some(name) => int_arrays
+                .get(name.text)
+                .cloned()
+                .ok_or_else(|| file.error(name.line, format!("could not find {}", name.text))),
+            None => Err(file.error(tokens[0].line, "expected NULL or an array")),
+        }
+    };
+    let mut array_types = HashMap::new();
+    for (name, etype, rank, sizes, lower_bounds) in array_defs {
+        let array_type = ArrayType {
+            etype,
+            rank,
+            sizes: int_array(sizes)?,
+            lower_bounds: int_array(lower_bounds)?,
+        };
+        array_types.insert(name.text, array_type);
+    }
+    Ok(array_types)
+}
+
 pub struct SourceGenericInst<'src> {
     pub types: Vec<&'src str>,
 }
 
 pub fn parse_inst_defs(src: &str) -> Result<(Vec<SourceGenericInst>, HashMap<&str, usize>)> {
-    let parser = SourceParser::new(src);
+    let file = SourceFile::new("Il2CppGenericInstDefinitions.c", src);
+    let parser = SourceParser::new(file.name, src);
+    let table =
+        match parser.find_array("const Il2CppGenericInst* const", "g_Il2CppGenericInstTable")? {
+            Some(table) => table,
+            None => return Ok(Default::default()),
+        };
 
     let mut insts = HashMap::new();
-    let insts_start = match src.find("static const Il2CppType* ") {
-        Some(insts_start) => insts_start,
-        None => return Ok(Default::default()),
-    };
-    let lines = src[insts_start..].lines().step_by(3);
-    for line in lines {
-        if !line.starts_with("static const Il2CppType*") {
-            break;
-        }
-
-        let words = line.split_whitespace().collect::<Vec<_>>();
-        let name = words[3]
-            .strip_suffix("_Types[]")
-            .context("generic inst def has wrong name suffix")?;
-        let types = words[6..words.len() - 1]
-            .iter()
-            .map(|item| {
-                item.trim_end_matches(',')
-                    .trim_end_matches(')')
-                    .trim_start_matches("(&")
+    for item in parse_items(&file)? {
+        let name = match item.name.and_then(|name| name.strip_suffix("_Types")) {
+            Some(name) if item.body().is_some() => name,
+            _ => continue,
+        };
+        let types = item
+            .array_elements()?
+            .into_iter()
+            .map(|element| {
+                field_reference(element).ok_or_else(|| {
+                    item.error(format!("expected a type reference, found {}", element))
+                })
             })
-            .collect();
+            .collect::<Result<_>>()?;
         insts.insert(name, SourceGenericInst { types });
     }
 
     let mut name_map = HashMap::new();
     let mut arr = Vec::new();
-    parser
-        .parse_array("const Il2CppGenericInst* const", "g_Il2CppGenericInstTable")?
-        .enumerate()
-        .for_each(|(i, str)| {
-            let name = str.trim_start_matches('&');
-            name_map.insert(name, i);
-            arr.push(
-                insts
-                    .remove(name)
-                    .context("gc table contained non-existant generic class")
-                    .unwrap(),
-            );
-        });
+    for (i, element) in table.enumerate() {
+        let inst = field_reference(element).and_then(|name| Some((name, insts.remove(name)?)));
+        let (name, inst) = inst.ok_or_else(|| {
+            parser.error_at(
+                element,
+                "generic inst table contained non-existant generic inst",
+            )
+        })?;
+        name_map.insert(name, i);
+        arr.push(inst);
+    }
 
     Ok((arr, name_map))
 }
@@ -299,19 +449,17 @@ pub struct GenericMethodSpec {
 }
 
 pub fn parse_generic_method_defs(src: &str) -> Result<Vec<GenericMethodSpec>> {
+    let parser = SourceParser::new("Il2CppGenericMethodDefinitions.c", src);
     let mut specs = Vec::new();
-    let arr_start = src
-        .find("const Il2CppMethodSpec g_Il2CppMethodSpecTable")
-        .context("could not find g_Il2CppMethodSpecTable")?;
-    for line in src[arr_start..].lines().skip(3) {
-        if line.starts_with('}') {
-            break;
-        }
-        let words = line.split_whitespace().collect::<Vec<_>>();
-        let class_inst = words[2].trim_end_matches(',').parse::<isize>()?;
-        let method_isnt = words[3].trim_end_matches(',').parse::<isize>()?;
+    for element in parser.parse_array("const Il2CppMethodSpec", "g_Il2CppMethodSpecTable")? {
+        let (method_def, class_inst, method_isnt) = match element_ints(element)?[..] {
+            [method_def, class_inst, method_isnt] if method_def >= 0 => {
+                (method_def, class_inst, method_isnt)
+            }
+            _ => return Err(parser.error_at(element, "malformed method spec")),
+        };
         specs.push(GenericMethodSpec {
-            method_def: words[1].trim_end_matches(',').parse()?,
+            method_def: method_def as usize,
             class_inst: class_inst.try_into().ok(),
             method_isnt: method_isnt.try_into().ok(),
         });
@@ -328,25 +476,43 @@ pub struct SrcGenericMethodFuncs {
     pub adjustor_thunk_idx: Option<usize>,
 }
 
-pub fn parse_generic_method_table(src: &str) -> Result<Vec<SrcGenericMethodFuncs>> {
+pub fn parse_generic_method_table(
+    src: &str,
+    revision: MetadataRevision,
+) -> Result<Vec<SrcGenericMethodFuncs>> {
+    let parser = SourceParser::new("Il2CppGenericMethodTable.c", src);
     let mut methods = Vec::new();
-    let arr_start = src
-        .find("const Il2CppGenericMethodFunctionsDefinitions s_Il2CppGenericMethodFunctions")
-        .context("could not find s_Il2CppGenericMethodFunctions")?;
-    for line in src[arr_start..].lines().skip(3) {
-        if line.starts_with('}') {
-            break;
-        }
-        let words = line.split_whitespace().collect::<Vec<_>>();
-        let generic_method_idx = words[1].trim_end_matches(',').parse()?;
-        let method_idx = words[2].split('/').next().unwrap().parse()?;
-        let invoker_idx = words[3].split('/').next().unwrap().parse()?;
-        let adjustor_thunk_idx = words[4].trim_end_matches("},").parse::<isize>()?;
+    for element in parser.parse_array(
+        "const Il2CppGenericMethodFunctionsDefinitions",
+        "s_Il2CppGenericMethodFunctions",
+    )? {
+        let ints = element_ints(element)?;
+        // Adjustor thunk indices were only added in 24.5
+        let (indices, adjustor_thunk_idx) =
+            match (&ints[..], revision.has_generic_adjustor_thunks()) {
+                ([indices @ .., adjustor_thunk_idx], true) => {
+                    (indices, (*adjustor_thunk_idx).try_into().ok())
+                }
+                (indices, false) => (indices, None),
+                _ => return Err(parser.error_at(element, "malformed generic method functions")),
+            };
+        let (generic_method_idx, method_idx, invoker_idx) = match *indices {
+            [generic_method_idx, method_idx, invoker_idx]
+                if generic_method_idx >= 0 && method_idx >= 0 && invoker_idx >= 0 =>
+            {
+                (
+                    generic_method_idx as usize,
+                    method_idx as usize,
+                    invoker_idx as usize,
+                )
+            }
+            _ => return Err(parser.error_at(element, "malformed generic method functions")),
+        };
         methods.push(SrcGenericMethodFuncs {
             generic_method_idx,
             method_idx,
             invoker_idx,
-            adjustor_thunk_idx: adjustor_thunk_idx.try_into().ok(),
+            adjustor_thunk_idx,
         });
     }
 
@@ -0,0 +1,93 @@
+use crate::manifest::Scripts;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    PreBuild,
+    PostIl2Cpp,
+    PreLink,
+    PostBuild,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::PreBuild => "pre_build",
+            Stage::PostIl2Cpp => "post_il2cpp",
+            Stage::PreLink => "pre_link",
+            Stage::PostBuild => "post_build",
+        }
+    }
+}
+
+/// Runs the manifest's `[scripts]` with environment variables describing the build
+pub struct ScriptRunner<'a> {
+    scripts: &'a Scripts,
+    env: Vec<(&'static str, String)>,
+}
+
+impl<'a> ScriptRunner<'a> {
+    pub fn new(
+        scripts: &'a Scripts,
+        mod_id: &str,
+        profile: &str,
+        cpp_path: &Path,
+        transformed_path: &Path,
+        out_path: &Path,
+    ) -> Result<Self> {
+        let dir = env::current_dir()?;
+        let abs_path = |path: &Path| dir.join(path).display().to_string();
+        let env = vec![
+            ("QMERGE_MOD_ID", mod_id.to_string()),
+            ("QMERGE_PROFILE", profile.to_string()),
+            ("QMERGE_CPP_DIR", abs_path(cpp_path)),
+            ("QMERGE_TRANSFORMED_DIR", abs_path(transformed_path)),
+            ("QMERGE_OUT_DIR", abs_path(out_path)),
+        ];
+        Ok(Self { scripts, env })
+    }
+
+    /// Runs the script for `stage` if there is one, failing if it does
+    pub fn run(&self, stage: Stage) -> Result<()> {
+        let script = match stage {
+            Stage::PreBuild => &self.scripts.pre_build,
+            Stage::PostIl2Cpp => &self.scripts.post_il2cpp,
+            Stage::PreLink => &self.scripts.pre_link,
+            Stage::PostBuild => &self.scripts.post_build,
+        };
+        let script = match script {
+            Some(script) => script,
+            None => return Ok(()),
+        };
+
+        println!("Running {} script", stage.name());
+        let mut command = shell_command(script);
+        command.envs(self.env.iter().cloned());
+        debug!("{} command: {:?}", stage.name(), &command);
+        let status = command
+            .status()
+            .with_context(|| format!("failed to run {} script", stage.name()))?;
+        if !status.success() {
+            bail!("{} script exited with {}", stage.name(), status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(script: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(script);
+    command
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell_command(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script);
+    command
+}
@@ -0,0 +1,180 @@
+use super::data::{get_str, offset_len};
+use super::libil2cpp;
+use crate::adb;
+use crate::config::{AppConfig, Config};
+use crate::manifest::Mod;
+use color_eyre::eyre::{Context, Result};
+use il2cpp_metadata_raw::Metadata;
+use merge_data::MetadataRevision;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+/// The game's files are kept per app version so they're only pulled from the device once
+const GAME_FILES_PATH: &str = "./build/game";
+
+/// Identifies a method across the game's and the mod's metadata, which index them differently.
+/// Overloads with the same number of parameters share a key.
+#[derive(Hash, PartialEq, Eq)]
+struct MethodKey<'a> {
+    image: &'a str,
+    /// The namespace and names of the declaring types, outermost first
+    ty: Vec<&'a str>,
+    name: &'a str,
+    num_gen_params: u32,
+    num_params: u32,
+}
+
+/// The key of each method definition in `metadata`
+fn method_keys<'a>(metadata: &'a Metadata) -> Result<Vec<Option<MethodKey<'a>>>> {
+    let mut decl_tys = HashMap::new();
+    for (i, ty_def) in metadata.type_definitions.iter().enumerate() {
+        let range = offset_len(ty_def.nested_types_start, ty_def.nested_type_count as u32);
+        for &nested in &metadata.nested_types[range] {
+            decl_tys.insert(nested as usize, i);
+        }
+    }
+
+    let mut keys: Vec<_> = metadata.methods.iter().map(|_| None).collect();
+    for image in &metadata.images {
+        let image_name = get_str(metadata.string, image.name_index as usize)?;
+        for ty_def_idx in offset_len(image.type_start, image.type_count) {
+            let mut ty = Vec::new();
+            let mut idx = Some(ty_def_idx);
+            while let Some(i) = idx {
+                let ty_def = &metadata.type_definitions[i];
+                ty.push(get_str(metadata.string, ty_def.name_index as usize)?);
+                idx = decl_tys.get(&i).copied();
+                if idx.is_none() {
+                    ty.push(get_str(metadata.string, ty_def.namespace_index as usize)?);
+                }
+            }
+            ty.reverse();
+
+            let ty_def = &metadata.type_definitions[ty_def_idx];
+            for method_idx in offset_len(ty_def.method_start, ty_def.method_count as u32) {
+                let method = &metadata.methods[method_idx];
+                let num_gen_params = if method.generic_container_index != u32::MAX {
+                    metadata.generic_containers[method.generic_container_index as usize].type_argc
+                } else {
+                    0
+                };
+                keys[method_idx] = Some(MethodKey {
+                    image: image_name,
+                    ty: ty.clone(),
+                    name: get_str(metadata.string, method.name_index as usize)?,
+                    num_gen_params,
+                    num_params: method.parameter_count as u32,
+                });
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Marks the methods in `metadata` that the game has generic code for. Where overloads share a
+/// key, the game has to have code for all of them.
+fn match_game_methods(
+    metadata: &Metadata,
+    game_metadata: &Metadata,
+    game_methods: &HashSet<u32>,
+) -> Result<Vec<bool>> {
+    let mut game_keys: HashMap<MethodKey, bool> = HashMap::new();
+    for (idx, key) in method_keys(game_metadata)?.into_iter().enumerate() {
+        if let Some(key) = key {
+            let compiled = game_methods.contains(&(idx as u32));
+            *game_keys.entry(key).or_insert(true) &= compiled;
+        }
+    }
+
+    let methods = method_keys(metadata)?
+        .iter()
+        .map(|key| match key {
+            Some(key) => game_keys.get(key).copied().unwrap_or(false),
+            None => false,
+        })
+        .collect();
+    Ok(methods)
+}
+
+/// Reads a file of the game from `dir`, pulling it from the device first if it isn't there
+fn read_game_file(
+    dir: &Path,
+    name: &str,
+    pull: impl FnOnce(&str) -> Result<()>,
+) -> Result<Vec<u8>> {
+    let path = dir.join(name);
+    if !path.exists() {
+        fs::create_dir_all(dir)?;
+        pull(path.to_str().unwrap()).with_context(|| {
+            format!(
+                "failed to pull the game's {}, copy it to {} or set the app's `detect_shims` to false",
+                name,
+                path.display()
+            )
+        })?;
+    }
+    fs::read(&path).with_context(|| format!("could not read {}", path.display()))
+}
+
+/// Finds the generic methods that have to call the game's implementation instead of being
+/// compiled into the mod, by their index in the mod's `metadata`. These are the ones that the
+/// game's `libil2cpp.so` has code for, because the game's assemblies that the mod is built
+/// against may be dummies without any method bodies.
+///
+/// The methods of images in the app's `shims` always are shims, the ones in its `no_shims` never
+/// are. Without `detect_shims`, only `shims` are.
+pub fn find_shims(
+    config: &mut Config,
+    mod_config: &Mod,
+    metadata: &Metadata,
+    app: &AppConfig,
+    metadata_revision: MetadataRevision,
+) -> Result<Vec<bool>> {
+    let mut shims = if app.detect_shims {
+        let dir = Path::new(GAME_FILES_PATH).join(&mod_config.app_version);
+        let game_metadata_data = read_game_file(&dir, "global-metadata.dat", |to| {
+            adb::pull_metadata(config, &mod_config.app, to)
+        })?;
+        let libil2cpp_data = read_game_file(&dir, "libil2cpp.so", |to| {
+            adb::pull_libil2cpp(config, &mod_config.app, to)
+        })?;
+
+        let (game_metadata_data, _) =
+            crate::metadata::read(&game_metadata_data).context("failed to read game metadata")?;
+        let game_metadata = il2cpp_metadata_raw::deserialize(&game_metadata_data)
+            .context("failed to deserialize game metadata")?;
+        let game_methods = libil2cpp::compiled_generic_methods(
+            &libil2cpp_data,
+            game_metadata.type_definitions.len(),
+            metadata_revision,
+        )
+        .context("failed to read the game's libil2cpp.so")?;
+        debug!(
+            "The game has generic code for {} method definitions",
+            game_methods.len()
+        );
+        match_game_methods(metadata, &game_metadata, &game_methods)?
+    } else {
+        vec![false; metadata.methods.len()]
+    };
+
+    for image in &metadata.images {
+        let name = get_str(metadata.string, image.name_index as usize)?;
+        let is_shim = if app.no_shims.contains(name) {
+            false
+        } else if app.shims.contains(name) {
+            true
+        } else {
+            continue;
+        };
+        for ty_def in &metadata.type_definitions[offset_len(image.type_start, image.type_count)] {
+            let method_range = offset_len(ty_def.method_start, ty_def.method_count as u32);
+            shims[method_range]
+                .iter_mut()
+                .for_each(|shim| *shim = is_shim);
+        }
+    }
+    Ok(shims)
+}
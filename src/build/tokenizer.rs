@@ -0,0 +1,363 @@
+//! A tokenizer for the C++ that il2cpp generates. It only distinguishes as much as the builder
+//! needs and keeps every token as a slice of the source, so that parsed declarations can be
+//! written back out verbatim.
+
+use color_eyre::eyre::{eyre, Report};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident,
+    Number,
+    /// A string or character literal, including its quotes
+    Literal,
+    /// A single punctuation character, or `::`
+    Punct,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'src> {
+    pub kind: TokenKind,
+    pub text: &'src str,
+    /// Byte offset of the token in the source
+    pub offset: usize,
+    /// 1-based line number of the token
+    pub line: usize,
+}
+
+impl<'src> Token<'src> {
+    pub fn is_ident(&self, ident: &str) -> bool {
+        self.kind == TokenKind::Ident && self.text == ident
+    }
+
+    pub fn is_punct(&self, punct: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == punct
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.text.len()
+    }
+}
+
+/// Identifies a source file in error messages
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFile<'src> {
+    pub name: &'src str,
+    pub src: &'src str,
+}
+
+impl<'src> SourceFile<'src> {
+    pub fn new(name: &'src str, src: &'src str) -> Self {
+        Self { name, src }
+    }
+
+    pub fn error(&self, line: usize, msg: impl std::fmt::Display) -> Report {
+        eyre!("{}:{}: {}", self.name, line, msg)
+    }
+
+    pub fn tokenize(&self) -> Result<Vec<Token<'src>>, Report> {
+        Tokenizer::new(self.src)
+            .collect::<Result<_, _>>()
+            .map_err(|(line, msg)| self.error(line, msg))
+    }
+}
+
+/// Splits source into tokens, skipping whitespace, comments and preprocessor directives
+pub struct Tokenizer<'src> {
+    src: &'src str,
+    pos: usize,
+    line: usize,
+    /// Whether only whitespace has been seen since the start of the line
+    line_start: bool,
+}
+
+impl<'src> Tokenizer<'src> {
+    pub fn new(src: &'src str) -> Self {
+        let pos = if src.starts_with('\u{FEFF}') {
+            '\u{FEFF}'.len_utf8()
+        } else {
+            0
+        };
+        Self {
+            src,
+            pos,
+            line: 1,
+            line_start: true,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = true;
+        }
+        Some(c)
+    }
+
+    fn skip_line(&mut self) {
+        // Preprocessor directives may be continued with a trailing backslash
+        let mut escaped = false;
+        while let Some(c) = self.peek() {
+            if c == '\n' && !escaped {
+                return;
+            }
+            escaped = c == '\\' || (escaped && c == '\r');
+            self.bump();
+        }
+    }
+
+    /// Skips whitespace, comments and preprocessor directives
+    fn skip_trivia(&mut self) -> Result<(), (usize, String)> {
+        loop {
+            let rest = &self.src[self.pos..];
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some('#') if self.line_start => self.skip_line(),
+                Some('/') if rest.starts_with("//") => self.skip_line(),
+                Some('/') if rest.starts_with("/*") => {
+                    let start_line = self.line;
+                    let len = rest[2..]
+                        .find("*/")
+                        .ok_or((start_line, "unterminated block comment".to_string()))?;
+                    let end = self.pos + 2 + len + 2;
+                    while self.pos < end {
+                        self.bump();
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn literal(&mut self, quote: char) -> Result<(), (usize, String)> {
+        let start_line = self.line;
+        self.bump();
+        loop {
+            match self.bump() {
+                Some('\\') => {
+                    self.bump();
+                }
+                Some('\n') | None => {
+                    return Err((start_line, "unterminated literal".to_string()));
+                }
+                Some(c) if c == quote => return Ok(()),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+impl<'src> Iterator for Tokenizer<'src> {
+    type Item = Result<Token<'src>, (usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.skip_trivia() {
+            // Make sure we don't report the same error forever
+            self.pos = self.src.len();
+            return Some(Err(err));
+        }
+
+        let offset = self.pos;
+        let line = self.line;
+        let c = self.peek()?;
+        self.line_start = false;
+
+        let kind = if c.is_ascii_alphabetic() || c == '_' {
+            while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_') {
+                self.bump();
+            }
+            // Encoding prefixes like `L"..."` belong to the literal
+            match (&self.src[offset..self.pos], self.peek()) {
+                ("L" | "u" | "U" | "u8", Some(quote @ ('"' | '\''))) => {
+                    if let Err(err) = self.literal(quote) {
+                        return Some(Err(err));
+                    }
+                    TokenKind::Literal
+                }
+                _ => TokenKind::Ident,
+            }
+        } else if c.is_ascii_digit() {
+            // Good enough for il2cpp's literals, which include suffixes, hex and floats
+            while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '.') {
+                self.bump();
+            }
+            TokenKind::Number
+        } else if c == '"' || c == '\'' {
+            if let Err(err) = self.literal(c) {
+                return Some(Err(err));
+            }
+            TokenKind::Literal
+        } else {
+            self.bump();
+            if c == ':' && self.peek() == Some(':') {
+                self.bump();
+            }
+            TokenKind::Punct
+        };
+
+        Some(Ok(Token {
+            kind,
+            text: &self.src[offset..self.pos],
+            offset,
+            line,
+        }))
+    }
+}
+
+/// Returns the index of the token that closes the group opened at `open`, e.g. the matching `}`
+/// for a `{`
+pub fn matching_close(tokens: &[Token], open: usize) -> Option<usize> {
+    let (open_text, close_text) = match tokens[open].text {
+        "(" => ("(", ")"),
+        "[" => ("[", "]"),
+        "{" => ("{", "}"),
+        "<" => ("<", ">"),
+        _ => return None,
+    };
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.is_punct(open_text) {
+            depth += 1;
+        } else if token.is_punct(close_text) {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// Splits the tokens of a brace initializer or argument list on the commas that aren't nested in
+/// any brackets, leaving out empty elements such as the one after a trailing comma
+pub fn split_list<'a, 'src>(tokens: &'a [Token<'src>]) -> Vec<&'a [Token<'src>]> {
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind != TokenKind::Punct {
+            continue;
+        }
+        match token.text {
+            "(" | "{" | "[" => depth += 1,
+            ")" | "}" | "]" => depth -= 1,
+            "," if depth == 0 => {
+                if start < i {
+                    elements.push(&tokens[start..i]);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        elements.push(&tokens[start..]);
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(src: &str) -> Vec<&str> {
+        Tokenizer::new(src)
+            .map(|token| token.unwrap().text)
+            .collect()
+    }
+
+    #[test]
+    fn skips_comments_and_directives() {
+        let src = "// System.Void Foo::Bar()\n#include \"foo.h\"\n#define A \\\n    B\nint32_t /* a\n*/ x; // trailing";
+        assert_eq!(texts(src), ["int32_t", "x", ";"]);
+
+        let tokens: Vec<_> = Tokenizer::new(src).map(Result::unwrap).collect();
+        assert_eq!(tokens[0].line, 5);
+        assert_eq!(tokens[1].line, 6);
+    }
+
+    #[test]
+    fn keeps_literals_whole() {
+        let src = r#"f("a, b // not a comment", '\'', L"wide\"", '{');"#;
+        assert_eq!(
+            texts(src),
+            [
+                "f",
+                "(",
+                r#""a, b // not a comment""#,
+                ",",
+                r"'\''",
+                ",",
+                r#"L"wide\"""#,
+                ",",
+                "'{'",
+                ")",
+                ";"
+            ]
+        );
+        let kinds: Vec<_> = Tokenizer::new(src)
+            .map(|token| token.unwrap().kind)
+            .collect();
+        assert_eq!(kinds[2], TokenKind::Literal);
+        assert_eq!(kinds[8], TokenKind::Literal);
+    }
+
+    #[test]
+    fn reports_unterminated_literals() {
+        let mut tokenizer = Tokenizer::new("x;\n\"abc\ny");
+        assert!(tokenizer.next().unwrap().is_ok());
+        assert!(tokenizer.next().unwrap().is_ok());
+        assert_eq!(tokenizer.next().unwrap().unwrap_err().0, 2);
+    }
+
+    #[test]
+    fn reports_errors_with_file_and_line() {
+        let file = SourceFile::new("Bulk_Mod_0.cpp", "int x;\n/* never closed");
+        let err = file.tokenize().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bulk_Mod_0.cpp:2: unterminated block comment"
+        );
+    }
+
+    #[test]
+    fn groups_across_lines() {
+        let src = "void Foo(\n    int32_t a,\n    Bar_t* b) { return; }";
+        let tokens = SourceFile::new("test.cpp", src).tokenize().unwrap();
+        let open = tokens.iter().position(|t| t.is_punct("(")).unwrap();
+        let close = matching_close(&tokens, open).unwrap();
+        assert_eq!(tokens[close].line, 3);
+        assert!(tokens[close + 1].is_punct("{"));
+        assert_eq!(tokens[close + 1].line, 3);
+
+        let params: Vec<Vec<_>> = split_list(&tokens[open + 1..close])
+            .into_iter()
+            .map(|param| param.iter().map(|t| t.text).collect())
+            .collect();
+        assert_eq!(params, [vec!["int32_t", "a"], vec!["Bar_t", "*", "b"]]);
+    }
+
+    #[test]
+    fn splits_nested_lists() {
+        let src = "{ 0x02000004, { 0, -1 } }, { 1, { 2, 3 } },";
+        let tokens = SourceFile::new("test.c", src).tokenize().unwrap();
+        let elements = split_list(&tokens);
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0].len(), 10);
+        assert_eq!(tokens[0].kind, TokenKind::Punct);
+        assert_eq!(tokens[1].kind, TokenKind::Number);
+        assert_eq!(texts("std::vector"), ["std", "::", "vector"]);
+    }
+
+    #[test]
+    fn skips_byte_order_mark() {
+        assert_eq!(texts("\u{FEFF}#include \"a.h\"\nx"), ["x"]);
+    }
+}
@@ -0,0 +1,537 @@
+//! Resolves the references in a mod's data against a game's `global-metadata.dat` the same way
+//! the loader does on device, so that missing types and methods are found before deploying
+
+use crate::adb;
+use crate::build::{get_str, offset_len};
+use crate::config::Config;
+use crate::inspect::ModDataPrinter;
+use crate::manifest::Manifest;
+use crate::metadata;
+use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use il2cpp_metadata_raw::Metadata;
+use merge_data::{DeclaringType, MergeModData, TypeDescriptionData};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+const PULLED_METADATA_PATH: &str = "./build/global-metadata.dat";
+
+// Il2CppTypeEnum
+const IL2CPP_TYPE_VALUETYPE: u8 = 0x11;
+const IL2CPP_TYPE_CLASS: u8 = 0x12;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ImageRef {
+    Game(u32),
+    /// The image the mod adds itself
+    Mod,
+    /// An image added by one of the mod's dependencies, which can't be checked without its data
+    Dependency,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum TypeDefRef {
+    Game(u32),
+    /// Index into the mod's added type definitions
+    Mod(usize),
+    Dependency,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct TypeDefLookupKey<'a> {
+    namespace: &'a str,
+    name: &'a str,
+    decl_ty: Option<u32>,
+}
+
+/// Looks up an entry of one of the mod data's tables by an index the mod data itself contains
+fn mod_entry<'a, T>(table: &'a [T], idx: usize, table_name: &str) -> Result<&'a T> {
+    table.get(idx).with_context(|| {
+        format!(
+            "mod data is corrupt: {} index {} is out of bounds",
+            table_name, idx
+        )
+    })
+}
+
+struct Checker<'a> {
+    metadata: &'a Metadata<'a>,
+    mod_data: &'a MergeModData,
+    printer: ModDataPrinter<'a>,
+    image_type_def_map: HashMap<u32, HashMap<TypeDefLookupKey<'a>, u32>>,
+
+    images: Vec<Option<ImageRef>>,
+    type_defs: HashMap<usize, Option<TypeDefRef>>,
+    /// Whether each method description could be resolved
+    methods: Vec<bool>,
+
+    checked: usize,
+    errors: Vec<String>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(metadata: &'a Metadata<'a>, mod_data: &'a MergeModData) -> Result<Self> {
+        // Unlike the loader, we don't have the runtime's type table to look up declaring types
+        // in, so the nested types of each type definition are used instead
+        let mut decl_tys = HashMap::new();
+        for (i, ty_def) in metadata.type_definitions.iter().enumerate() {
+            let range = offset_len(ty_def.nested_types_start, ty_def.nested_type_count as u32);
+            for &nested in &metadata.nested_types[range] {
+                decl_tys.insert(nested, i as u32);
+            }
+        }
+
+        let mut image_type_def_map = HashMap::new();
+        for (image_idx, image) in metadata.images.iter().enumerate() {
+            let mut type_def_map = HashMap::new();
+            for idx in offset_len(image.type_start, image.type_count) {
+                let ty_def = &metadata.type_definitions[idx];
+                let key = TypeDefLookupKey {
+                    namespace: get_str(metadata.string, ty_def.namespace_index as usize)?,
+                    name: get_str(metadata.string, ty_def.name_index as usize)?,
+                    decl_ty: decl_tys.get(&(idx as u32)).copied(),
+                };
+                type_def_map.insert(key, idx as u32);
+            }
+            image_type_def_map.insert(image_idx as u32, type_def_map);
+        }
+
+        Ok(Self {
+            metadata,
+            mod_data,
+            printer: ModDataPrinter::new(mod_data),
+            image_type_def_map,
+            images: Vec::new(),
+            type_defs: HashMap::new(),
+            methods: Vec::new(),
+            checked: 0,
+            errors: Vec::new(),
+        })
+    }
+
+    fn error(&mut self, msg: String) {
+        self.errors.push(msg);
+    }
+
+    fn resolve_image(&mut self, name: &str) -> Result<Option<ImageRef>> {
+        if name == self.mod_data.added_image.name {
+            return Ok(Some(ImageRef::Mod));
+        }
+        for (i, image) in self.metadata.images.iter().enumerate() {
+            if get_str(self.metadata.string, image.name_index as usize)? == name {
+                return Ok(Some(ImageRef::Game(i as u32)));
+            }
+        }
+        let is_dependency = self
+            .mod_data
+            .dependencies
+            .iter()
+            .any(|id| format!("{}.dll", id) == name);
+        if is_dependency {
+            return Ok(Some(ImageRef::Dependency));
+        }
+        Ok(None)
+    }
+
+    fn check_images(&mut self) -> Result<()> {
+        let mod_data = self.mod_data;
+        for desc in &mod_data.image_descriptions {
+            self.checked += 1;
+            let image = self.resolve_image(&desc.name)?;
+            if image.is_none() {
+                self.error(format!("could not resolve image reference: {}", desc.name));
+            }
+            self.images.push(image);
+        }
+        Ok(())
+    }
+
+    fn image(&self, idx: usize) -> Result<Option<ImageRef>> {
+        mod_entry(&self.images, idx, "image description").copied()
+    }
+
+    fn decl_type_def(&self, decl_ty: DeclaringType) -> Result<Option<usize>> {
+        Ok(match decl_ty {
+            DeclaringType::TypeDef(idx) => Some(idx),
+            // Lookups only care about the generic type definition
+            DeclaringType::GenericClass(idx) => {
+                mod_entry(
+                    &self.mod_data.generic_class_insts,
+                    idx,
+                    "generic class instance",
+                )?
+                .class
+            }
+        })
+    }
+
+    /// Returns `None` if the type definition or anything it depends on could not be resolved,
+    /// which has already been reported
+    fn resolve_type_def(&mut self, idx: usize) -> Result<Option<TypeDefRef>> {
+        if let Some(&resolved) = self.type_defs.get(&idx) {
+            return Ok(resolved);
+        }
+
+        let mod_data = self.mod_data;
+        let desc = mod_entry(&mod_data.type_def_descriptions, idx, "type definition")?;
+        let decl_ty = match desc.decl_type {
+            Some(decl_ty) => {
+                let resolved = match self.decl_type_def(decl_ty)? {
+                    Some(decl_ty_def) => self.resolve_type_def(decl_ty_def)?,
+                    None => None,
+                };
+                match resolved {
+                    Some(decl_ty) => Some(decl_ty),
+                    None => {
+                        self.type_defs.insert(idx, None);
+                        return Ok(None);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let resolved = match (self.image(desc.image)?, decl_ty) {
+            (None, _) => None,
+            (Some(ImageRef::Dependency), _) | (_, Some(TypeDefRef::Dependency)) => {
+                Some(TypeDefRef::Dependency)
+            }
+            (Some(ImageRef::Mod), decl_ty) => {
+                let mut resolved = None;
+                for (i, added) in mod_data.added_type_defintions.iter().enumerate() {
+                    if added.name != desc.name || added.namespace != desc.namespace {
+                        continue;
+                    }
+                    let added_decl_ty = match added.declaring_type_def {
+                        Some(idx) => self.resolve_type_def(idx)?,
+                        None => None,
+                    };
+                    if added_decl_ty == decl_ty {
+                        resolved = Some(TypeDefRef::Mod(i));
+                        break;
+                    }
+                }
+                resolved
+            }
+            // Game types can't be nested in the mod's types
+            (Some(ImageRef::Game(_)), Some(TypeDefRef::Mod(_))) => None,
+            (Some(ImageRef::Game(image)), decl_ty) => {
+                let key = TypeDefLookupKey {
+                    namespace: &desc.namespace,
+                    name: &desc.name,
+                    decl_ty: match decl_ty {
+                        Some(TypeDefRef::Game(idx)) => Some(idx),
+                        _ => None,
+                    },
+                };
+                self.image_type_def_map[&image]
+                    .get(&key)
+                    .map(|&idx| TypeDefRef::Game(idx))
+            }
+        };
+        self.type_defs.insert(idx, resolved);
+        Ok(resolved)
+    }
+
+    fn check_type_defs(&mut self) -> Result<()> {
+        let mod_data = self.mod_data;
+        for (idx, desc) in mod_data.type_def_descriptions.iter().enumerate() {
+            self.checked += 1;
+            let decl_ty_resolved = match desc.decl_type {
+                Some(decl_ty) => self
+                    .decl_type_def(decl_ty)?
+                    .map_or(false, |idx| self.type_defs.get(&idx) != Some(&None)),
+                None => true,
+            };
+            let image_resolved = self.image(desc.image)?.is_some();
+            // Only report the first thing that couldn't be resolved
+            if self.resolve_type_def(idx)?.is_none() && image_resolved && decl_ty_resolved {
+                let name = self.printer.type_def_name(idx);
+                self.error(format!("could not resolve type definition: {}", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The index into the game's type table that a type description resolves to, if it can be
+    /// worked out from the metadata alone
+    fn known_game_type(&mut self, idx: usize) -> Result<Option<u32>> {
+        let desc = mod_entry(&self.mod_data.type_descriptions, idx, "type description")?;
+        if desc.attrs != 0 || !matches!(desc.ty, IL2CPP_TYPE_CLASS | IL2CPP_TYPE_VALUETYPE) {
+            return Ok(None);
+        }
+        let by_ref = desc.by_ref;
+        let ty_def_idx = match desc.data {
+            TypeDescriptionData::TypeDefIdx(idx) => idx,
+            _ => return Ok(None),
+        };
+        Ok(match self.resolve_type_def(ty_def_idx)? {
+            Some(TypeDefRef::Game(idx)) => {
+                let ty_def = &self.metadata.type_definitions[idx as usize];
+                Some(if by_ref {
+                    ty_def.byref_type_index
+                } else {
+                    ty_def.byval_type_index
+                })
+            }
+            _ => None,
+        })
+    }
+
+    /// Whether a type from the game's metadata could be the described type. Without the
+    /// runtime's type table, only types that are plain type definitions can be compared.
+    fn type_matches(&mut self, desc_idx: usize, ty: u32) -> Result<bool> {
+        Ok(self
+            .known_game_type(desc_idx)?
+            .map_or(true, |known| known == ty))
+    }
+
+    fn check_methods(&mut self) -> Result<()> {
+        let mod_data = self.mod_data;
+        for (idx, desc) in mod_data.method_descriptions.iter().enumerate() {
+            self.checked += 1;
+            let resolved = match self.resolve_type_def(desc.defining_type)? {
+                Some(TypeDefRef::Game(ty_def_idx)) => self.find_game_method(idx, ty_def_idx)?,
+                Some(TypeDefRef::Mod(ty_def_idx)) => {
+                    let added = &mod_data.added_type_defintions[ty_def_idx];
+                    added.methods.iter().any(|method| {
+                        let num_gen_params = method
+                            .generic_container
+                            .as_ref()
+                            .map_or(0, |gc| gc.parameters.len());
+                        method.name == desc.name
+                            && method.parameters.len() == desc.params.len()
+                            && num_gen_params == desc.num_gen_params as usize
+                    })
+                }
+                Some(TypeDefRef::Dependency) => true,
+                // The declaring type was already reported
+                None => {
+                    self.methods.push(false);
+                    continue;
+                }
+            };
+            self.methods.push(resolved);
+            if !resolved {
+                let name = self.printer.method_name(idx);
+                self.error(format!("could not resolve method reference: {}", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn find_game_method(&mut self, idx: usize, ty_def_idx: u32) -> Result<bool> {
+        let (metadata, mod_data) = (self.metadata, self.mod_data);
+        let desc = &mod_data.method_descriptions[idx];
+        let ty_def = &metadata.type_definitions[ty_def_idx as usize];
+        for method_idx in offset_len(ty_def.method_start, ty_def.method_count as u32) {
+            let method = &metadata.methods[method_idx];
+            let num_gen_params = if method.generic_container_index != u32::MAX {
+                metadata.generic_containers[method.generic_container_index as usize].type_argc
+            } else {
+                0
+            };
+            if get_str(metadata.string, method.name_index as usize)? != desc.name
+                || method.parameter_count as usize != desc.params.len()
+                || num_gen_params != desc.num_gen_params
+            {
+                continue;
+            }
+
+            let params_range = offset_len(method.parameter_start, method.parameter_count as u32);
+            let params = &metadata.parameters[params_range];
+            let mut matches = self.type_matches(desc.return_ty, method.return_type)?;
+            for (&param, game_param) in desc.params.iter().zip(params) {
+                matches = matches && self.type_matches(param, game_param.type_index as u32)?;
+            }
+            if matches {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_fields(&mut self) -> Result<()> {
+        let mod_data = self.mod_data;
+        for (idx, desc) in mod_data.field_descriptions.iter().enumerate() {
+            self.checked += 1;
+            let defining_ty = mod_entry(
+                &mod_data.type_descriptions,
+                desc.defining_type,
+                "type description",
+            )?;
+            let ty_def_idx = match defining_ty.data {
+                TypeDescriptionData::TypeDefIdx(idx) => Some(idx),
+                TypeDescriptionData::GenericClass(idx) => {
+                    mod_entry(&mod_data.generic_class_insts, idx, "generic class instance")?.class
+                }
+                _ => None,
+            };
+            let ty_def_idx = match ty_def_idx {
+                Some(idx) => idx,
+                None => {
+                    let name = self.printer.field_name(idx);
+                    self.error(format!("field reference has no declaring type: {}", name));
+                    continue;
+                }
+            };
+            let field_count = match self.resolve_type_def(ty_def_idx)? {
+                Some(TypeDefRef::Game(idx)) => {
+                    self.metadata.type_definitions[idx as usize].field_count as u32
+                }
+                Some(TypeDefRef::Mod(idx)) => {
+                    mod_data.added_type_defintions[idx].fields.len() as u32
+                }
+                Some(TypeDefRef::Dependency) | None => continue,
+            };
+            if desc.idx >= field_count {
+                let name = self.printer.field_name(idx);
+                self.error(format!(
+                    "could not resolve field reference: {}, the type only has {} fields",
+                    name, field_count
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of generic parameters of a type definition, or `None` if it isn't known
+    fn type_def_generic_argc(&self, ty_def: TypeDefRef) -> Option<usize> {
+        match ty_def {
+            TypeDefRef::Game(idx) => {
+                let ty_def = &self.metadata.type_definitions[idx as usize];
+                Some(if ty_def.generic_container_index != u32::MAX {
+                    self.metadata.generic_containers[ty_def.generic_container_index as usize]
+                        .type_argc as usize
+                } else {
+                    0
+                })
+            }
+            TypeDefRef::Mod(idx) => Some(
+                self.mod_data.added_type_defintions[idx]
+                    .generic_container
+                    .as_ref()
+                    .map_or(0, |gc| gc.parameters.len()),
+            ),
+            TypeDefRef::Dependency => None,
+        }
+    }
+
+    fn check_generics(&mut self) -> Result<()> {
+        let mod_data = self.mod_data;
+        let inst_len = |inst: Option<usize>| -> Result<usize> {
+            Ok(match inst {
+                Some(idx) => mod_entry(&mod_data.generic_instances, idx, "generic instance")?
+                    .types
+                    .len(),
+                None => 0,
+            })
+        };
+
+        for (idx, gc) in mod_data.generic_class_insts.iter().enumerate() {
+            self.checked += 1;
+            let class = match gc.class {
+                Some(class) => self.resolve_type_def(class)?,
+                None => None,
+            };
+            let class = match class {
+                Some(class) => class,
+                None => continue,
+            };
+            let argc = inst_len(gc.context.class)?;
+            if let Some(expected) = self.type_def_generic_argc(class) {
+                if argc != expected {
+                    let name = self.printer.generic_class_name(idx);
+                    self.error(format!(
+                        "generic class instance {} has {} type arguments, but the class takes {}",
+                        name, argc, expected
+                    ));
+                }
+            }
+        }
+
+        for (idx, inst) in mod_data.generic_method_insts.iter().enumerate() {
+            self.checked += 1;
+            // Unresolved methods have already been reported, and the number of generic parameters
+            // is part of matching a method
+            if !*mod_entry(&self.methods, inst.method, "method description")? {
+                continue;
+            }
+            let argc = inst_len(inst.context.method)?;
+            let expected = mod_data.method_descriptions[inst.method].num_gen_params as usize;
+            if argc != expected {
+                let name = self.printer.generic_method_name(idx);
+                self.error(format!(
+                    "generic method instance {} has {} type arguments, but the method takes {}",
+                    name, argc, expected
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check(mut self) -> Result<(usize, Vec<String>)> {
+        self.check_images()?;
+        self.check_type_defs()?;
+        self.check_methods()?;
+        self.check_fields()?;
+        self.check_generics()?;
+        Ok((self.checked, self.errors))
+    }
+}
+
+pub fn check(
+    mod_data_path: Option<String>,
+    metadata_path: Option<String>,
+    config: &mut Config,
+) -> Result<()> {
+    let manifest = match (&mod_data_path, &metadata_path) {
+        (Some(_), Some(_)) => None,
+        _ => Some(Manifest::load()?),
+    };
+    let mod_data_path = match mod_data_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let id = &manifest.as_ref().unwrap().plugin.id;
+            PathBuf::from("./build/bin/out").join(format!("{}.mmd", id))
+        }
+    };
+    let metadata_path = match metadata_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let app = &manifest.as_ref().unwrap().plugin.app;
+            adb::pull_metadata(config, app, PULLED_METADATA_PATH)?;
+            PathBuf::from(PULLED_METADATA_PATH)
+        }
+    };
+
+    let data = fs::read(&mod_data_path)
+        .with_context(|| format!("could not read mod data at {}", mod_data_path.display()))?;
+    let mod_data = MergeModData::deserialize(&data).with_context(|| {
+        format!(
+            "failed to deserialize mod data at {}",
+            mod_data_path.display()
+        )
+    })?;
+
+    let metadata_data = fs::read(&metadata_path)
+        .with_context(|| format!("could not read metadata at {}", metadata_path.display()))?;
+    let (metadata_data, _) = metadata::read(&metadata_data)?;
+    let metadata = il2cpp_metadata_raw::deserialize(&metadata_data)
+        .context("failed to deserialize game metadata")?;
+
+    let (checked, errors) = Checker::new(&metadata, &mod_data)?.check()?;
+    for error in &errors {
+        println!("error: {}", error);
+    }
+    if !errors.is_empty() {
+        bail!(
+            "{} of {} references in {} would fail to resolve",
+            errors.len(),
+            checked,
+            mod_data_path.display()
+        );
+    }
+    println!("All {} references resolved", checked);
+    Ok(())
+}
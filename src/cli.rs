@@ -1,22 +1,50 @@
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::{Result, WrapErr};
+use color_eyre::eyre::{bail, Result, WrapErr};
 
-use crate::config::Config;
-use crate::{adb, build, package};
+use crate::config::{AppConfigDocument, Config, ConfigOverrides};
+use crate::{adb, build, check, diff, doctor, inspect, new, package};
 
 #[derive(Parser)]
 #[clap(version)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Path to the NDK installation, overrides the configured path
+    #[clap(long, global = true, env = "QMERGE_NDK")]
+    ndk: Option<String>,
+    /// Path to the unity installation, overrides the configured path for the app's unity version
+    #[clap(long, global = true, env = "QMERGE_UNITY")]
+    unity: Option<String>,
+    /// The unity version of the application, overrides the configured version
+    #[clap(long, global = true, env = "QMERGE_UNITY_VERSION")]
+    unity_version: Option<String>,
+    /// Path to the ADB executable, overrides the configured path
+    #[clap(long, global = true, env = "QMERGE_ADB")]
+    adb: Option<String>,
+    /// Fail with an error instead of asking for missing configuration
+    #[clap(long, global = true, env = "QMERGE_NO_PROMPT")]
+    no_prompt: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Compile mod in working directory
     Build {
+        /// Directory containing an already compiled mod dll, instead of compiling the project's
+        /// .csproj. It is remembered for later builds, and il2cpp is only run again when the
+        /// managed assemblies or its settings have changed
         #[clap(long)]
         regen_cpp: Option<String>,
+        /// Number of source files to compile in parallel, defaults to the number of CPUs
+        #[clap(short, long)]
+        jobs: Option<usize>,
+        /// Build with the release profile (the default)
+        #[clap(long, conflicts_with = "debug")]
+        release: bool,
+        /// Build with the debug profile, which has debug info and no optimizations
+        #[clap(long)]
+        debug: bool,
     },
     /// Upload the generated mod files
     Upload,
@@ -26,15 +54,131 @@ enum Commands {
     Run,
     /// Setup an application with the given id
     Setup { id: String },
+    /// Create a new mod project in a directory with the given name
+    New {
+        name: String,
+        /// The id of the application the mod is for
+        #[clap(long)]
+        app: String,
+        /// The version of the application the mod is for
+        #[clap(long, default_value = "0.0.0")]
+        app_version: String,
+    },
+    /// Print the contents of a mod data (`.mmd`) file
+    Inspect {
+        /// Path to the mod data file, defaults to the output of the mod in working directory
+        path: Option<String>,
+        /// Print the mod data as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that everything a mod references exists in the game, without deploying it
+    Check {
+        /// Path to the mod data file, defaults to the output of the mod in working directory
+        path: Option<String>,
+        /// Path to the game's `global-metadata.dat`, pulled from the device if not given
+        #[clap(long)]
+        metadata: Option<String>,
+    },
+    /// List the types, methods and fields that changed between two versions of a game's metadata
+    DiffApi {
+        /// Path to the `global-metadata.dat` of the old version
+        #[clap(long)]
+        old: String,
+        /// Path to the `global-metadata.dat` of the new version
+        #[clap(long)]
+        new: String,
+        /// Only list the changes that affect the references in this mod data (`.mmd`) file
+        #[clap(long = "mod")]
+        mod_data: Option<String>,
+    },
+    /// Read and edit the global configuration or the configuration of an application
+    Config {
+        /// Edit the configuration of the application with this id instead of the global configuration
+        #[clap(long)]
+        app: Option<String>,
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Check that the configured tools and installations are usable
+    Doctor,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the value of a key
+    Get { key: String },
+    /// Set the value of a key
+    Set { key: String, value: String },
+    /// Remove a key
+    Unset { key: String },
+    /// Print all keys and their values
+    List,
+    /// Add an entry to a list (`shims` or `no_shims`)
+    Add { key: String, value: String },
+    /// Remove an entry from a list (`shims` or `no_shims`)
+    Remove { key: String, value: String },
+}
+
+fn run_config_command(config: &mut Config, command: ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Get { key } => match config.get_value(&key)? {
+            Some(value) => println!("{}", value),
+            None => bail!("`{}` is not set", key),
+        },
+        ConfigCommand::Set { key, value } => config.set_value(&key, &value)?,
+        ConfigCommand::Unset { key } => config.unset_value(&key)?,
+        ConfigCommand::List => {
+            for (key, value) in config.list_values() {
+                println!("{} = {}", key, value);
+            }
+        }
+        ConfigCommand::Add { .. } | ConfigCommand::Remove { .. } => {
+            bail!("the global configuration has no lists, use `--app <id>` to edit app shims")
+        }
+    }
+    Ok(())
+}
+
+fn run_app_config_command(id: &str, command: ConfigCommand) -> Result<()> {
+    let mut app = AppConfigDocument::load(id)?;
+    match command {
+        ConfigCommand::Get { key } => match app.get_value(&key)? {
+            Some(value) => println!("{}", value),
+            None => bail!("`{}` is not set", key),
+        },
+        ConfigCommand::Set { key, value } => app.set_value(&key, &value)?,
+        ConfigCommand::Unset { key } => app.unset_value(&key)?,
+        ConfigCommand::List => {
+            for (key, value) in app.list_values()? {
+                println!("{} = {}", key, value);
+            }
+        }
+        ConfigCommand::Add { key, value } => app.add_value(&key, &value)?,
+        ConfigCommand::Remove { key, value } => app.remove_value(&key, &value)?,
+    }
+    Ok(())
 }
 
 pub fn run() -> Result<()> {
     let cli = Cli::parse();
 
-    let mut config = Config::load().context("error loading configuration")?;
+    let overrides = ConfigOverrides {
+        ndk_path: cli.ndk,
+        adb_path: cli.adb,
+        unity_install: cli.unity,
+        unity_version: cli.unity_version,
+        no_prompt: cli.no_prompt,
+    };
+    let mut config = Config::load(overrides).context("error loading configuration")?;
 
     match cli.command {
-        Commands::Build { regen_cpp } => build::build(regen_cpp, &mut config)?,
+        Commands::Build {
+            regen_cpp,
+            jobs,
+            release: _,
+            debug,
+        } => build::build(regen_cpp, jobs, debug, &mut config)?,
         Commands::Upload => adb::upload(&mut config)?,
         Commands::Package => package::build_package(&mut config)?,
         Commands::Run => {
@@ -42,6 +186,19 @@ pub fn run() -> Result<()> {
             adb::start_and_log(&mut config)?;
         }
         Commands::Setup { id } => config.setup_app(&id)?,
+        Commands::New {
+            name,
+            app,
+            app_version,
+        } => new::new_project(&name, &app, &app_version, &mut config)?,
+        Commands::Inspect { path, json } => inspect::inspect(path, json)?,
+        Commands::Check { path, metadata } => check::check(path, metadata, &mut config)?,
+        Commands::DiffApi { old, new, mod_data } => diff::diff_api(&old, &new, mod_data)?,
+        Commands::Config { app, command } => match app {
+            Some(id) => run_app_config_command(&id, command)?,
+            None => run_config_command(&mut config, command)?,
+        },
+        Commands::Doctor => doctor::doctor(&config)?,
     }
 
     Ok(())
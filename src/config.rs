@@ -1,10 +1,15 @@
-use color_eyre::eyre::{ContextCompat, Result, WrapErr};
+mod edit;
+
+use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use merge_data::MetadataRevision;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
-use toml_edit::{Document, Item, Table};
+use toml_edit::{DocumentMut, Item, Table};
+
+pub use edit::AppConfigDocument;
 
 fn ask_and_verify<F>(ask: &str, verify: F) -> Result<String>
 where
@@ -37,9 +42,8 @@ fn verify_unity_install(path: &str) -> bool {
 }
 
 fn verify_unity_ver(ver: &str) -> bool {
-    // TODO: more unity versions
-    if !matches!(ver, "2019.4.28f1") {
-        println!("unity version does not exist.");
+    if MetadataRevision::from_unity_version(ver).is_none() {
+        println!("unity version is not supported, only unity versions using metadata version 24.1 through 24.5 (2018.3 - 2018.4, 2019.x and 2020.1) are.");
         return false;
     }
 
@@ -58,6 +62,14 @@ fn verify_ndk_install(path: &str) -> bool {
     true
 }
 
+fn verify_repository(path: &str) -> bool {
+    if !Path::new(path).is_dir() {
+        println!("path is not a directory.");
+        return false;
+    }
+    true
+}
+
 fn verify_adb_executable(path: &str) -> bool {
     let path = Path::new(path);
     if !path.exists() {
@@ -77,15 +89,38 @@ struct ConfigTOML {
     ndk_path: Option<String>,
     adb_path: Option<String>,
     use_system_mono: Option<bool>,
+    /// Directory of packaged mods and mod projects that dependencies are resolved against
+    repository_path: Option<String>,
+}
+
+/// Settings passed on the command line or through the environment, these take precedence over
+/// the configuration files and are never saved.
+#[derive(Default, Debug)]
+pub struct ConfigOverrides {
+    pub ndk_path: Option<String>,
+    pub adb_path: Option<String>,
+    pub unity_install: Option<String>,
+    pub unity_version: Option<String>,
+    /// Fail instead of asking for missing settings on stdin
+    pub no_prompt: bool,
+}
+
+/// A setting that a command needs in order to run
+pub enum Setting<'a> {
+    NdkPath,
+    AdbPath,
+    /// The application config and the unity installation for its unity version
+    App(&'a str),
 }
 
 pub struct Config {
     toml: ConfigTOML,
-    doc: Document,
+    doc: DocumentMut,
+    overrides: ConfigOverrides,
 }
 
 impl Config {
-    pub fn load() -> Result<Self> {
+    pub fn load(overrides: ConfigOverrides) -> Result<Self> {
         let config_dir = config_dir()?;
 
         let config_path = config_dir.join("Config.toml");
@@ -93,8 +128,10 @@ impl Config {
             fs::read_to_string(&config_path)
                 .with_context(|| format!("error reading file at {}", config_path.display()))?
         } else {
-            println!("Welcome to QMerge!");
-            println!("Since this is your first time starting this tool, you may be asked questions to complete your configuration.");
+            if !overrides.no_prompt {
+                println!("Welcome to QMerge!");
+                println!("Since this is your first time starting this tool, you may be asked questions to complete your configuration.");
+            }
             String::new()
         };
 
@@ -102,9 +139,83 @@ impl Config {
             toml: toml::from_str(&config_str)
                 .with_context(|| format!("error parsing TOML at {}", config_path.display()))?,
             doc: config_str.parse()?,
+            overrides,
         })
     }
 
+    fn ask_and_verify<F>(&self, setting: &str, ask: &str, verify: F) -> Result<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.overrides.no_prompt {
+            bail!("{} is not configured and prompting is disabled", setting);
+        }
+        ask_and_verify(ask, verify)
+    }
+
+    /// Makes sure that all of the settings are available without prompting. This does nothing
+    /// unless prompting is disabled, in which case an error listing every missing setting is
+    /// returned.
+    pub fn ensure_settings(&self, settings: &[Setting]) -> Result<()> {
+        if !self.overrides.no_prompt {
+            return Ok(());
+        }
+
+        let mut missing = Vec::new();
+        for setting in settings {
+            match setting {
+                Setting::NdkPath => {
+                    if self.overrides.ndk_path.is_none() && self.toml.ndk_path.is_none() {
+                        missing.push("NDK path (`--ndk` or `QMERGE_NDK`)".to_string());
+                    }
+                }
+                Setting::AdbPath => {
+                    if self.overrides.adb_path.is_none() && self.toml.adb_path.is_none() {
+                        missing.push("ADB path (`--adb` or `QMERGE_ADB`)".to_string());
+                    }
+                }
+                Setting::App(id) => {
+                    let unity_version = match &self.overrides.unity_version {
+                        Some(unity_version) => Some(unity_version.clone()),
+                        None => self.read_app(id)?.map(|app| app.unity_version),
+                    };
+                    let unity_version = match unity_version {
+                        Some(unity_version) => unity_version,
+                        None => {
+                            missing.push(format!(
+                                "unity version of application {} (`--unity-version` or `QMERGE_UNITY_VERSION`)",
+                                id
+                            ));
+                            continue;
+                        }
+                    };
+                    let installed = self
+                        .toml
+                        .unity_installs
+                        .as_ref()
+                        .map_or(false, |installs| installs.contains_key(&unity_version));
+                    if self.overrides.unity_install.is_none() && !installed {
+                        missing.push(format!(
+                            "unity {} installation path (`--unity` or `QMERGE_UNITY`)",
+                            unity_version
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let mut msg = String::from("missing configuration settings and prompting is disabled:");
+            for setting in missing {
+                msg.push_str("\n  - ");
+                msg.push_str(&setting);
+            }
+            bail!(msg);
+        }
+
+        Ok(())
+    }
+
     fn save(&self) -> Result<()> {
         let config_dir = config_dir()?;
         fs::create_dir_all(&config_dir)?;
@@ -114,6 +225,7 @@ impl Config {
     }
 
     pub fn setup_app(&mut self, id: &str) -> Result<()> {
+        self.ensure_settings(&[Setting::NdkPath, Setting::AdbPath, Setting::App(id)])?;
         self.get_ndk_path()?;
         self.get_adb_path()?;
 
@@ -125,6 +237,10 @@ impl Config {
     }
 
     pub fn get_unity_install(&mut self, version: &str) -> Result<String> {
+        if let Some(path) = &self.overrides.unity_install {
+            return Ok(path.clone());
+        }
+
         let mut path = None;
         if let Some(installs) = &self.toml.unity_installs {
             if let Some(install) = installs.get(version) {
@@ -140,7 +256,11 @@ impl Config {
                     "Please enter the path to your unity installation (version {})",
                     version
                 );
-                let path = ask_and_verify(&ask, verify_unity_install)?;
+                let path = self.ask_and_verify(
+                    &format!("Unity {} installation path", version),
+                    &ask,
+                    verify_unity_install,
+                )?;
                 let installs = self.doc["unity_installs"].or_insert(Item::Table(Table::new()));
                 installs[version] = toml_edit::value(&path);
                 self.save()?;
@@ -150,35 +270,57 @@ impl Config {
         Ok(path)
     }
 
+    fn read_app(&self, id: &str) -> Result<Option<AppConfig>> {
+        let config_path = config_dir()?.join(format!("apps/{}/App.toml", id));
+        if !config_path.exists() {
+            return Ok(None);
+        }
+        let str = fs::read_to_string(config_path).context("failed to read app config")?;
+        Ok(Some(
+            toml::from_str(&str).context("failed to deserialize app config")?,
+        ))
+    }
+
     pub fn get_app(&self, id: &str) -> Result<AppConfig> {
-        let config_dir = config_dir()?;
+        let unity_ver_override = match &self.overrides.unity_version {
+            Some(unity_ver) if verify_unity_ver(unity_ver) => Some(unity_ver),
+            Some(unity_ver) => bail!("unsupported unity version: {}", unity_ver),
+            None => None,
+        };
 
-        let app_dir = config_dir.join(format!("apps/{}", id));
-        let config_path = app_dir.join("App.toml");
-        let app = if !config_path.exists() {
-            println!("Application {} has not yet been configured.", id);
-            let unity_ver = ask_and_verify(
-                "Please enter this application's unity version",
-                verify_unity_ver,
-            )?;
-
-            let app = AppConfig {
-                unity_version: unity_ver,
-                shims: HashSet::new(),
-            };
-            fs::create_dir_all(app_dir)?;
-            let str = toml::to_string(&app)?;
-            fs::write(config_path, str).context("failed to write app config")?;
-
-            app
-        } else {
-            let str = fs::read_to_string(config_path).context("failed to read app config")?;
-            toml::from_str(&str).context("failed to deserialize app config")?
+        let app = match (self.read_app(id)?, unity_ver_override) {
+            (Some(mut app), Some(unity_ver)) => {
+                app.unity_version = unity_ver.clone();
+                // The recorded revision goes with the configured version
+                app.metadata_revision = MetadataRevision::from_unity_version(unity_ver);
+                app
+            }
+            (Some(app), None) => app,
+            (None, Some(unity_ver)) => AppConfig::new(unity_ver.clone()),
+            (None, None) => {
+                println!("Application {} has not yet been configured.", id);
+                let unity_ver = self.ask_and_verify(
+                    &format!("Unity version of application {}", id),
+                    "Please enter this application's unity version",
+                    verify_unity_ver,
+                )?;
+
+                let app = AppConfig::new(unity_ver);
+                let app_dir = config_dir()?.join(format!("apps/{}", id));
+                fs::create_dir_all(&app_dir)?;
+                let str = toml::to_string(&app)?;
+                fs::write(app_dir.join("App.toml"), str).context("failed to write app config")?;
+                app
+            }
         };
         Ok(app)
     }
 
     pub fn get_ndk_path(&mut self) -> Result<String> {
+        if let Some(path) = &self.overrides.ndk_path {
+            return Ok(path.clone());
+        }
+
         let path = match &self.toml.ndk_path {
             Some(path) => path.clone(),
             None => {
@@ -191,7 +333,8 @@ impl Config {
                 #[cfg(target_os = "windows")]
                 println!("You may download it from here: https://dl.google.com/android/repository/android-ndk-r22b-windows-x86_64.zip");
 
-                let path = ask_and_verify(
+                let path = self.ask_and_verify(
+                    "NDK path",
                     "Please enter your NDK installation directory",
                     verify_ndk_install,
                 )?;
@@ -205,6 +348,10 @@ impl Config {
     }
 
     pub fn get_adb_path(&mut self) -> Result<String> {
+        if let Some(path) = &self.overrides.adb_path {
+            return Ok(path.clone());
+        }
+
         let path = match &self.toml.adb_path {
             Some(path) => path.clone(),
             None => {
@@ -217,7 +364,8 @@ impl Config {
                 #[cfg(target_os = "windows")]
                 println!("You may download SDK Platform-Tools here: https://dl.google.com/android/repository/platform-tools-latest-windows.zip");
 
-                let path = ask_and_verify(
+                let path = self.ask_and_verify(
+                    "ADB path",
                     "Please enter your ADB executable path",
                     verify_adb_executable,
                 )?;
@@ -231,6 +379,52 @@ impl Config {
         Ok(path)
     }
 
+    /// The configured NDK path, without asking for it
+    pub fn configured_ndk_path(&self) -> Option<&str> {
+        self.overrides
+            .ndk_path
+            .as_deref()
+            .or(self.toml.ndk_path.as_deref())
+    }
+
+    /// The configured ADB path, without asking for it
+    pub fn configured_adb_path(&self) -> Option<&str> {
+        self.overrides
+            .adb_path
+            .as_deref()
+            .or(self.toml.adb_path.as_deref())
+    }
+
+    /// All configured unity installations as (version, path) pairs. An install passed through
+    /// `--unity` is listed without a version.
+    pub fn configured_unity_installs(&self) -> Vec<(Option<&str>, &str)> {
+        if let Some(path) = &self.overrides.unity_install {
+            return vec![(self.overrides.unity_version.as_deref(), path)];
+        }
+        let mut installs: Vec<_> = self
+            .toml
+            .unity_installs
+            .iter()
+            .flatten()
+            .map(|(version, path)| (Some(version.as_str()), path.as_str()))
+            .collect();
+        installs.sort_unstable();
+        installs
+    }
+
+    pub fn configured_use_system_mono(&self) -> bool {
+        self.toml.use_system_mono.unwrap_or(false)
+    }
+
+    /// The directory dependencies are resolved against, `repository` in the config directory
+    /// unless configured otherwise
+    pub fn repository_path(&self) -> Result<PathBuf> {
+        match &self.toml.repository_path {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Ok(config_dir()?.join("repository")),
+        }
+    }
+
     pub fn get_use_system_mono(&mut self) -> Result<bool> {
         let use_system_mono = match self.toml.use_system_mono {
             Some(use_system_mono) => use_system_mono,
@@ -255,5 +449,44 @@ fn config_dir() -> Result<PathBuf> {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AppConfig {
     pub unity_version: String,
+    /// The metadata revision il2cpp uses in `unity_version`, recorded when the app is set up and
+    /// whenever `unity_version` is changed. It can be set by hand for games whose il2cpp doesn't
+    /// match the usual revision of their unity version. App configs written by older versions of
+    /// qmerge don't have it, so it is derived from `unity_version` then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_revision: Option<MetadataRevision>,
+    /// Whether generic methods that the game's `libil2cpp.so` has code for are detected as shims.
+    /// The game's library and metadata are pulled from the device for this.
+    #[serde(default = "default_detect_shims")]
+    pub detect_shims: bool,
+    /// Images whose generic methods always call the game's implementation
+    #[serde(default)]
     pub shims: HashSet<String>,
+    /// Images whose generic methods are never treated as shims, even if they are detected as one
+    #[serde(default)]
+    pub no_shims: HashSet<String>,
+}
+
+fn default_detect_shims() -> bool {
+    true
+}
+
+impl AppConfig {
+    fn new(unity_version: String) -> Self {
+        Self {
+            metadata_revision: MetadataRevision::from_unity_version(&unity_version),
+            unity_version,
+            detect_shims: true,
+            shims: HashSet::new(),
+            no_shims: HashSet::new(),
+        }
+    }
+
+    pub fn metadata_revision(&self) -> Result<MetadataRevision> {
+        match self.metadata_revision {
+            Some(revision) => Ok(revision),
+            None => MetadataRevision::from_unity_version(&self.unity_version)
+                .with_context(|| format!("unsupported unity version: {}", self.unity_version)),
+        }
+    }
 }
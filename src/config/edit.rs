@@ -0,0 +1,279 @@
+use super::{
+    config_dir, verify_adb_executable, verify_ndk_install, verify_repository, verify_unity_install,
+    verify_unity_ver, Config,
+};
+use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use merge_data::MetadataRevision;
+use std::fs;
+use std::path::PathBuf;
+use toml_edit::{Array, DocumentMut, Item, Table};
+
+enum GlobalKey<'a> {
+    NdkPath,
+    AdbPath,
+    UseSystemMono,
+    RepositoryPath,
+    UnityInstall(&'a str),
+}
+
+impl<'a> GlobalKey<'a> {
+    fn parse(key: &'a str) -> Result<Self> {
+        Ok(match key {
+            "ndk_path" => GlobalKey::NdkPath,
+            "adb_path" => GlobalKey::AdbPath,
+            "use_system_mono" => GlobalKey::UseSystemMono,
+            "repository_path" => GlobalKey::RepositoryPath,
+            _ => match key.strip_prefix("unity_installs.") {
+                Some(version) if !version.is_empty() => GlobalKey::UnityInstall(version),
+                _ => bail!(
+                    "unknown configuration key `{}`, expected one of `ndk_path`, `adb_path`, `use_system_mono`, `repository_path` or `unity_installs.<version>`",
+                    key
+                ),
+            },
+        })
+    }
+}
+
+fn item_to_string(item: &Item) -> String {
+    match item.as_str() {
+        Some(str) => str.to_string(),
+        None => item.to_string().trim().to_string(),
+    }
+}
+
+fn verified(value: &str, verify: fn(&str) -> bool, what: &str) -> Result<()> {
+    if !verify(value) {
+        bail!("invalid {}: {}", what, value);
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let table = self.doc.as_table();
+        let item = match GlobalKey::parse(key)? {
+            GlobalKey::NdkPath => table.get("ndk_path"),
+            GlobalKey::AdbPath => table.get("adb_path"),
+            GlobalKey::UseSystemMono => table.get("use_system_mono"),
+            GlobalKey::RepositoryPath => table.get("repository_path"),
+            GlobalKey::UnityInstall(version) => table
+                .get("unity_installs")
+                .and_then(Item::as_table)
+                .and_then(|installs| installs.get(version)),
+        };
+        Ok(item.map(item_to_string))
+    }
+
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<()> {
+        match GlobalKey::parse(key)? {
+            GlobalKey::NdkPath => {
+                verified(value, verify_ndk_install, "NDK installation")?;
+                self.doc["ndk_path"] = toml_edit::value(value);
+            }
+            GlobalKey::AdbPath => {
+                verified(value, verify_adb_executable, "ADB executable")?;
+                self.doc["adb_path"] = toml_edit::value(value);
+            }
+            GlobalKey::UseSystemMono => {
+                let value: bool = value
+                    .parse()
+                    .with_context(|| format!("`{}` must be `true` or `false`", key))?;
+                self.doc["use_system_mono"] = toml_edit::value(value);
+            }
+            GlobalKey::RepositoryPath => {
+                verified(value, verify_repository, "mod repository")?;
+                self.doc["repository_path"] = toml_edit::value(value);
+            }
+            GlobalKey::UnityInstall(version) => {
+                verified(version, verify_unity_ver, "unity version")?;
+                verified(value, verify_unity_install, "unity installation")?;
+                let installs = self.doc["unity_installs"].or_insert(Item::Table(Table::new()));
+                installs[version] = toml_edit::value(value);
+            }
+        }
+        self.save()
+    }
+
+    pub fn unset_value(&mut self, key: &str) -> Result<()> {
+        let table = self.doc.as_table_mut();
+        let removed = match GlobalKey::parse(key)? {
+            GlobalKey::NdkPath => table.remove("ndk_path"),
+            GlobalKey::AdbPath => table.remove("adb_path"),
+            GlobalKey::UseSystemMono => table.remove("use_system_mono"),
+            GlobalKey::RepositoryPath => table.remove("repository_path"),
+            GlobalKey::UnityInstall(version) => table
+                .get_mut("unity_installs")
+                .and_then(Item::as_table_mut)
+                .and_then(|installs| installs.remove(version)),
+        };
+        if removed.is_none() {
+            bail!("`{}` is not set", key);
+        }
+        self.save()
+    }
+
+    pub fn list_values(&self) -> Vec<(String, String)> {
+        let mut values = Vec::new();
+        let table = self.doc.as_table();
+        for key in ["ndk_path", "adb_path", "use_system_mono", "repository_path"] {
+            if let Some(item) = table.get(key) {
+                values.push((key.to_string(), item_to_string(item)));
+            }
+        }
+        if let Some(installs) = table.get("unity_installs").and_then(Item::as_table) {
+            for (version, item) in installs.iter() {
+                values.push((format!("unity_installs.{}", version), item_to_string(item)));
+            }
+        }
+        values
+    }
+}
+
+/// The app configuration keys that hold lists of images
+const APP_LIST_KEYS: [&str; 2] = ["shims", "no_shims"];
+
+/// An editable view of an application's `App.toml` that keeps its formatting intact
+pub struct AppConfigDocument {
+    path: PathBuf,
+    doc: DocumentMut,
+}
+
+impl AppConfigDocument {
+    pub fn load(id: &str) -> Result<Self> {
+        let path = config_dir()?.join(format!("apps/{}/App.toml", id));
+        if !path.exists() {
+            bail!(
+                "application {} has not been configured yet, run `qmerge setup {}` first",
+                id,
+                id
+            );
+        }
+        let str = fs::read_to_string(&path).context("failed to read app config")?;
+        let doc = str
+            .parse()
+            .with_context(|| format!("error parsing TOML at {}", path.display()))?;
+        Ok(Self { path, doc })
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, self.doc.to_string()).context("failed to write app config")
+    }
+
+    fn list_mut(&mut self, key: &str) -> Result<&mut Array> {
+        if !APP_LIST_KEYS.contains(&key) {
+            bail!("`{}` is not a list, only `shims` and `no_shims` are", key);
+        }
+        self.doc[key]
+            .or_insert(toml_edit::value(Array::new()))
+            .as_array_mut()
+            .with_context(|| format!("`{}` in app config is not an array", key))
+    }
+
+    pub fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let item = match key {
+            "unity_version" => self.doc.as_table().get("unity_version"),
+            "metadata_revision" => self.doc.as_table().get("metadata_revision"),
+            "detect_shims" => self.doc.as_table().get("detect_shims"),
+            "shims" | "no_shims" => {
+                return Ok(self.doc.as_table().get(key).map(|shims| {
+                    shims
+                        .as_array()
+                        .map(|shims| {
+                            shims
+                                .iter()
+                                .filter_map(|shim| shim.as_str())
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        })
+                        .unwrap_or_default()
+                }))
+            }
+            _ => bail!(
+                "unknown app configuration key `{}`, expected `unity_version`, `metadata_revision`, `detect_shims`, `shims` or `no_shims`",
+                key
+            ),
+        };
+        Ok(item.map(item_to_string))
+    }
+
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "unity_version" => {
+                verified(value, verify_unity_ver, "unity version")?;
+                let revision = MetadataRevision::from_unity_version(value)
+                    .context("unity version has no metadata revision")?;
+                self.doc["unity_version"] = toml_edit::value(value);
+                self.doc["metadata_revision"] = toml_edit::value(revision.to_string());
+            }
+            "metadata_revision" => {
+                let revision: MetadataRevision = value.parse()?;
+                self.doc["metadata_revision"] = toml_edit::value(revision.to_string());
+            }
+            "detect_shims" => {
+                let value: bool = value
+                    .parse()
+                    .with_context(|| format!("`{}` must be `true` or `false`", key))?;
+                self.doc["detect_shims"] = toml_edit::value(value);
+            }
+            "shims" | "no_shims" => {
+                bail!("use `add` and `remove` to change the `{}` list", key)
+            }
+            _ => bail!(
+                "unknown app configuration key `{}`, expected `unity_version`, `metadata_revision`, `detect_shims`, `shims` or `no_shims`",
+                key
+            ),
+        }
+        self.save()
+    }
+
+    pub fn unset_value(&mut self, key: &str) -> Result<()> {
+        match key {
+            "unity_version" => bail!("`unity_version` is required and cannot be unset"),
+            "metadata_revision" | "detect_shims" => {
+                if self.doc.as_table_mut().remove(key).is_none() {
+                    bail!("`{}` is not set", key);
+                }
+            }
+            "shims" | "no_shims" => self.list_mut(key)?.clear(),
+            _ => bail!(
+                "unknown app configuration key `{}`, expected `unity_version`, `metadata_revision`, `detect_shims`, `shims` or `no_shims`",
+                key
+            ),
+        }
+        self.save()
+    }
+
+    pub fn add_value(&mut self, key: &str, value: &str) -> Result<()> {
+        let shims = self.list_mut(key)?;
+        if shims.iter().any(|shim| shim.as_str() == Some(value)) {
+            bail!("`{}` is already in `{}`", value, key);
+        }
+        shims.push(value);
+        self.save()
+    }
+
+    pub fn remove_value(&mut self, key: &str, value: &str) -> Result<()> {
+        let shims = self.list_mut(key)?;
+        match shims.iter().position(|shim| shim.as_str() == Some(value)) {
+            Some(idx) => shims.remove(idx),
+            None => bail!("`{}` is not in `{}`", value, key),
+        };
+        self.save()
+    }
+
+    pub fn list_values(&self) -> Result<Vec<(String, String)>> {
+        let mut values = Vec::new();
+        for key in [
+            "unity_version",
+            "metadata_revision",
+            "detect_shims",
+            "shims",
+            "no_shims",
+        ] {
+            if let Some(value) = self.get_value(key)? {
+                values.push((key.to_string(), value.replace('\n', ", ")));
+            }
+        }
+        Ok(values)
+    }
+}
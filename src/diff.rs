@@ -0,0 +1,521 @@
+//! Compares the API of two versions of a game's metadata, to find out what a game update broke
+
+use crate::build::{get_str, offset_len};
+use crate::metadata;
+use color_eyre::eyre::{Result, WrapErr};
+use il2cpp_metadata_raw::Metadata;
+use merge_data::{DeclaringType, MergeModData, TypeDescriptionData};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+/// The image name and full name of a type definition, with nested types separated by `/`
+type TypeKey = (String, String);
+
+struct ParamApi {
+    name: String,
+    /// `None` if the type isn't a plain type definition, which can't be named from the metadata
+    /// alone since the runtime's type table lives in libil2cpp.so
+    ty: Option<String>,
+}
+
+struct MethodApi {
+    name: String,
+    return_ty: Option<String>,
+    params: Vec<ParamApi>,
+    num_gen_params: u32,
+}
+
+impl MethodApi {
+    fn signature(&self) -> String {
+        let params = self
+            .params
+            .iter()
+            .map(|param| format!("{} {}", type_name(&param.ty), param.name))
+            .collect::<Vec<_>>();
+        let gen_params = if self.num_gen_params > 0 {
+            format!("`{}", self.num_gen_params)
+        } else {
+            String::new()
+        };
+        format!(
+            "{} {}{}({})",
+            type_name(&self.return_ty),
+            self.name,
+            gen_params,
+            params.join(", ")
+        )
+    }
+
+    /// Whether the loader would still match a reference to `self` with `other`, ignoring the
+    /// name. Types that couldn't be named are assumed to match.
+    fn same_signature(&self, other: &MethodApi) -> bool {
+        self.num_gen_params == other.num_gen_params
+            && self.params.len() == other.params.len()
+            && same_type(&self.return_ty, &other.return_ty)
+            && self
+                .params
+                .iter()
+                .zip(&other.params)
+                .all(|(a, b)| same_type(&a.ty, &b.ty))
+    }
+}
+
+struct FieldApi {
+    name: String,
+    ty: Option<String>,
+}
+
+#[derive(Default)]
+struct TypeApi {
+    methods: Vec<MethodApi>,
+    fields: Vec<FieldApi>,
+}
+
+impl TypeApi {
+    /// Used to recognize renamed types
+    fn member_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .methods
+            .iter()
+            .map(|method| method.name.as_str())
+            .chain(self.fields.iter().map(|field| field.name.as_str()))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+fn type_name(ty: &Option<String>) -> &str {
+    ty.as_deref().unwrap_or("?")
+}
+
+fn same_type(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+struct Api {
+    types: BTreeMap<TypeKey, TypeApi>,
+}
+
+impl Api {
+    fn load(metadata: &Metadata) -> Result<Self> {
+        let str = |idx: u32| get_str(metadata.string, idx as usize);
+
+        let mut decl_tys = HashMap::new();
+        for (i, ty_def) in metadata.type_definitions.iter().enumerate() {
+            let range = offset_len(ty_def.nested_types_start, ty_def.nested_type_count as u32);
+            for &nested in &metadata.nested_types[range] {
+                decl_tys.insert(nested as usize, i);
+            }
+        }
+        let mut names = Vec::with_capacity(metadata.type_definitions.len());
+        for ty_def in &metadata.type_definitions {
+            let namespace = str(ty_def.namespace_index)?;
+            let name = str(ty_def.name_index)?;
+            names.push(if namespace.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}.{}", namespace, name)
+            });
+        }
+        let full_name = |mut idx: usize| {
+            let mut name = names[idx].clone();
+            while let Some(&decl_ty) = decl_tys.get(&idx) {
+                name = format!("{}/{}", names[decl_ty], name);
+                idx = decl_ty;
+            }
+            name
+        };
+
+        // Plain type definitions are the only types that can be named without the type table
+        let mut type_names = HashMap::new();
+        for (i, ty_def) in metadata.type_definitions.iter().enumerate() {
+            let name = full_name(i);
+            type_names.insert(ty_def.byref_type_index, format!("{}&", name));
+            type_names.insert(ty_def.byval_type_index, name);
+        }
+        let ty = |idx: u32| type_names.get(&idx).cloned();
+
+        let mut types = BTreeMap::new();
+        for image in &metadata.images {
+            let image_name = str(image.name_index)?;
+            for i in offset_len(image.type_start, image.type_count) {
+                let ty_def = &metadata.type_definitions[i];
+                let mut api = TypeApi::default();
+                for method_idx in offset_len(ty_def.method_start, ty_def.method_count as u32) {
+                    let method = &metadata.methods[method_idx];
+                    let params_range =
+                        offset_len(method.parameter_start, method.parameter_count as u32);
+                    let params = metadata.parameters[params_range]
+                        .iter()
+                        .map(|param| {
+                            Ok(ParamApi {
+                                name: str(param.name_index as u32)?.to_string(),
+                                ty: ty(param.type_index as u32),
+                            })
+                        })
+                        .collect::<Result<_>>()?;
+                    api.methods.push(MethodApi {
+                        name: str(method.name_index)?.to_string(),
+                        return_ty: ty(method.return_type),
+                        params,
+                        num_gen_params: if method.generic_container_index != u32::MAX {
+                            metadata.generic_containers[method.generic_container_index as usize]
+                                .type_argc
+                        } else {
+                            0
+                        },
+                    });
+                }
+                for field_idx in offset_len(ty_def.field_start, ty_def.field_count as u32) {
+                    let field = &metadata.fields[field_idx];
+                    api.fields.push(FieldApi {
+                        name: str(field.name_index)?.to_string(),
+                        ty: ty(field.type_index),
+                    });
+                }
+                types.insert((image_name.to_string(), full_name(i)), api);
+            }
+        }
+
+        Ok(Self { types })
+    }
+}
+
+enum Change<'a> {
+    RemovedType,
+    RenamedType(&'a TypeKey),
+    RemovedMethod(&'a MethodApi),
+    RenamedMethod(&'a MethodApi, &'a MethodApi),
+    ChangedMethod(&'a MethodApi, Vec<&'a MethodApi>),
+    RemovedField(&'a FieldApi),
+    RenamedField(&'a FieldApi, &'a FieldApi),
+    ChangedField(&'a FieldApi, &'a FieldApi),
+    /// A field that mods reference by index which is now at another index
+    MovedField(&'a FieldApi, usize, usize),
+}
+
+impl<'a> Change<'a> {
+    fn describe(&self, (image, name): &TypeKey) -> String {
+        match self {
+            Change::RemovedType => format!("removed type {} ({})", name, image),
+            Change::RenamedType((_, new_name)) => {
+                format!("renamed type {} to {} ({})", name, new_name, image)
+            }
+            Change::RemovedMethod(method) => {
+                format!("removed method {}::{}", name, method.signature())
+            }
+            Change::RenamedMethod(old, new) => format!(
+                "renamed method {}::{} to {}",
+                name,
+                old.signature(),
+                new.name
+            ),
+            Change::ChangedMethod(old, new) => {
+                let new = new
+                    .iter()
+                    .map(|method| method.signature())
+                    .collect::<Vec<_>>();
+                format!(
+                    "changed method {}::{} to {}",
+                    name,
+                    old.signature(),
+                    new.join(" or ")
+                )
+            }
+            Change::RemovedField(field) => {
+                format!(
+                    "removed field {} {}::{}",
+                    type_name(&field.ty),
+                    name,
+                    field.name
+                )
+            }
+            Change::RenamedField(old, new) => format!(
+                "renamed field {} {}::{} to {}",
+                type_name(&old.ty),
+                name,
+                old.name,
+                new.name
+            ),
+            Change::ChangedField(old, new) => format!(
+                "changed type of field {}::{} from {} to {}",
+                name,
+                old.name,
+                type_name(&old.ty),
+                type_name(&new.ty)
+            ),
+            Change::MovedField(field, old, new) => format!(
+                "moved field {}::{} from index {} to {}",
+                name, field.name, old, new
+            ),
+        }
+    }
+
+    /// The name of the member the change is about, for filtering by a mod's references
+    fn member_name(&self) -> Option<&'a str> {
+        match self {
+            Change::RemovedType | Change::RenamedType(_) => None,
+            Change::RemovedMethod(method)
+            | Change::RenamedMethod(method, _)
+            | Change::ChangedMethod(method, _) => Some(&method.name),
+            Change::RemovedField(field)
+            | Change::RenamedField(field, _)
+            | Change::ChangedField(field, _)
+            | Change::MovedField(field, _, _) => Some(&field.name),
+        }
+    }
+}
+
+fn diff_methods<'a>(old: &'a TypeApi, new: &'a TypeApi, changes: &mut Vec<Change<'a>>) {
+    let old_names: HashSet<&str> = old.methods.iter().map(|m| m.name.as_str()).collect();
+    let mut renamed_to = HashSet::new();
+
+    for method in &old.methods {
+        let exists = new
+            .methods
+            .iter()
+            .any(|new| new.name == method.name && method.same_signature(new));
+        if exists {
+            continue;
+        }
+
+        let overloads: Vec<_> = new
+            .methods
+            .iter()
+            .filter(|new| new.name == method.name)
+            .collect();
+        if !overloads.is_empty() {
+            changes.push(Change::ChangedMethod(method, overloads));
+            continue;
+        }
+
+        // Only methods that are new can be what a removed method was renamed to
+        let mut candidates = new.methods.iter().filter(|new| {
+            !old_names.contains(new.name.as_str())
+                && !renamed_to.contains(new.name.as_str())
+                && method.same_signature(new)
+        });
+        match (candidates.next(), candidates.next()) {
+            (Some(new), None) => {
+                renamed_to.insert(new.name.as_str());
+                changes.push(Change::RenamedMethod(method, new));
+            }
+            _ => changes.push(Change::RemovedMethod(method)),
+        }
+    }
+}
+
+fn diff_fields<'a>(old: &'a TypeApi, new: &'a TypeApi, changes: &mut Vec<Change<'a>>) {
+    let old_names: HashSet<&str> = old.fields.iter().map(|f| f.name.as_str()).collect();
+    let mut renamed_to = HashSet::new();
+
+    for field in &old.fields {
+        if let Some(new) = new.fields.iter().find(|new| new.name == field.name) {
+            if !same_type(&field.ty, &new.ty) {
+                changes.push(Change::ChangedField(field, new));
+            }
+            continue;
+        }
+
+        let mut candidates = new.fields.iter().filter(|new| {
+            !old_names.contains(new.name.as_str())
+                && !renamed_to.contains(new.name.as_str())
+                && field.ty.is_some()
+                && field.ty == new.ty
+        });
+        match (candidates.next(), candidates.next()) {
+            (Some(new), None) => {
+                renamed_to.insert(new.name.as_str());
+                changes.push(Change::RenamedField(field, new));
+            }
+            _ => changes.push(Change::RemovedField(field)),
+        }
+    }
+}
+
+fn diff<'a>(old: &'a Api, new: &'a Api) -> Vec<(&'a TypeKey, Change<'a>)> {
+    let mut changes = Vec::new();
+
+    let added: Vec<_> = new
+        .types
+        .iter()
+        .filter(|(key, _)| !old.types.contains_key(*key))
+        .collect();
+    let mut renamed_to = HashSet::new();
+    for (key, old_ty) in &old.types {
+        let new_ty = match new.types.get(key) {
+            Some(new_ty) => new_ty,
+            None => {
+                // A type that was renamed keeps its members, and stays in the same image
+                let member_names = old_ty.member_names();
+                let mut candidates = added.iter().filter(|((image, _), new_ty)| {
+                    *image == key.0
+                        && !member_names.is_empty()
+                        && new_ty.member_names() == member_names
+                });
+                match (candidates.next(), candidates.next()) {
+                    (Some((new_key, _)), None) if !renamed_to.contains(new_key) => {
+                        renamed_to.insert(*new_key);
+                        changes.push((key, Change::RenamedType(new_key)));
+                    }
+                    _ => changes.push((key, Change::RemovedType)),
+                }
+                continue;
+            }
+        };
+
+        let mut type_changes = Vec::new();
+        diff_methods(old_ty, new_ty, &mut type_changes);
+        diff_fields(old_ty, new_ty, &mut type_changes);
+        changes.extend(type_changes.into_iter().map(|change| (key, change)));
+    }
+
+    changes
+}
+
+/// What a mod references, in terms of the names that the API uses
+#[derive(Default)]
+struct ModRefs {
+    types: HashSet<TypeKey>,
+    methods: HashSet<(TypeKey, String)>,
+    fields: HashSet<(TypeKey, u32)>,
+}
+
+impl ModRefs {
+    fn new(mod_data: &MergeModData) -> Self {
+        fn name(mod_data: &MergeModData, idx: usize) -> Option<String> {
+            let desc = &mod_data.type_def_descriptions[idx];
+            let name = if desc.namespace.is_empty() {
+                desc.name.clone()
+            } else {
+                format!("{}.{}", desc.namespace, desc.name)
+            };
+            let decl_ty = match desc.decl_type {
+                None => return Some(name),
+                Some(DeclaringType::TypeDef(idx)) => idx,
+                Some(DeclaringType::GenericClass(idx)) => mod_data.generic_class_insts[idx].class?,
+            };
+            Some(format!("{}/{}", name(mod_data, decl_ty)?, name))
+        }
+        let key = |idx: usize| {
+            let desc = &mod_data.type_def_descriptions[idx];
+            let image = &mod_data.image_descriptions[desc.image].name;
+            Some((image.clone(), name(mod_data, idx)?))
+        };
+
+        let mut refs = ModRefs::default();
+        refs.types
+            .extend((0..mod_data.type_def_descriptions.len()).filter_map(key));
+        for method in &mod_data.method_descriptions {
+            if let Some(key) = key(method.defining_type) {
+                refs.methods.insert((key, method.name.clone()));
+            }
+        }
+        for field in &mod_data.field_descriptions {
+            let ty_def = match mod_data.type_descriptions[field.defining_type].data {
+                TypeDescriptionData::TypeDefIdx(idx) => Some(idx),
+                TypeDescriptionData::GenericClass(idx) => mod_data.generic_class_insts[idx].class,
+                _ => None,
+            };
+            if let Some(key) = ty_def.and_then(key) {
+                refs.fields.insert((key, field.idx));
+            }
+        }
+        refs
+    }
+
+    /// Fields are referenced by their index in the type, so fields that are added or removed
+    /// before a referenced field break the mod as well
+    fn moved_fields<'a>(&self, old: &'a Api, new: &'a Api) -> Vec<(&'a TypeKey, Change<'a>)> {
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        fields.sort();
+
+        let mut changes = Vec::new();
+        for (key, idx) in fields {
+            let (old_key, old_ty) = match old.types.get_key_value(key) {
+                Some(old_ty) => old_ty,
+                None => continue,
+            };
+            let (field, new_ty) = match (old_ty.fields.get(*idx as usize), new.types.get(key)) {
+                (Some(field), Some(new_ty)) => (field, new_ty),
+                _ => continue,
+            };
+            let new_idx = new_ty.fields.iter().position(|new| new.name == field.name);
+            if let Some(new_idx) = new_idx {
+                if new_idx != *idx as usize {
+                    changes.push((old_key, Change::MovedField(field, *idx as usize, new_idx)));
+                }
+            }
+        }
+        changes
+    }
+
+    fn affected_by(&self, old: &Api, key: &TypeKey, change: &Change) -> bool {
+        if !self.types.contains(key) {
+            return false;
+        }
+        let member = match change.member_name() {
+            Some(member) => member,
+            None => return true,
+        };
+        match change {
+            Change::RemovedField(_)
+            | Change::RenamedField(_, _)
+            | Change::ChangedField(_, _)
+            | Change::MovedField(_, _, _) => {
+                let fields = &old.types[key].fields;
+                self.fields.iter().any(|(field_key, idx)| {
+                    field_key == key
+                        && fields
+                            .get(*idx as usize)
+                            .map_or(false, |field| field.name == member)
+                })
+            }
+            _ => self.methods.contains(&(key.clone(), member.to_string())),
+        }
+    }
+}
+
+fn load_metadata(path: &str) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("could not read metadata at {}", path))?;
+    let (data, _) = metadata::read(&data).with_context(|| format!("could not load {}", path))?;
+    Ok(data.into_owned())
+}
+
+pub fn diff_api(old_path: &str, new_path: &str, mod_path: Option<String>) -> Result<()> {
+    let old_data = load_metadata(old_path)?;
+    let new_data = load_metadata(new_path)?;
+    let old_metadata = il2cpp_metadata_raw::deserialize(&old_data)
+        .with_context(|| format!("failed to deserialize {}", old_path))?;
+    let new_metadata = il2cpp_metadata_raw::deserialize(&new_data)
+        .with_context(|| format!("failed to deserialize {}", new_path))?;
+    let old = Api::load(&old_metadata)?;
+    let new = Api::load(&new_metadata)?;
+
+    let mut changes = diff(&old, &new);
+    if let Some(mod_path) = &mod_path {
+        let data = fs::read(mod_path)
+            .with_context(|| format!("could not read mod data at {}", mod_path))?;
+        let mod_data = MergeModData::deserialize(&data)
+            .with_context(|| format!("failed to deserialize mod data at {}", mod_path))?;
+        let refs = ModRefs::new(&mod_data);
+        changes.extend(refs.moved_fields(&old, &new));
+        changes.retain(|(key, change)| refs.affected_by(&old, key, change));
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    for (key, change) in &changes {
+        println!("{}", change.describe(key));
+    }
+    match (&mod_path, changes.len()) {
+        (Some(mod_path), 0) => println!("Nothing that {} references changed", mod_path),
+        (Some(mod_path), n) => println!("{} changes affect {}", n, mod_path),
+        (None, n) => println!("{} changes", n),
+    }
+
+    Ok(())
+}
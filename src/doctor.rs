@@ -0,0 +1,249 @@
+use crate::build::{applier_path, clang_path};
+use crate::config::Config;
+use crate::utils::platform_executable;
+use color_eyre::eyre::{bail, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The newest NDK major version that is known to work, r22b reports itself as 22.1
+const MAX_NDK_MAJOR: u32 = 22;
+
+struct Check {
+    name: String,
+    result: Result<String, Failure>,
+}
+
+struct Failure {
+    reason: String,
+    hint: String,
+}
+
+fn fail(reason: impl Into<String>, hint: impl Into<String>) -> Result<String, Failure> {
+    Err(Failure {
+        reason: reason.into(),
+        hint: hint.into(),
+    })
+}
+
+/// Runs `command` and returns the first line it prints
+fn probe(mut command: Command) -> Result<String, String> {
+    let output = command
+        .output()
+        .map_err(|err| format!("failed to execute {:?}: {}", command.get_program(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} exited with {}",
+            command.get_program(),
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Ok(stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_string())
+}
+
+fn check_unity_install(version: Option<&str>, path: &str) -> Check {
+    let name = match version {
+        Some(version) => format!("unity {}", version),
+        None => "unity".to_string(),
+    };
+    let unity_path = Path::new(path);
+    let hint = format!(
+        "install unity{} with the Android build support module and run `qmerge config set unity_installs.<version> <path>`",
+        version.map(|v| format!(" {}", v)).unwrap_or_default()
+    );
+    let result = if !unity_path.exists() {
+        fail(format!("{} does not exist", path), hint)
+    } else if !unity_path
+        .join("Editor/Data/il2cpp/build/deploy/net471/il2cpp.exe")
+        .exists()
+    {
+        fail(format!("{} does not contain il2cpp.exe", path), hint)
+    } else if !unity_path
+        .join("Editor/Data/il2cpp/libil2cpp/il2cpp-config.h")
+        .exists()
+    {
+        fail(
+            format!("{} does not contain the libil2cpp headers", path),
+            hint,
+        )
+    } else {
+        Ok(path.to_string())
+    };
+    Check { name, result }
+}
+
+fn ndk_revision(ndk_path: &Path) -> Result<String, String> {
+    let properties = fs::read_to_string(ndk_path.join("source.properties"))
+        .map_err(|err| format!("unable to read source.properties: {}", err))?;
+    properties
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "Pkg.Revision")
+        .map(|(_, value)| value.trim().to_string())
+        .ok_or_else(|| "source.properties has no Pkg.Revision".to_string())
+}
+
+fn check_ndk(ndk_path: Option<&str>) -> Check {
+    let hint = "download NDK r22b and run `qmerge config set ndk_path <path>`";
+    let result = match ndk_path {
+        None => fail("not configured", hint),
+        Some(path) => match ndk_revision(Path::new(path)) {
+            Err(err) => fail(err, hint),
+            Ok(revision) => match revision.split('.').next().map(str::parse::<u32>) {
+                Some(Ok(major)) if major <= MAX_NDK_MAJOR => {
+                    Ok(format!("r{} at {}", revision, path))
+                }
+                Some(Ok(_)) => fail(
+                    format!("NDK {} is newer than r22b and is not supported", revision),
+                    hint,
+                ),
+                _ => fail(format!("unrecognized NDK revision {}", revision), hint),
+            },
+        },
+    };
+    Check {
+        name: "ndk".to_string(),
+        result,
+    }
+}
+
+fn check_clang(ndk_path: Option<&str>) -> Check {
+    let result = match ndk_path {
+        None => fail("NDK is not configured", "configure the NDK first"),
+        Some(path) => {
+            let clang_path = clang_path(path, true);
+            let mut command = Command::new(&clang_path);
+            command.arg("--version");
+            probe(command).or_else(|err| {
+                fail(
+                    err,
+                    format!(
+                        "make sure {} exists and the NDK was built for this host",
+                        clang_path.display()
+                    ),
+                )
+            })
+        }
+    };
+    Check {
+        name: "clang".to_string(),
+        result,
+    }
+}
+
+fn check_adb(adb_path: Option<&str>) -> Check {
+    let hint = "install SDK Platform-Tools and run `qmerge config set adb_path <path>`";
+    let result = match adb_path {
+        None => fail("not configured", hint),
+        Some(path) => {
+            let mut command = Command::new(path);
+            command.arg("version");
+            probe(command).or_else(|err| fail(err, hint))
+        }
+    };
+    Check {
+        name: "adb".to_string(),
+        result,
+    }
+}
+
+fn check_mono(config: &Config) -> Check {
+    let (mono_path, hint) = if config.configured_use_system_mono() {
+        (
+            PathBuf::from("mono"),
+            "install mono or run `qmerge config set use_system_mono false`".to_string(),
+        )
+    } else {
+        match config.configured_unity_installs().first() {
+            Some((_, unity_path)) => {
+                let mut mono_path =
+                    Path::new(unity_path).join("Editor/Data/MonoBleedingEdge/bin/mono");
+                platform_executable(&mut mono_path);
+                (
+                    mono_path,
+                    "reinstall unity or run `qmerge config set use_system_mono true`".to_string(),
+                )
+            }
+            None => {
+                return Check {
+                    name: "mono".to_string(),
+                    result: fail(
+                        "no unity installation to take mono from",
+                        "configure a unity installation first",
+                    ),
+                }
+            }
+        }
+    };
+    let mut command = Command::new(&mono_path);
+    command.arg("--version");
+    Check {
+        name: "mono".to_string(),
+        result: probe(command).or_else(|err| fail(err, hint)),
+    }
+}
+
+fn check_applier() -> Check {
+    let path = applier_path();
+    let result = if path.exists() {
+        Ok(path.display().to_string())
+    } else {
+        fail(
+            format!("{} does not exist", path.display()),
+            "build the applier with `cargo ndk -t arm64-v8a build --release` or copy libmerge_applier.so into ./build/bin/libs",
+        )
+    };
+    Check {
+        name: "applier".to_string(),
+        result,
+    }
+}
+
+pub fn doctor(config: &Config) -> Result<()> {
+    let mut checks = Vec::new();
+
+    let unity_installs = config.configured_unity_installs();
+    if unity_installs.is_empty() {
+        checks.push(Check {
+            name: "unity".to_string(),
+            result: fail(
+                "no unity installations are configured",
+                "run `qmerge setup <app>` or `qmerge config set unity_installs.<version> <path>`",
+            ),
+        });
+    }
+    for (version, path) in unity_installs {
+        checks.push(check_unity_install(version, path));
+    }
+    checks.push(check_ndk(config.configured_ndk_path()));
+    checks.push(check_clang(config.configured_ndk_path()));
+    checks.push(check_adb(config.configured_adb_path()));
+    checks.push(check_mono(config));
+    checks.push(check_applier());
+
+    let mut failed = 0;
+    for check in checks {
+        match check.result {
+            Ok(detail) => println!("[ ok ] {}: {}", check.name, detail),
+            Err(failure) => {
+                failed += 1;
+                println!("[fail] {}: {}", check.name, failure.reason);
+                println!("       hint: {}", failure.hint);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} check(s) failed", failed);
+    }
+    println!("Everything looks good.");
+    Ok(())
+}
@@ -0,0 +1,497 @@
+use crate::manifest::Manifest;
+use color_eyre::eyre::{Result, WrapErr};
+use merge_data::{
+    AddedGenericContainer, DeclaringType, EncodedMethodIndex, GenericContainerOwner,
+    GenericContext, MergeModData, RgctxData, TypeDescriptionData,
+};
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+/// Stands in for a name when a mod data file refers to something that isn't there
+fn invalid_index(idx: usize) -> String {
+    format!("<invalid index {}>", idx)
+}
+
+/// Resolves the indices inside of a [`MergeModData`] into readable names. Indices that are out of
+/// bounds are printed as such instead of panicking, since the file may be corrupt.
+pub struct ModDataPrinter<'a> {
+    data: &'a MergeModData,
+}
+
+impl<'a> ModDataPrinter<'a> {
+    pub fn new(data: &'a MergeModData) -> Self {
+        Self { data }
+    }
+
+    pub fn type_def_name(&self, idx: usize) -> String {
+        let desc = match self.data.type_def_descriptions.get(idx) {
+            Some(desc) => desc,
+            None => return invalid_index(idx),
+        };
+        match desc.decl_type {
+            Some(DeclaringType::TypeDef(decl_ty)) => {
+                format!("{}/{}", self.type_def_name(decl_ty), desc.name)
+            }
+            Some(DeclaringType::GenericClass(decl_ty)) => {
+                format!("{}/{}", self.generic_class_name(decl_ty), desc.name)
+            }
+            None if desc.namespace.is_empty() => desc.name.clone(),
+            None => format!("{}.{}", desc.namespace, desc.name),
+        }
+    }
+
+    pub fn type_name(&self, idx: usize) -> String {
+        let desc = match self.data.type_descriptions.get(idx) {
+            Some(desc) => desc,
+            None => return invalid_index(idx),
+        };
+        let mut name = match desc.data {
+            TypeDescriptionData::TypeDefIdx(idx) => self.type_def_name(idx),
+            TypeDescriptionData::TypeIdx(idx) => match desc.ty {
+                // IL2CPP_TYPE_PTR
+                0x0f => format!("{}*", self.type_name(idx)),
+                _ => format!("{}[]", self.type_name(idx)),
+            },
+            TypeDescriptionData::Array(ref array) => format!(
+                "{}[{}]",
+                self.type_name(array.elem_ty),
+                ",".repeat((array.rank as usize).saturating_sub(1))
+            ),
+            TypeDescriptionData::GenericParam(GenericContainerOwner::Class(_), num) => {
+                format!("!{}", num)
+            }
+            TypeDescriptionData::GenericParam(GenericContainerOwner::Method(_), num) => {
+                format!("!!{}", num)
+            }
+            TypeDescriptionData::GenericClass(idx) => self.generic_class_name(idx),
+        };
+        if desc.by_ref {
+            name.push('&');
+        }
+        name
+    }
+
+    pub fn generic_class_name(&self, idx: usize) -> String {
+        let gc = match self.data.generic_class_insts.get(idx) {
+            Some(gc) => gc,
+            None => return invalid_index(idx),
+        };
+        let class = match gc.class {
+            Some(idx) => self.type_def_name(idx),
+            None => "<unknown>".to_string(),
+        };
+        format!("{}{}", class, self.context_args(&gc.context))
+    }
+
+    pub fn generic_inst(&self, idx: usize) -> String {
+        let inst = match self.data.generic_instances.get(idx) {
+            Some(inst) => inst,
+            None => return invalid_index(idx),
+        };
+        let types = inst
+            .types
+            .iter()
+            .map(|&ty| self.type_name(ty))
+            .collect::<Vec<_>>();
+        format!("<{}>", types.join(", "))
+    }
+
+    fn context_args(&self, context: &GenericContext) -> String {
+        let mut args = String::new();
+        if let Some(class) = context.class {
+            args.push_str(&self.generic_inst(class));
+        }
+        if let Some(method) = context.method {
+            args.push_str(&self.generic_inst(method));
+        }
+        args
+    }
+
+    pub fn method_name(&self, idx: usize) -> String {
+        let desc = match self.data.method_descriptions.get(idx) {
+            Some(desc) => desc,
+            None => return invalid_index(idx),
+        };
+        let params = desc
+            .params
+            .iter()
+            .map(|&ty| self.type_name(ty))
+            .collect::<Vec<_>>();
+        let gen_params = if desc.num_gen_params > 0 {
+            format!("`{}", desc.num_gen_params)
+        } else {
+            String::new()
+        };
+        format!(
+            "{} {}::{}{}({})",
+            self.type_name(desc.return_ty),
+            self.type_def_name(desc.defining_type),
+            desc.name,
+            gen_params,
+            params.join(", ")
+        )
+    }
+
+    pub fn field_name(&self, idx: usize) -> String {
+        let desc = match self.data.field_descriptions.get(idx) {
+            Some(desc) => desc,
+            None => return invalid_index(idx),
+        };
+        format!("{}#{}", self.type_name(desc.defining_type), desc.idx)
+    }
+
+    pub fn generic_method_name(&self, idx: usize) -> String {
+        let inst = match self.data.generic_method_insts.get(idx) {
+            Some(inst) => inst,
+            None => return invalid_index(idx),
+        };
+        format!(
+            "{} {}",
+            self.method_name(inst.method),
+            self.context_args(&inst.context)
+        )
+    }
+
+    pub fn encoded(&self, eidx: EncodedMethodIndex) -> String {
+        match eidx {
+            EncodedMethodIndex::Invalid => "invalid".to_string(),
+            EncodedMethodIndex::Il2CppClass(idx) => format!("class {}", self.type_name(idx)),
+            EncodedMethodIndex::Il2CppType(idx) => format!("type {}", self.type_name(idx)),
+            EncodedMethodIndex::MethodInfo(idx) => format!("method {}", self.method_name(idx)),
+            EncodedMethodIndex::FieldInfo(idx) => format!("field {}", self.field_name(idx)),
+            EncodedMethodIndex::StringLiteral(idx) => {
+                match self.data.added_string_literals.get(idx) {
+                    Some(literal) => format!("string {:?}", literal),
+                    None => format!("string {}", invalid_index(idx)),
+                }
+            }
+            EncodedMethodIndex::MethodRef(idx) => {
+                format!("generic method {}", self.generic_method_name(idx))
+            }
+        }
+    }
+
+    fn write_generic_container(
+        &self,
+        out: &mut String,
+        indent: &str,
+        container: &Option<AddedGenericContainer>,
+    ) -> Result<()> {
+        if let Some(container) = container {
+            for (num, param) in container.parameters.iter().enumerate() {
+                let constraints = param
+                    .constraints
+                    .iter()
+                    .map(|&ty| self.type_name(ty))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    out,
+                    "{}generic param {} {} (flags: {:#x}) : [{}]",
+                    indent,
+                    num,
+                    param.name,
+                    param.flags,
+                    constraints.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> Result<String> {
+        let data = self.data;
+        let mut out = String::new();
+
+        writeln!(out, "Assembly: {}", data.added_assembly.name)?;
+        writeln!(
+            out,
+            "  version: {}.{}.{}.{}",
+            data.added_assembly.major,
+            data.added_assembly.minor,
+            data.added_assembly.build,
+            data.added_assembly.revision
+        )?;
+        writeln!(
+            out,
+            "  image: {} (token: {:#x})",
+            data.added_image.name, data.added_image.token
+        )?;
+        writeln!(out, "  dependencies: [{}]", data.dependencies.join(", "))?;
+        writeln!(out, "  load before: [{}]", data.load_before.join(", "))?;
+        writeln!(out, "  load after: [{}]", data.load_after.join(", "))?;
+        writeln!(out, "  metadata revision: {}", data.metadata_revision)?;
+        writeln!(out)?;
+
+        let sizes = &data.code_table_sizes;
+        writeln!(out, "Code table sizes:")?;
+        writeln!(
+            out,
+            "  generic adjustor thunks: {}",
+            sizes.generic_adjustor_thunks
+        )?;
+        writeln!(
+            out,
+            "  generic method pointers: {}",
+            sizes.generic_method_pointers
+        )?;
+        writeln!(out, "  invoker pointers: {}", sizes.invoker_pointers)?;
+        writeln!(out, "  metadata usages: {}", sizes.metadata_usages)?;
+        writeln!(
+            out,
+            "  attribute generators: {}",
+            sizes.attribute_generators
+        )?;
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Image descriptions ({}):",
+            data.image_descriptions.len()
+        )?;
+        for (i, image) in data.image_descriptions.iter().enumerate() {
+            writeln!(out, "  [{}] {}", i, image.name)?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Type definition descriptions ({}):",
+            data.type_def_descriptions.len()
+        )?;
+        for (i, desc) in data.type_def_descriptions.iter().enumerate() {
+            let image = match data.image_descriptions.get(desc.image) {
+                Some(image) => image.name.clone(),
+                None => invalid_index(desc.image),
+            };
+            writeln!(out, "  [{}] {} in {}", i, self.type_def_name(i), image)?;
+        }
+        writeln!(out)?;
+
+        writeln!(out, "Type descriptions ({}):", data.type_descriptions.len())?;
+        for (i, desc) in data.type_descriptions.iter().enumerate() {
+            writeln!(
+                out,
+                "  [{}] {} (type: {:#04x}, attrs: {:#x}{})",
+                i,
+                self.type_name(i),
+                desc.ty,
+                desc.attrs,
+                if desc.pinned { ", pinned" } else { "" }
+            )?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Method descriptions ({}):",
+            data.method_descriptions.len()
+        )?;
+        for i in 0..data.method_descriptions.len() {
+            writeln!(out, "  [{}] {}", i, self.method_name(i))?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Field descriptions ({}):",
+            data.field_descriptions.len()
+        )?;
+        for i in 0..data.field_descriptions.len() {
+            writeln!(out, "  [{}] {}", i, self.field_name(i))?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Added type definitions ({}):",
+            data.added_type_defintions.len()
+        )?;
+        for ty_def in &data.added_type_defintions {
+            if ty_def.namespace.is_empty() {
+                writeln!(out, "  {} (token: {:#x})", ty_def.name, ty_def.token)?;
+            } else {
+                writeln!(
+                    out,
+                    "  {}.{} (token: {:#x})",
+                    ty_def.namespace, ty_def.name, ty_def.token
+                )?;
+            }
+            writeln!(out, "    flags: {:#x}", ty_def.flags)?;
+            if let Some(parent) = ty_def.parent_type {
+                writeln!(out, "    parent: {}", self.type_name(parent))?;
+            }
+            if let Some(decl_ty) = ty_def.declaring_type_def {
+                writeln!(out, "    declaring type: {}", self.type_def_name(decl_ty))?;
+            }
+            self.write_generic_container(&mut out, "    ", &ty_def.generic_container)?;
+            for &interface in &ty_def.interfaces {
+                writeln!(out, "    implements {}", self.type_name(interface))?;
+            }
+            for &nested in &ty_def.nested_types {
+                writeln!(out, "    nested {}", self.type_def_name(nested))?;
+            }
+            for field in &ty_def.fields {
+                writeln!(
+                    out,
+                    "    field {} {} (token: {:#x}{})",
+                    self.type_name(field.ty),
+                    field.name,
+                    field.token,
+                    if field.default_val.is_some() {
+                        ", has default"
+                    } else {
+                        ""
+                    }
+                )?;
+            }
+            for method in &ty_def.methods {
+                let params = method
+                    .parameters
+                    .iter()
+                    .map(|param| format!("{} {}", self.type_name(param.ty), param.name))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    out,
+                    "    method {} {}({}) (token: {:#x}, flags: {:#x}, slot: {})",
+                    self.type_name(method.return_ty),
+                    method.name,
+                    params.join(", "),
+                    method.token,
+                    method.flags,
+                    method.slot
+                )?;
+                self.write_generic_container(&mut out, "      ", &method.generic_container)?;
+            }
+            for property in &ty_def.properties {
+                writeln!(out, "    property {}", property.name)?;
+            }
+            for event in &ty_def.events {
+                writeln!(out, "    event {} {}", self.type_name(event.ty), event.name)?;
+            }
+            for (slot, &eidx) in ty_def.vtable.iter().enumerate() {
+                writeln!(out, "    vtable[{}] {}", slot, self.encoded(eidx))?;
+            }
+            for &(interface, offset) in &ty_def.interface_offsets {
+                writeln!(
+                    out,
+                    "    interface offset {} @ {}",
+                    self.type_name(interface),
+                    offset
+                )?;
+            }
+        }
+        writeln!(out)?;
+
+        writeln!(out, "Generic instances ({}):", data.generic_instances.len())?;
+        for i in 0..data.generic_instances.len() {
+            writeln!(out, "  [{}] {}", i, self.generic_inst(i))?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Generic method instances ({}):",
+            data.generic_method_insts.len()
+        )?;
+        for i in 0..data.generic_method_insts.len() {
+            writeln!(out, "  [{}] {}", i, self.generic_method_name(i))?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Generic method functions ({}):",
+            data.generic_method_funcs.len()
+        )?;
+        for funcs in &data.generic_method_funcs {
+            writeln!(
+                out,
+                "  {}: method {}, invoker {}, adjustor thunk {:?}",
+                self.generic_method_name(funcs.generic_method),
+                funcs.method_idx,
+                funcs.invoker_idx,
+                funcs.adjustor_thunk_idx
+            )?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Generic class instances ({}):",
+            data.generic_class_insts.len()
+        )?;
+        for (i, gc) in data.generic_class_insts.iter().enumerate() {
+            let class = match gc.class {
+                Some(idx) => self.type_def_name(idx),
+                None => "<unknown>".to_string(),
+            };
+            writeln!(out, "  [{}] {}{}", i, class, self.context_args(&gc.context))?;
+        }
+        writeln!(out)?;
+
+        writeln!(out, "Usage lists ({}):", data.added_usage_lists.len())?;
+        for (i, list) in data.added_usage_lists.iter().enumerate() {
+            writeln!(out, "  [{}]", i)?;
+            for pair in list {
+                writeln!(out, "    {} -> {}", self.encoded(pair.source), pair.dest)?;
+            }
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Custom attribute ranges ({}):",
+            data.added_ca_ranges.len()
+        )?;
+        for range in &data.added_ca_ranges {
+            let types = range
+                .types
+                .iter()
+                .map(|&ty| self.type_name(ty))
+                .collect::<Vec<_>>();
+            writeln!(out, "  {:#x}: [{}]", range.token, types.join(", "))?;
+        }
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "Runtime generic context values ({}):",
+            data.rgctx_values.len()
+        )?;
+        for (i, value) in data.rgctx_values.iter().enumerate() {
+            let data = match value.data {
+                RgctxData::Type(idx) => format!("type {}", self.type_name(idx)),
+                RgctxData::Class(idx) => format!("class {}", self.type_name(idx)),
+                RgctxData::Method(idx) => format!("method {}", self.generic_method_name(idx)),
+                RgctxData::Array(idx) => format!("array of {}", self.type_name(idx)),
+            };
+            writeln!(out, "  [{}] {:#x}: {}", i, value.owner_token, data)?;
+        }
+
+        Ok(out)
+    }
+}
+
+pub fn inspect(path: Option<String>, json: bool) -> Result<()> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let manifest = Manifest::load()?;
+            PathBuf::from("./build/bin/out").join(format!("{}.mmd", manifest.plugin.id))
+        }
+    };
+
+    let data = fs::read(&path)
+        .with_context(|| format!("could not read mod data at {}", path.display()))?;
+    let mod_data = MergeModData::deserialize(&data)
+        .with_context(|| format!("failed to deserialize mod data at {}", path.display()))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&mod_data)?);
+    } else {
+        print!("{}", ModDataPrinter::new(&mod_data).report()?);
+    }
+
+    Ok(())
+}
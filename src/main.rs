@@ -2,10 +2,17 @@
 
 mod adb;
 mod build;
+mod check;
 mod cli;
 mod config;
+mod diff;
+mod doctor;
+mod inspect;
 mod manifest;
+mod metadata;
+mod new;
 mod package;
+mod repository;
 mod utils;
 
 use color_eyre::Result;
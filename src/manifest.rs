@@ -1,18 +1,33 @@
+mod validate;
+
 use color_eyre::eyre::{Result, WrapErr};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
 
+pub use validate::is_valid_id;
+
+const MANIFEST_PATH: &str = "QMerge.toml";
+
 #[derive(Deserialize, Debug)]
 pub struct Manifest {
     pub plugin: Mod,
     pub dependencies: HashMap<String, String>,
+    #[serde(default)]
+    pub profile: Profiles,
+    #[serde(default)]
+    pub il2cpp: Il2CppSettings,
+    #[serde(default)]
+    pub native: NativeSettings,
+    #[serde(default)]
+    pub scripts: Scripts,
 }
 
 impl Manifest {
     pub fn load() -> Result<Manifest> {
-        let str = fs::read_to_string("./QMerge.toml")
+        let str = fs::read_to_string(MANIFEST_PATH)
             .context("failed to read plugin manifest (`QMerge.toml`)")?;
+        validate::validate(&str)?;
         let manifest = toml::from_str(&str).context("failed to deserialize plugin manifest")?;
         Ok(manifest)
     }
@@ -41,3 +56,77 @@ pub struct Mod {
     #[serde(default)]
     pub core_files: Vec<String>,
 }
+
+/// Overrides for the built-in build profiles
+#[derive(Deserialize, Debug, Default)]
+pub struct Profiles {
+    #[serde(default)]
+    pub release: ProfileSettings,
+    #[serde(default)]
+    pub debug: ProfileSettings,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSettings {
+    /// One of `0`, `1`, `2`, `3`, `s` or `z`
+    pub opt_level: Option<String>,
+    pub debug: Option<bool>,
+    pub lto: Option<bool>,
+    /// Preprocessor definitions passed to clang as `-D`
+    #[serde(default)]
+    pub defines: Vec<String>,
+    /// Overrides for the manifest's `[il2cpp]` table
+    #[serde(default)]
+    pub il2cpp: Il2CppSettings,
+}
+
+/// Code generation options for il2cpp, from the `[il2cpp]` table or a profile's
+/// `[profile.<name>.il2cpp]` table
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Il2CppSettings {
+    pub null_checks: Option<bool>,
+    pub array_bounds_checks: Option<bool>,
+    pub divide_by_zero_checks: Option<bool>,
+    pub generic_sharing: Option<bool>,
+    /// Passed to il2cpp as they are, after the arguments for the other options
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+/// Hand-written C and C++ that gets compiled into the mod's library, from the `[native]` table.
+/// Paths are relative to the manifest.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NativeSettings {
+    /// `.c`, `.cc`, `.cpp` or `.cxx` files
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub include_dirs: Vec<String>,
+    /// Preprocessor definitions passed to clang as `-D`
+    #[serde(default)]
+    pub defines: Vec<String>,
+    /// Extra flags for compiling the sources
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Static (`.a`) or shared (`.so`) libraries to link against. Shared libraries still have to
+    /// be shipped with the mod, for example as `native_libs`.
+    #[serde(default)]
+    pub libs: Vec<String>,
+}
+
+/// Shell commands that run at stages of the build, from the `[scripts]` table
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scripts {
+    /// Runs before the C# project is compiled
+    pub pre_build: Option<String>,
+    /// Runs after il2cpp has generated the cpp sources, even if they were up to date
+    pub post_il2cpp: Option<String>,
+    /// Runs after every source has been compiled and before the library is linked
+    pub pre_link: Option<String>,
+    /// Runs after the library and mod data have been written
+    pub post_build: Option<String>,
+}
@@ -0,0 +1,370 @@
+//! Checks `QMerge.toml` for mistakes that deserializing it either wouldn't catch or would only
+//! report vaguely, pointing at where they are in the file
+
+use super::MANIFEST_PATH;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use semver::{Version, VersionReq};
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+use toml_edit::{ImDocument, Item, TableLike};
+
+const MANIFEST_KEYS: &[&str] = &[
+    "plugin",
+    "dependencies",
+    "profile",
+    "il2cpp",
+    "native",
+    "scripts",
+];
+const PLUGIN_KEYS: &[&str] = &[
+    "app",
+    "app_version",
+    "id",
+    "name",
+    "author",
+    "version",
+    "description",
+    "load_before",
+    "load_after",
+    "native_mods",
+    "native_libs",
+    "core_files",
+];
+const PROFILES_KEYS: &[&str] = &["release", "debug"];
+const PROFILE_KEYS: &[&str] = &["opt_level", "debug", "lto", "defines", "il2cpp"];
+const IL2CPP_KEYS: &[&str] = &[
+    "null_checks",
+    "array_bounds_checks",
+    "divide_by_zero_checks",
+    "generic_sharing",
+    "extra_args",
+];
+const NATIVE_KEYS: &[&str] = &["sources", "include_dirs", "defines", "flags", "libs"];
+const SCRIPTS_KEYS: &[&str] = &["pre_build", "post_il2cpp", "pre_link", "post_build"];
+
+/// 1-based line and column of the byte at `offset` in `src`
+fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    let line = before.matches('\n').count() + 1;
+    let col = before[line_start..].chars().count() + 1;
+    (line, col)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if a_char == b_char {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The known key closest to `key`, if it's close enough to be a typo
+fn suggest<'k>(key: &str, known: &[&'k str]) -> Option<&'k str> {
+    let max_distance = (key.chars().count() / 3).max(1);
+    known
+        .iter()
+        .map(|&known| (edit_distance(key, known), known))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, known)| known)
+}
+
+/// Whether `id` can be used as a file name, a path component and as part of a C identifier
+pub fn is_valid_id(id: &str) -> bool {
+    let mut chars = id.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Validator<'a> {
+    src: &'a str,
+    errors: Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, span: Option<Range<usize>>, msg: impl Display) {
+        let error = match span {
+            Some(span) => {
+                let (line, col) = line_col(self.src, span.start);
+                format!("{}:{}:{}: {}", MANIFEST_PATH, line, col, msg)
+            }
+            None => format!("{}: {}", MANIFEST_PATH, msg),
+        };
+        self.errors.push(error);
+    }
+
+    fn check_keys(&mut self, table: &dyn TableLike, name: &str, known: &[&str]) {
+        for (key, _) in table.iter() {
+            if known.contains(&key) {
+                continue;
+            }
+            let span = table.get_key_value(key).and_then(|(key, _)| key.span());
+            match suggest(key, known) {
+                Some(suggestion) => self.error(
+                    span,
+                    format!(
+                        "unknown key `{}` in {}, did you mean `{}`?",
+                        key, name, suggestion
+                    ),
+                ),
+                None => self.error(span, format!("unknown key `{}` in {}", key, name)),
+            }
+        }
+    }
+
+    /// Reports the keys of `item` that aren't `known`, returning it as a table if it is one.
+    /// Anything with the wrong type is left for deserialization to report.
+    fn table<'i>(
+        &mut self,
+        item: Option<&'i Item>,
+        name: &str,
+        known: &[&str],
+    ) -> Option<&'i dyn TableLike> {
+        let table = item?.as_table_like()?;
+        self.check_keys(table, name, known);
+        Some(table)
+    }
+
+    fn check_files(&mut self, table: &dyn TableLike, key: &str) {
+        let array = match table.get(key).and_then(Item::as_array) {
+            Some(array) => array,
+            None => return,
+        };
+        for value in array.iter() {
+            if let Some(path) = value.as_str() {
+                if !Path::new(path).exists() {
+                    self.error(
+                        value.span(),
+                        format!("`{}` file {} does not exist", key, path),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_plugin(&mut self, plugin: &dyn TableLike) {
+        if let Some(item) = plugin.get("id") {
+            if let Some(id) = item.as_str() {
+                if !is_valid_id(id) {
+                    self.error(
+                        item.span(),
+                        format!(
+                            "invalid mod id `{}`: ids may only contain ASCII letters, digits and underscores, and may not start with a digit",
+                            id
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(item) = plugin.get("version") {
+            if let Some(version) = item.as_str() {
+                if let Err(err) = Version::parse(version) {
+                    self.error(
+                        item.span(),
+                        format!("`{}` is not a valid semantic version: {}", version, err),
+                    );
+                }
+            }
+        }
+        for key in ["native_mods", "native_libs", "core_files"] {
+            self.check_files(plugin, key);
+        }
+    }
+
+    fn check_dependencies(&mut self, dependencies: &dyn TableLike) {
+        for (id, item) in dependencies.iter() {
+            if let Some(req) = item.as_str() {
+                if let Err(err) = VersionReq::parse(req) {
+                    self.error(
+                        item.span(),
+                        format!(
+                            "`{}` is not a valid version requirement for `{}`: {}",
+                            req, id, err
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_profiles(&mut self, profiles: &dyn TableLike) {
+        for name in PROFILES_KEYS {
+            let table_name = format!("[profile.{}]", name);
+            if let Some(profile) = self.table(profiles.get(name), &table_name, PROFILE_KEYS) {
+                let table_name = format!("[profile.{}.il2cpp]", name);
+                self.table(profile.get("il2cpp"), &table_name, IL2CPP_KEYS);
+            }
+        }
+    }
+
+    fn check(&mut self, doc: &ImDocument<&str>) {
+        let root = doc.as_table();
+        self.check_keys(root, "the manifest", MANIFEST_KEYS);
+
+        if let Some(plugin) = self.table(root.get("plugin"), "[plugin]", PLUGIN_KEYS) {
+            self.check_plugin(plugin);
+        }
+        if let Some(dependencies) = root.get("dependencies").and_then(Item::as_table_like) {
+            self.check_dependencies(dependencies);
+        }
+        if let Some(profiles) = self.table(root.get("profile"), "[profile]", PROFILES_KEYS) {
+            self.check_profiles(profiles);
+        }
+        self.table(root.get("il2cpp"), "[il2cpp]", IL2CPP_KEYS);
+        if let Some(native) = self.table(root.get("native"), "[native]", NATIVE_KEYS) {
+            for key in ["sources", "libs"] {
+                self.check_files(native, key);
+            }
+        }
+        self.table(root.get("scripts"), "[scripts]", SCRIPTS_KEYS);
+    }
+}
+
+/// Validates the manifest source, reporting every problem at once
+pub fn validate(src: &str) -> Result<()> {
+    let doc = ImDocument::parse(src).context("failed to parse plugin manifest")?;
+    let mut validator = Validator {
+        src,
+        errors: Vec::new(),
+    };
+    validator.check(&doc);
+
+    if !validator.errors.is_empty() {
+        bail!(
+            "plugin manifest has {} error(s):\n{}",
+            validator.errors.len(),
+            validator.errors.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLUGIN: &str = r#"[plugin]
+app = "com.beatgames.beatsaber"
+app_version = "1.13.2"
+id = "TestMod"
+name = "Test Mod"
+author = "test"
+version = "0.1.0"
+description = "A test mod"
+"#;
+
+    fn errors(src: &str) -> Vec<String> {
+        let doc = ImDocument::parse(src).unwrap();
+        let mut validator = Validator {
+            src,
+            errors: Vec::new(),
+        };
+        validator.check(&doc);
+        validator.errors
+    }
+
+    #[test]
+    fn accepts_valid_manifest() {
+        let src = format!(
+            "{}core_files = [\"Cargo.toml\"]\n\n[dependencies]\nOtherMod = \"^1.2\"\n\n[profile.debug.il2cpp]\nnull_checks = true\n",
+            PLUGIN
+        );
+        assert!(errors(&src).is_empty());
+        assert!(validate(&src).is_ok());
+    }
+
+    #[test]
+    fn finds_line_and_column() {
+        let src = "a = 1\nbé = 2\n";
+        assert_eq!(line_col(src, 0), (1, 1));
+        assert_eq!(line_col(src, 4), (1, 5));
+        assert_eq!(line_col(src, 6), (2, 1));
+        // Columns count characters, not bytes
+        assert_eq!(line_col(src, 10), (2, 4));
+    }
+
+    #[test]
+    fn suggests_close_keys() {
+        assert_eq!(suggest("verison", PLUGIN_KEYS), Some("version"));
+        assert_eq!(suggest("ap", PLUGIN_KEYS), Some("app"));
+        assert_eq!(suggest("dependencies", PLUGIN_KEYS), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let src = format!(
+            "scripst = {{}}\n{}verison = \"0.1.0\"\n\n[profile.debug]\nfoo = 1\n",
+            PLUGIN
+        );
+        assert_eq!(
+            errors(&src),
+            [
+                "QMerge.toml:1:1: unknown key `scripst` in the manifest, did you mean `scripts`?",
+                "QMerge.toml:10:1: unknown key `verison` in [plugin], did you mean `version`?",
+                "QMerge.toml:13:1: unknown key `foo` in [profile.debug]",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_ids() {
+        assert!(is_valid_id("TestMod"));
+        assert!(is_valid_id("_test_mod2"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("2mod"));
+        assert!(!is_valid_id("test-mod"));
+        assert!(!is_valid_id("test.mod"));
+
+        let src = PLUGIN.replace("\"TestMod\"", "\"test-mod\"");
+        let errors = errors(&src);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("QMerge.toml:4:6: invalid mod id `test-mod`"));
+    }
+
+    #[test]
+    fn checks_versions() {
+        let src = format!(
+            "{}\n[dependencies]\nOtherMod = \"^1.2\"\nBrokenMod = \"=>1\"\n",
+            PLUGIN.replace("\"0.1.0\"", "\"0.1\"")
+        );
+        let errors = errors(&src);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("QMerge.toml:7:11: `0.1` is not a valid semantic version"));
+        assert!(errors[1].starts_with(
+            "QMerge.toml:12:13: `=>1` is not a valid version requirement for `BrokenMod`"
+        ));
+    }
+
+    #[test]
+    fn checks_files_exist() {
+        let src = format!(
+            "{}native_libs = [\"Cargo.toml\", \"missing.so\"]\n\n[native]\nsources = [\"missing.cpp\"]\n",
+            PLUGIN
+        );
+        assert_eq!(
+            errors(&src),
+            [
+                "QMerge.toml:9:30: `native_libs` file missing.so does not exist",
+                "QMerge.toml:12:12: `sources` file missing.cpp does not exist",
+            ]
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! Reads `global-metadata.dat` of every supported revision into the layout `il2cpp_metadata_raw`
+//! deserializes, which is the one 24.2 and later use
+
+use color_eyre::eyre::{bail, ContextCompat, Result};
+use std::borrow::Cow;
+
+/// The number of offset and size pairs in the header after 24.1
+const HEADER_PAIRS: usize = 32;
+/// 24.1 has one extra pair for the runtime generic context entries
+const V24_1_HEADER_SIZE: usize = 8 + (HEADER_PAIRS + 1) * 8;
+
+// The index of the tables that changed after 24.1 in its header
+const METHODS_PAIR: usize = 5;
+const TYPE_DEFINITIONS_PAIR: usize = 19;
+const RGCTX_ENTRIES_PAIR: usize = 20;
+
+const V24_1_METHOD_SIZE: usize = 52;
+const V24_1_TYPE_DEFINITION_SIZE: usize = 100;
+
+/// Where the code of a method definition is in 24.1
+#[derive(Clone, Copy)]
+pub struct MethodCode {
+    /// Index into the game's `g_MethodPointers`, -1 if the method has no code
+    pub method_index: i32,
+    pub invoker_index: i32,
+    /// Start and length of the method's runtime generic context in `rgctx_entries`
+    pub rgctx: (i32, i32),
+}
+
+/// What 24.1 keeps in the metadata which later revisions moved into each image's code gen module
+#[derive(Default)]
+pub struct MetadataCode {
+    /// Indexed by method definition index
+    pub methods: Vec<MethodCode>,
+    /// Start and length of each type definition's runtime generic context in `rgctx_entries`
+    pub type_rgctxs: Vec<(i32, i32)>,
+    /// The data type and index of every runtime generic context value
+    pub rgctx_entries: Vec<(u32, u32)>,
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Result<i32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("global metadata is truncated")?;
+    Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Makes sure the metadata is a version we can read before handing it off to the deserializer
+pub fn check_version(data: &[u8]) -> Result<()> {
+    let header = data.get(..8).context("global metadata is too short")?;
+    let sanity = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let version = i32::from_le_bytes(header[4..].try_into().unwrap());
+    if sanity != 0xFAB11BAF {
+        bail!("global metadata has an invalid sanity value");
+    }
+    if version != 24 {
+        bail!(
+            "global metadata is version {}, but only version 24 is supported",
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Splits a table into its records, converting each with `f`
+fn convert_table(
+    table: &[u8],
+    record_size: usize,
+    mut f: impl FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    if table.len() % record_size != 0 {
+        bail!(
+            "global metadata table of size {} does not hold {} byte records",
+            table.len(),
+            record_size
+        );
+    }
+    let mut new_table = Vec::new();
+    for record in table.chunks_exact(record_size) {
+        new_table.extend(f(record)?);
+    }
+    Ok(new_table)
+}
+
+/// Converts 24.1 metadata to the 24.2 layout, also returning the method pointer indices, invoker
+/// indices and runtime generic context that are dropped from it. Metadata of later revisions is
+/// returned as is.
+///
+/// 24.0 has the same header as 24.1, but isn't supported because its type and method definitions
+/// have custom attribute indices instead of using the token ranges.
+pub fn read(data: &[u8]) -> Result<(Cow<'_, [u8]>, Option<MetadataCode>)> {
+    check_version(data)?;
+    // The first table directly follows the header, so its offset tells how big the header is
+    if read_i32(data, 8)? as usize != V24_1_HEADER_SIZE {
+        return Ok((Cow::Borrowed(data), None));
+    }
+
+    let mut code = MetadataCode::default();
+    let mut new_data = vec![0; 8 + HEADER_PAIRS * 8];
+    new_data[..8].copy_from_slice(&data[..8]);
+    let mut pair = 0;
+    for i in 0..HEADER_PAIRS + 1 {
+        let offset = read_i32(data, 8 + i * 8)? as usize;
+        let size = read_i32(data, 12 + i * 8)? as usize;
+        let table = data
+            .get(offset..offset + size)
+            .context("global metadata table is out of bounds")?;
+
+        let new_table = match i {
+            RGCTX_ENTRIES_PAIR => {
+                code.rgctx_entries = table
+                    .chunks_exact(8)
+                    .map(|entry| {
+                        let data_ty = read_i32(entry, 0)? as u32;
+                        let idx = read_i32(entry, 4)? as u32;
+                        Ok((data_ty, idx))
+                    })
+                    .collect::<Result<_>>()?;
+                continue;
+            }
+            METHODS_PAIR => convert_table(table, V24_1_METHOD_SIZE, |method| {
+                code.methods.push(MethodCode {
+                    method_index: read_i32(method, 20)?,
+                    invoker_index: read_i32(method, 24)?,
+                    rgctx: (read_i32(method, 32)?, read_i32(method, 36)?),
+                });
+                // Drops methodIndex, invokerIndex, reversePInvokeWrapperIndex, rgctxStartIndex
+                // and rgctxCount
+                Ok([&method[..20], &method[40..]].concat())
+            })?,
+            TYPE_DEFINITIONS_PAIR => convert_table(table, V24_1_TYPE_DEFINITION_SIZE, |ty_def| {
+                code.type_rgctxs
+                    .push((read_i32(ty_def, 28)?, read_i32(ty_def, 32)?));
+                // Drops rgctxStartIndex and rgctxCount
+                Ok([&ty_def[..28], &ty_def[36..]].concat())
+            })?,
+            _ => table.to_vec(),
+        };
+
+        let new_offset = new_data.len() as i32;
+        let header = &mut new_data[8 + pair * 8..16 + pair * 8];
+        header[..4].copy_from_slice(&new_offset.to_le_bytes());
+        header[4..].copy_from_slice(&(new_table.len() as i32).to_le_bytes());
+        new_data.extend(new_table);
+        while new_data.len() % 4 != 0 {
+            new_data.push(0);
+        }
+        pair += 1;
+    }
+
+    Ok((Cow::Owned(new_data), Some(code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGCTX_ENTRIES: [(u32, u32); 2] = [(1, 2210), (3, 12522)];
+
+    fn push_i32(data: &mut Vec<u8>, value: i32) {
+        data.extend(value.to_le_bytes());
+    }
+
+    fn method_record(i: u8) -> Vec<u8> {
+        let mut record = vec![0x40 + i; 20];
+        for value in [10 + i as i32, 20 + i as i32, -1, i as i32, 1] {
+            push_i32(&mut record, value);
+        }
+        record.extend([0x50 + i; 12]);
+        record
+    }
+
+    fn type_def_record() -> Vec<u8> {
+        let mut record = vec![0x60; 28];
+        push_i32(&mut record, -1);
+        push_i32(&mut record, 0);
+        record.extend([0x70; 64]);
+        record
+    }
+
+    /// The tables of a 24.1 metadata file, every table that isn't converted just holds its own
+    /// index
+    fn v24_1_tables() -> Vec<Vec<u8>> {
+        (0..HEADER_PAIRS + 1)
+            .map(|i| match i {
+                METHODS_PAIR => [method_record(0), method_record(1)].concat(),
+                TYPE_DEFINITIONS_PAIR => type_def_record(),
+                RGCTX_ENTRIES_PAIR => RGCTX_ENTRIES
+                    .iter()
+                    .flat_map(|&(data_ty, idx)| [data_ty, idx])
+                    .flat_map(u32::to_le_bytes)
+                    .collect(),
+                _ => vec![i as u8; 4],
+            })
+            .collect()
+    }
+
+    fn write_metadata(tables: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_i32(&mut data, 0xFAB11BAFu32 as i32);
+        push_i32(&mut data, 24);
+        let mut offset = 8 + tables.len() * 8;
+        for table in tables {
+            push_i32(&mut data, offset as i32);
+            push_i32(&mut data, table.len() as i32);
+            offset += table.len();
+        }
+        data.extend(tables.concat());
+        data
+    }
+
+    fn table(data: &[u8], pair: usize) -> &[u8] {
+        let offset = read_i32(data, 8 + pair * 8).unwrap() as usize;
+        let size = read_i32(data, 12 + pair * 8).unwrap() as usize;
+        &data[offset..offset + size]
+    }
+
+    #[test]
+    fn converts_v24_1() {
+        let tables = v24_1_tables();
+        let data = write_metadata(&tables);
+        assert_eq!(read_i32(&data, 8).unwrap() as usize, V24_1_HEADER_SIZE);
+
+        let (converted, code) = read(&data).unwrap();
+        let code = code.unwrap();
+        assert_eq!(converted[..8], data[..8]);
+        assert_eq!(
+            read_i32(&converted, 8).unwrap() as usize,
+            8 + HEADER_PAIRS * 8
+        );
+        for pair in 0..HEADER_PAIRS {
+            let old_pair = if pair < RGCTX_ENTRIES_PAIR {
+                pair
+            } else {
+                pair + 1
+            };
+            let expected = match old_pair {
+                METHODS_PAIR => [
+                    [vec![0x40; 20], vec![0x50; 12]].concat(),
+                    [vec![0x41; 20], vec![0x51; 12]].concat(),
+                ]
+                .concat(),
+                TYPE_DEFINITIONS_PAIR => [vec![0x60; 28], vec![0x70; 64]].concat(),
+                _ => tables[old_pair].clone(),
+            };
+            assert_eq!(table(&converted, pair), expected, "table {}", pair);
+        }
+
+        let methods: Vec<_> = code
+            .methods
+            .iter()
+            .map(|method| (method.method_index, method.invoker_index, method.rgctx))
+            .collect();
+        assert_eq!(methods, [(10, 20, (0, 1)), (11, 21, (1, 1))]);
+        assert_eq!(code.type_rgctxs, [(-1, 0)]);
+        assert_eq!(code.rgctx_entries, RGCTX_ENTRIES);
+    }
+
+    #[test]
+    fn keeps_later_revisions() {
+        let tables: Vec<_> = (0..HEADER_PAIRS).map(|i| vec![i as u8; 4]).collect();
+        let data = write_metadata(&tables);
+        let (read_data, code) = read(&data).unwrap();
+        assert!(matches!(read_data, Cow::Borrowed(_)));
+        assert!(code.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_metadata() {
+        let mut tables = v24_1_tables();
+        tables[METHODS_PAIR].pop();
+        assert!(read(&write_metadata(&tables)).is_err());
+
+        let mut data = write_metadata(&v24_1_tables());
+        data[4] = 27;
+        assert!(read(&data).is_err());
+        data[0] = 0;
+        assert!(read(&data).is_err());
+        assert!(read(&data[..4]).is_err());
+    }
+}
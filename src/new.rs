@@ -0,0 +1,134 @@
+use crate::config::Config;
+use crate::manifest::{is_valid_id, Manifest};
+use color_eyre::eyre::{bail, Result, WrapErr};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+fn manifest_template(name: &str, app: &str, app_version: &str) -> Result<String> {
+    let mut src = String::new();
+    writeln!(src, "[plugin]")?;
+    writeln!(src, "app = \"{}\"", app)?;
+    writeln!(src, "app_version = \"{}\"", app_version)?;
+    writeln!(src, "id = \"{}\"", name)?;
+    writeln!(src, "name = \"{}\"", name)?;
+    writeln!(src, "author = \"\"")?;
+    writeln!(src, "version = \"0.1.0\"")?;
+    writeln!(src, "description = \"\"")?;
+    writeln!(src)?;
+    writeln!(src, "[dependencies]")?;
+    writeln!(src, "QMergeCore = \"^0.1.0\"")?;
+    Ok(src)
+}
+
+/// Assemblies every mod references. References to the other game assemblies a mod uses are
+/// added by hand, the same way as in MergeExample.
+const CSPROJ_REFERENCES: [&str; 2] = ["UnityEngine.CoreModule", "QMergeCore"];
+
+fn csproj_template(name: &str) -> Result<String> {
+    let mut src = String::new();
+    writeln!(src, "<Project Sdk=\"Microsoft.NET.Sdk\">")?;
+    writeln!(src, "    <PropertyGroup>")?;
+    writeln!(src, "        <TargetFramework>net452</TargetFramework>")?;
+    writeln!(src, "        <LangVersion>latest</LangVersion>")?;
+    writeln!(src, "        <Nullable>enable</Nullable>")?;
+    writeln!(src, "        <AssemblyName>{}</AssemblyName>", name)?;
+    writeln!(src, "    </PropertyGroup>")?;
+    writeln!(src)?;
+    writeln!(src, "    <ItemGroup>")?;
+    for reference in CSPROJ_REFERENCES {
+        writeln!(src, "        <Reference Include=\"{}\">", reference)?;
+        writeln!(
+            src,
+            "            <HintPath>build/Managed/{}.dll</HintPath>",
+            reference
+        )?;
+        writeln!(src, "        </Reference>")?;
+    }
+    writeln!(src, "    </ItemGroup>")?;
+    writeln!(src)?;
+    writeln!(src, "    <ItemGroup>")?;
+    writeln!(src, "      <Compile Remove=\"build\\**\" />")?;
+    writeln!(src, "      <EmbeddedResource Remove=\"build\\**\" />")?;
+    writeln!(src, "      <None Remove=\"build\\**\" />")?;
+    writeln!(src, "    </ItemGroup>")?;
+    writeln!(src, "</Project>")?;
+    Ok(src)
+}
+
+const BUILD_TARGETS_TEMPLATE: &str = r#"<Project>
+    <PropertyGroup>
+        <BuildDependsOn>$(BuildDependsOn);QMergeBuild</BuildDependsOn>
+    </PropertyGroup>
+
+    <Target Name="QMergeBuild" DependsOnTargets="Compile">
+        <Exec Command="qmerge build --regen-cpp $(OutputPath)" />
+    </Target>
+</Project>
+"#;
+
+const GITIGNORE_TEMPLATE: &str = "build/\n.idea/\nobj/\nbin/\n*.user\n*.qmod\n";
+
+fn plugin_template(name: &str) -> Result<String> {
+    // `ModLoader::load_mod` looks for a parameterless `Plugin.Init` in the global namespace
+    let mut src = String::new();
+    writeln!(src, "using QMLogger = QMerge.Logging.Logger;")?;
+    writeln!(src)?;
+    writeln!(src, "public class Plugin")?;
+    writeln!(src, "{{")?;
+    writeln!(
+        src,
+        "    internal static readonly QMLogger Logger = new QMLogger(\"{}\");",
+        name
+    )?;
+    writeln!(src)?;
+    writeln!(src, "    public static void Init()")?;
+    writeln!(src, "    {{")?;
+    writeln!(src, "        Logger.Info(\"Initializing {}\");", name)?;
+    writeln!(src, "    }}")?;
+    writeln!(src, "}}")?;
+    Ok(src)
+}
+
+pub fn new_project(name: &str, app_id: &str, app_version: &str, config: &mut Config) -> Result<()> {
+    if !is_valid_id(name) {
+        bail!(
+            "invalid mod name `{}`: names may only contain ASCII letters, digits and underscores, and may not start with a digit",
+            name
+        );
+    }
+
+    let project_path = Path::new(name);
+    if project_path.exists() {
+        bail!("destination `{}` already exists", project_path.display());
+    }
+
+    // Only makes sure the app is configured before anything is created
+    config.get_app(app_id)?;
+
+    let manifest_src = manifest_template(name, app_id, app_version)?;
+    // Make sure we never hand out a manifest that `qmerge build` would reject
+    toml::from_str::<Manifest>(&manifest_src).context("generated manifest is invalid")?;
+
+    fs::create_dir_all(project_path.join("src"))?;
+    fs::create_dir_all(project_path.join("build/Managed"))?;
+    fs::write(project_path.join("QMerge.toml"), manifest_src)?;
+    fs::write(
+        project_path.join(format!("{}.csproj", name)),
+        csproj_template(name)?,
+    )?;
+    fs::write(
+        project_path.join("Directory.Build.targets"),
+        BUILD_TARGETS_TEMPLATE,
+    )?;
+    fs::write(project_path.join(".gitignore"), GITIGNORE_TEMPLATE)?;
+    fs::write(project_path.join("src/Plugin.cs"), plugin_template(name)?)?;
+
+    println!("Created mod project `{}` for {}", name, app_id);
+    println!(
+        "Copy the game's managed assemblies into `{}` and reference the ones the mod uses in {}.csproj, dependencies like QMergeCore are copied from the mod repository when building",
+        project_path.join("build/Managed").display(),
+        name
+    );
+    Ok(())
+}
@@ -31,6 +31,7 @@ struct QModJson<'a> {
     package_id: &'a str,
     package_version: &'a str,
     description: &'a str,
+    // Spelled the way existing packages and loaders read it
     dependences: Vec<Dependency<'a>>,
     mod_files: Vec<&'a str>,
     library_files: Vec<&'a str>,
@@ -114,6 +115,19 @@ pub fn build_package(_config: &mut Config) -> Result<()> {
         });
     }
 
+    // Not copied anywhere on the device, but lets the qmod be used in a mod repository by mods
+    // that depend on this one
+    let dll_name = format!("{}.dll", plugin.id);
+    let dll_path = Path::new("./build/Managed").join(&dll_name);
+    let dll = fs::read(&dll_path).with_context(|| {
+        format!(
+            "could not read managed dll at {}, make sure to build your mod first",
+            dll_path.display()
+        )
+    })?;
+    qmod.start_file(&dll_name, Default::default())?;
+    qmod.write_all(&dll)?;
+
     let json = QModJson {
         qp_ver: "0.1.1",
         name: &plugin.name,
@@ -0,0 +1,578 @@
+//! Resolves a mod's dependencies against a local repository, which is a directory of packaged
+//! `.qmod`s and mod projects, and installs their managed dlls for the C# project and il2cpp to
+//! compile against
+
+use crate::config::Config;
+use crate::manifest::Manifest;
+use color_eyre::eyre::{bail, eyre, ContextCompat, Result, WrapErr};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use zip::ZipArchive;
+
+const LOCK_PATH: &str = "QMerge.lock";
+const MANAGED_PATH: &str = "./build/Managed";
+
+#[derive(Deserialize)]
+struct QModDependency {
+    id: String,
+    version: String,
+}
+
+/// The parts of a qmod's `mod.json` needed for resolution
+#[derive(Deserialize)]
+struct QModJson {
+    id: String,
+    version: String,
+    // `qmerge package` writes this as `dependences`
+    #[serde(default, alias = "dependences")]
+    dependencies: Vec<QModDependency>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceKind {
+    QMod,
+    Project,
+}
+
+/// A version of a mod available in the repository
+struct Package {
+    id: String,
+    version: Version,
+    /// Pairs of dependency ids and version requirements
+    dependencies: Vec<(String, String)>,
+    kind: SourceKind,
+    source: PathBuf,
+}
+
+impl Package {
+    fn read_qmod(path: &Path) -> Result<Self> {
+        let mut qmod = ZipArchive::new(File::open(path)?)?;
+        let mod_json = qmod.by_name("mod.json").context("qmod has no mod.json")?;
+        let mod_json: QModJson = serde_json::from_reader(mod_json)?;
+        Ok(Self {
+            version: Version::parse(&mod_json.version)?,
+            id: mod_json.id,
+            dependencies: mod_json
+                .dependencies
+                .into_iter()
+                .map(|dependency| (dependency.id, dependency.version))
+                .collect(),
+            kind: SourceKind::QMod,
+            source: path.to_owned(),
+        })
+    }
+
+    fn read_project(path: &Path) -> Result<Self> {
+        let str = fs::read_to_string(path.join("QMerge.toml"))?;
+        let manifest: Manifest = toml::from_str(&str)?;
+        Ok(Self {
+            version: Version::parse(&manifest.plugin.version)?,
+            id: manifest.plugin.id,
+            dependencies: manifest.dependencies.into_iter().collect(),
+            kind: SourceKind::Project,
+            source: path.to_owned(),
+        })
+    }
+
+    /// Copies the package's managed dll into build/Managed
+    fn install(&self) -> Result<()> {
+        let file_name = format!("{}.dll", self.id);
+        let dest_path = Path::new(MANAGED_PATH).join(&file_name);
+        match self.kind {
+            SourceKind::QMod => {
+                let mut qmod = ZipArchive::new(File::open(&self.source)?)?;
+                let mut dll = qmod.by_name(&file_name).with_context(|| {
+                    format!(
+                        "{} does not contain {}, package it again with a newer qmerge",
+                        self.source.display(),
+                        file_name
+                    )
+                })?;
+                io::copy(&mut dll, &mut File::create(&dest_path)?)?;
+            }
+            SourceKind::Project => {
+                let src_path = self.source.join("build/Managed").join(&file_name);
+                fs::copy(&src_path, &dest_path).with_context(|| {
+                    format!(
+                        "failed to copy {}, make sure {} has been built",
+                        src_path.display(),
+                        self.id
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads every package in the repository, skipping the ones that can't be read
+fn scan(repository: &Path) -> Result<Vec<Package>> {
+    let mut entries = fs::read_dir(repository)
+        .with_context(|| format!("failed to read mod repository at {}", repository.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut packages = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        let package = if path.extension().map_or(false, |ext| ext == "qmod") {
+            Package::read_qmod(&path)
+        } else if path.join("QMerge.toml").is_file() {
+            Package::read_project(&path)
+        } else {
+            continue;
+        };
+        match package {
+            Ok(package) => packages.push(package),
+            Err(err) => println!("warning: skipping {}: {:#}", path.display(), err),
+        }
+    }
+    Ok(packages)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Default)]
+struct LockFile {
+    #[serde(default, rename = "package")]
+    packages: Vec<LockedPackage>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct LockedPackage {
+    id: String,
+    version: String,
+    /// The qmod or project the package was installed from, relative to the mod repository
+    source: String,
+}
+
+impl LockFile {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        let str = fs::read_to_string(path).context("failed to read QMerge.lock")?;
+        toml::from_str(&str).context("failed to deserialize QMerge.lock")
+    }
+
+    /// Records the resolved packages, with their sources relative to the repository
+    fn new(resolved: &BTreeMap<&str, &Package>, repository: &Path) -> Self {
+        let packages = resolved
+            .values()
+            .map(|package| {
+                // Packages are always read from the top level of the repository, so this is just
+                // the file or directory name, which stays the same wherever the repository is
+                let source = package
+                    .source
+                    .strip_prefix(repository)
+                    .unwrap_or(&package.source);
+                LockedPackage {
+                    id: package.id.clone(),
+                    version: package.version.to_string(),
+                    source: source.display().to_string(),
+                }
+            })
+            .collect();
+        Self { packages }
+    }
+
+    /// Writes the lock to `path` if it changed. Without any packages the file is removed
+    /// instead, because a lock left over from removed dependencies would pin them if they are
+    /// added back.
+    fn save(&self, path: &Path) -> Result<()> {
+        if self.packages.is_empty() {
+            if path.exists() {
+                fs::remove_file(path).context("failed to remove QMerge.lock")?;
+            }
+            return Ok(());
+        }
+        if *self != Self::load(path)? {
+            fs::write(path, toml::to_string(self)?).context("failed to write QMerge.lock")?;
+        }
+        Ok(())
+    }
+
+    fn locked_version(&self, id: &str) -> Option<&str> {
+        self.packages
+            .iter()
+            .find(|package| package.id == id)
+            .map(|package| package.version.as_str())
+    }
+}
+
+/// Picks a version for every dependency and their dependencies in turn. A version is chosen as
+/// soon as a mod is first required, preferring the locked version and otherwise the newest that
+/// matches, so a later requirement that the chosen version doesn't meet is an error. Requirements
+/// on the mod itself are checked against its version, but it's never installed.
+fn resolve<'a>(
+    manifest: &Manifest,
+    packages: &'a [Package],
+    lock: &LockFile,
+) -> Result<BTreeMap<&'a str, &'a Package>> {
+    let mut dependencies: Vec<_> = manifest.dependencies.iter().collect();
+    dependencies.sort();
+    let mut queue: VecDeque<_> = dependencies
+        .into_iter()
+        .map(|(id, req)| (id.as_str(), req.as_str(), manifest.plugin.id.as_str()))
+        .collect();
+    let mut resolved = BTreeMap::new();
+
+    while let Some((id, req, required_by)) = queue.pop_front() {
+        let req = VersionReq::parse(req).with_context(|| {
+            format!(
+                "{} has an invalid version requirement `{}` for {}",
+                required_by, req, id
+            )
+        })?;
+        if id == manifest.plugin.id {
+            let version = Version::parse(&manifest.plugin.version)?;
+            if !req.matches(&version) {
+                bail!(
+                    "{} requires {} {}, but this is version {}",
+                    required_by,
+                    id,
+                    req,
+                    version
+                );
+            }
+            continue;
+        }
+        if let Some(package) = resolved.get(id) {
+            if !req.matches(&package.version) {
+                bail!(
+                    "{} requires {} {}, but version {} was already selected",
+                    required_by,
+                    id,
+                    req,
+                    package.version
+                );
+            }
+            continue;
+        }
+
+        let candidates: Vec<_> = packages
+            .iter()
+            .filter(|package| package.id == id && req.matches(&package.version))
+            .collect();
+        let locked = lock.locked_version(id).and_then(|version| {
+            candidates
+                .iter()
+                .find(|package| package.version.to_string() == version)
+        });
+        let package = match locked {
+            Some(package) => *package,
+            None => candidates
+                .iter()
+                .max_by(|a, b| a.version.cmp(&b.version))
+                .copied()
+                .ok_or_else(|| {
+                    let available: Vec<_> = packages
+                        .iter()
+                        .filter(|package| package.id == id)
+                        .map(|package| package.version.to_string())
+                        .collect();
+                    if available.is_empty() {
+                        eyre!(
+                            "{} requires {} {}, which is not in the mod repository",
+                            required_by,
+                            id,
+                            req
+                        )
+                    } else {
+                        eyre!(
+                            "{} requires {} {}, but the mod repository only has {}",
+                            required_by,
+                            id,
+                            req,
+                            available.join(", ")
+                        )
+                    }
+                })?,
+        };
+
+        resolved.insert(package.id.as_str(), package);
+        for (dep_id, dep_req) in &package.dependencies {
+            queue.push_back((dep_id.as_str(), dep_req.as_str(), package.id.as_str()));
+        }
+    }
+
+    check_cycles(&resolved)?;
+    Ok(resolved)
+}
+
+/// Fails if resolved packages depend on each other in a cycle, which no load order satisfies
+fn check_cycles(resolved: &BTreeMap<&str, &Package>) -> Result<()> {
+    fn visit<'a>(
+        id: &'a str,
+        resolved: &BTreeMap<&str, &'a Package>,
+        visited: &mut HashSet<&'a str>,
+        path: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        if let Some(start) = path.iter().position(|&on_path| on_path == id) {
+            bail!("dependency cycle: {} -> {}", path[start..].join(" -> "), id);
+        }
+        // The mod itself isn't resolved, so requirements on it end here
+        let package = match resolved.get(id) {
+            Some(&package) if visited.insert(id) => package,
+            _ => return Ok(()),
+        };
+        path.push(id);
+        for (dep_id, _) in &package.dependencies {
+            visit(dep_id, resolved, visited, path)?;
+        }
+        path.pop();
+        Ok(())
+    }
+
+    let mut visited = HashSet::new();
+    for package in resolved.values() {
+        visit(&package.id, resolved, &mut visited, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+/// Resolves the manifest's dependencies, copies their managed dlls into build/Managed and
+/// records the chosen versions in `QMerge.lock`
+pub fn install_dependencies(manifest: &Manifest, config: &Config) -> Result<()> {
+    let lock_path = Path::new(LOCK_PATH);
+    if manifest.dependencies.is_empty() {
+        return LockFile::default().save(lock_path);
+    }
+    let repository = config.repository_path()?;
+    if !repository.is_dir() {
+        println!(
+            "warning: there is no mod repository at {}, dependencies have to be copied into build/Managed by hand",
+            repository.display()
+        );
+        return Ok(());
+    }
+
+    let packages = scan(&repository)?;
+    let old_lock = LockFile::load(lock_path)?;
+    let resolved = resolve(manifest, &packages, &old_lock)?;
+
+    fs::create_dir_all(MANAGED_PATH)?;
+    for package in resolved.values() {
+        debug!(
+            "using {} {} from {}",
+            package.id,
+            package.version,
+            package.source.display()
+        );
+        package.install()?;
+    }
+    LockFile::new(&resolved, &repository).save(lock_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::ZipWriter;
+
+    /// An empty mod repository for a single test
+    fn temp_repository(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qmerge-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest_src(id: &str, version: &str, dependencies: &[(&str, &str)]) -> String {
+        let mut src = format!(
+            "[plugin]\napp = \"com.beatgames.beatsaber\"\napp_version = \"1.13.2\"\nid = \"{}\"\nname = \"{}\"\nauthor = \"test\"\nversion = \"{}\"\ndescription = \"\"\n\n[dependencies]\n",
+            id, id, version
+        );
+        for (dep_id, req) in dependencies {
+            src.push_str(&format!("{} = \"{}\"\n", dep_id, req));
+        }
+        src
+    }
+
+    fn manifest(id: &str, version: &str, dependencies: &[(&str, &str)]) -> Manifest {
+        toml::from_str(&manifest_src(id, version, dependencies)).unwrap()
+    }
+
+    fn add_project(repository: &Path, id: &str, version: &str, dependencies: &[(&str, &str)]) {
+        let dir = repository.join(format!("{}-{}", id, version));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("QMerge.toml"),
+            manifest_src(id, version, dependencies),
+        )
+        .unwrap();
+    }
+
+    fn add_qmod(repository: &Path, name: &str, mod_json: &str) {
+        let mut qmod = ZipWriter::new(File::create(repository.join(name)).unwrap());
+        qmod.start_file("mod.json", Default::default()).unwrap();
+        qmod.write_all(mod_json.as_bytes()).unwrap();
+        qmod.finish().unwrap();
+    }
+
+    /// The id and version of each resolved package
+    fn resolve_versions(
+        manifest: &Manifest,
+        packages: &[Package],
+        lock: &LockFile,
+    ) -> Result<Vec<String>> {
+        let resolved = resolve(manifest, packages, lock)?;
+        Ok(resolved
+            .values()
+            .map(|package| format!("{} {}", package.id, package.version))
+            .collect())
+    }
+
+    #[test]
+    fn resolves_newest_matching_versions() {
+        let repository = temp_repository("resolve");
+        add_project(&repository, "A", "1.0.0", &[("B", "^1.0")]);
+        add_project(&repository, "A", "1.1.0", &[("B", "^1.1")]);
+        add_project(&repository, "A", "2.0.0", &[]);
+        add_project(&repository, "B", "1.0.0", &[]);
+        add_project(&repository, "B", "1.2.0", &[]);
+        add_project(&repository, "B", "2.0.0", &[]);
+        let packages = scan(&repository).unwrap();
+        let lock = LockFile::default();
+
+        let root = manifest("Root", "0.1.0", &[("A", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock).unwrap(),
+            ["A 1.1.0", "B 1.2.0"]
+        );
+
+        let root = manifest("Root", "0.1.0", &[("A", "^1"), ("B", "=1.0.0")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock)
+                .unwrap_err()
+                .to_string(),
+            "A requires B ^1.1, but version 1.0.0 was already selected"
+        );
+        let root = manifest("Root", "0.1.0", &[("A", "^3")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock)
+                .unwrap_err()
+                .to_string(),
+            "Root requires A ^3, but the mod repository only has 1.0.0, 1.1.0, 2.0.0"
+        );
+        let root = manifest("Root", "0.1.0", &[("C", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock)
+                .unwrap_err()
+                .to_string(),
+            "Root requires C ^1, which is not in the mod repository"
+        );
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn prefers_locked_versions() {
+        let repository = temp_repository("locked");
+        add_project(&repository, "A", "1.0.0", &[]);
+        add_project(&repository, "A", "1.1.0", &[]);
+        let packages = scan(&repository).unwrap();
+        let lock = LockFile {
+            packages: vec![LockedPackage {
+                id: "A".to_string(),
+                version: "1.0.0".to_string(),
+                source: "A-1.0.0".to_string(),
+            }],
+        };
+
+        let root = manifest("Root", "0.1.0", &[("A", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock).unwrap(),
+            ["A 1.0.0"]
+        );
+        // A locked version that no longer matches is replaced
+        let root = manifest("Root", "0.1.0", &[("A", "^1.1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock).unwrap(),
+            ["A 1.1.0"]
+        );
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn skips_mod_and_detects_cycles() {
+        let repository = temp_repository("cycles");
+        add_project(&repository, "A", "1.0.0", &[("Root", "^0.1")]);
+        add_project(&repository, "B", "1.0.0", &[("C", "^1")]);
+        add_project(&repository, "C", "1.0.0", &[("B", "^1")]);
+        // The mod itself in the repository is never installed
+        add_project(&repository, "Root", "0.1.0", &[]);
+        let packages = scan(&repository).unwrap();
+        let lock = LockFile::default();
+
+        let root = manifest("Root", "0.1.0", &[("A", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock).unwrap(),
+            ["A 1.0.0"]
+        );
+        let root = manifest("Root", "1.0.0", &[("A", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock)
+                .unwrap_err()
+                .to_string(),
+            "A requires Root ^0.1, but this is version 1.0.0"
+        );
+        let root = manifest("Root", "0.1.0", &[("B", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &lock)
+                .unwrap_err()
+                .to_string(),
+            "dependency cycle: B -> C -> B"
+        );
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn reads_qmods() {
+        let repository = temp_repository("qmods");
+        add_qmod(
+            &repository,
+            "A.qmod",
+            r#"{"id": "A", "version": "1.0.0", "dependences": [{"id": "B", "version": "^1"}]}"#,
+        );
+        add_qmod(
+            &repository,
+            "B.qmod",
+            r#"{"id": "B", "version": "1.0.0", "dependencies": []}"#,
+        );
+        let packages = scan(&repository).unwrap();
+        assert!(packages
+            .iter()
+            .all(|package| package.kind == SourceKind::QMod));
+
+        let root = manifest("Root", "0.1.0", &[("A", "^1")]);
+        assert_eq!(
+            resolve_versions(&root, &packages, &LockFile::default()).unwrap(),
+            ["A 1.0.0", "B 1.0.0"]
+        );
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn syncs_lock() {
+        let repository = temp_repository("lock");
+        add_project(&repository, "A", "1.0.0", &[]);
+        let packages = scan(&repository).unwrap();
+        let root = manifest("Root", "0.1.0", &[("A", "^1")]);
+        let resolved = resolve(&root, &packages, &LockFile::default()).unwrap();
+
+        let lock = LockFile::new(&resolved, &repository);
+        assert_eq!(lock.packages.len(), 1);
+        assert_eq!(lock.packages[0].source, "A-1.0.0");
+
+        let lock_path = repository.join(LOCK_PATH);
+        lock.save(&lock_path).unwrap();
+        assert!(LockFile::load(&lock_path).unwrap() == lock);
+        LockFile::default().save(&lock_path).unwrap();
+        assert!(!lock_path.exists());
+        fs::remove_dir_all(repository).unwrap();
+    }
+}
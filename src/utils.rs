@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::path::PathBuf;
 
 pub fn platform_executable(path: &mut PathBuf) {
@@ -6,3 +7,35 @@ pub fn platform_executable(path: &mut PathBuf) {
     #[cfg(not(target_os = "windows"))]
     path.set_extension("");
 }
+
+/// Hashes the keys of caches that are kept between builds. Unlike `DefaultHasher`, its output
+/// doesn't change between Rust versions or platforms.
+#[derive(Default)]
+pub struct CacheHasher(blake3::Hasher);
+
+impl CacheHasher {
+    /// Adds `data`, prefixed by its length so that consecutive inputs can't run together
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        let data = data.as_ref();
+        self.0.update(&(data.len() as u64).to_le_bytes());
+        self.0.update(data);
+    }
+
+    pub fn update_os_str(&mut self, s: impl AsRef<OsStr>) {
+        self.update(s.as_ref().to_string_lossy().as_bytes());
+    }
+
+    /// Adds each item of a list along with how many there are
+    pub fn update_all<T: AsRef<[u8]>>(&mut self, items: impl IntoIterator<Item = T>) {
+        let mut count = 0u64;
+        for item in items {
+            self.update(item);
+            count += 1;
+        }
+        self.0.update(&count.to_le_bytes());
+    }
+
+    pub fn finish(&self) -> String {
+        self.0.finalize().to_hex().to_string()
+    }
+}